@@ -0,0 +1,541 @@
+use crate::frame_id::*;
+use crate::packet::*;
+use crate::serial::*;
+use futures::{SinkExt, StreamExt};
+use std::fmt;
+use std::io;
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::time::Instant;
+
+pub const DEFAULT_AT_TIMEOUT: Duration = Duration::from_secs(2);
+
+// ND answers for NT (6 s by default) plus some slack for the last responses
+pub const DEFAULT_DISCOVERY_TIMEOUT: Duration = Duration::from_secs(8);
+
+const BAUD_RATES: [u32; 8] = [1200, 2400, 4800, 9600, 19200, 38400, 57600, 115200];
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum AtCommand {
+    PanId,
+    Channel,
+    SerialHigh,
+    SerialLow,
+    NodeIdentifier,
+    NodeJoinTime,
+    ApiMode,
+    BaudRate,
+    EncryptionEnable,
+    EncryptionKey,
+    NodeDiscover,
+    Write,
+    ApplyChanges,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum CommandStatus {
+    Ok = 0,
+    Error = 1,
+    InvalidCommand = 2,
+    InvalidParameter = 3,
+    TransmitFailure = 4,
+    Unknown = 0xFF,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ApiMode {
+    Transparent = 0,
+    Api = 1,
+    ApiEscaped = 2,
+}
+
+#[derive(Debug)]
+pub enum AtError {
+    Status {
+        command: AtCommand,
+        status: CommandStatus,
+    },
+    Malformed(AtCommand),
+    Timeout(AtCommand),
+    NoFrameIds,
+    Transport(io::Error),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct DiscoveredNode {
+    pub network_address: [u8; 2],
+    pub address: [u8; 8],
+    pub node_identifier: String,
+    pub parent_network_address: [u8; 2],
+    pub device_type: u8,
+    pub status: u8,
+    pub profile_id: u16,
+    pub manufacturer_id: u16,
+}
+
+pub struct AtClient<T> {
+    transport: XBeeTransport<T>,
+    frame_ids: FrameIdAllocator,
+    pub timeout: Duration,
+    // frames that arrived while waiting on a response, in arrival order
+    pub unsolicited: Vec<Packet>,
+}
+
+impl AtCommand {
+    pub fn code(&self) -> u16 {
+        let name = match self {
+            AtCommand::PanId => b"ID",
+            AtCommand::Channel => b"CH",
+            AtCommand::SerialHigh => b"SH",
+            AtCommand::SerialLow => b"SL",
+            AtCommand::NodeIdentifier => b"NI",
+            AtCommand::NodeJoinTime => b"NJ",
+            AtCommand::ApiMode => b"AP",
+            AtCommand::BaudRate => b"BD",
+            AtCommand::EncryptionEnable => b"EE",
+            AtCommand::EncryptionKey => b"KY",
+            AtCommand::NodeDiscover => b"ND",
+            AtCommand::Write => b"WR",
+            AtCommand::ApplyChanges => b"AC",
+        };
+        ((name[0] as u16) << 8) | (name[1] as u16)
+    }
+
+    pub fn from_code(code: u16) -> Option<Self> {
+        match &[(code >> 8) as u8, (code & 0x0FF) as u8] {
+            b"ID" => Some(AtCommand::PanId),
+            b"CH" => Some(AtCommand::Channel),
+            b"SH" => Some(AtCommand::SerialHigh),
+            b"SL" => Some(AtCommand::SerialLow),
+            b"NI" => Some(AtCommand::NodeIdentifier),
+            b"NJ" => Some(AtCommand::NodeJoinTime),
+            b"AP" => Some(AtCommand::ApiMode),
+            b"BD" => Some(AtCommand::BaudRate),
+            b"EE" => Some(AtCommand::EncryptionEnable),
+            b"KY" => Some(AtCommand::EncryptionKey),
+            b"ND" => Some(AtCommand::NodeDiscover),
+            b"WR" => Some(AtCommand::Write),
+            b"AC" => Some(AtCommand::ApplyChanges),
+            _ => None,
+        }
+    }
+}
+
+impl CommandStatus {
+    pub fn new(val: u8) -> Self {
+        match val {
+            0 => CommandStatus::Ok,
+            1 => CommandStatus::Error,
+            2 => CommandStatus::InvalidCommand,
+            3 => CommandStatus::InvalidParameter,
+            4 => CommandStatus::TransmitFailure,
+            _ => CommandStatus::Unknown,
+        }
+    }
+}
+
+impl ApiMode {
+    pub fn new(val: u8) -> Option<Self> {
+        match val {
+            0 => Some(ApiMode::Transparent),
+            1 => Some(ApiMode::Api),
+            2 => Some(ApiMode::ApiEscaped),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for AtError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            AtError::Status { command, status } => {
+                write!(f, "{:?} returned status {:?}", command, status)
+            }
+            AtError::Malformed(c) => write!(f, "malformed response to {:?}", c),
+            AtError::Timeout(c) => write!(f, "timed out waiting for {:?}", c),
+            AtError::NoFrameIds => write!(f, "no free frame ids"),
+            AtError::Transport(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl From<io::Error> for AtError {
+    fn from(e: io::Error) -> Self {
+        AtError::Transport(e)
+    }
+}
+
+impl DiscoveredNode {
+    // MY, SH, SL, NI (null terminated), PARENT, DEVICE_TYPE, STATUS, PROFILE_ID, MANUFACTURER_ID
+    pub fn from_data(data: &[u8]) -> Option<Self> {
+        if data.len() < 11 {
+            return None;
+        }
+        let mut network_address = [0; 2];
+        network_address.copy_from_slice(&data[0..2]);
+        let mut address = [0; 8];
+        address.copy_from_slice(&data[2..10]);
+
+        let ni_len = data[10..].iter().position(|x| *x == 0x00)?;
+        let node_identifier = String::from_utf8_lossy(&data[10..10 + ni_len]).to_string();
+        let rest = &data[10 + ni_len + 1..];
+        if rest.len() < 8 {
+            return None;
+        }
+
+        Some(DiscoveredNode {
+            network_address,
+            address,
+            node_identifier,
+            parent_network_address: [rest[0], rest[1]],
+            device_type: rest[2],
+            status: rest[3],
+            profile_id: ((rest[4] as u16) << 8) | (rest[5] as u16),
+            manufacturer_id: ((rest[6] as u16) << 8) | (rest[7] as u16),
+        })
+    }
+}
+
+fn to_u64(command: AtCommand, data: &[u8]) -> Result<u64, AtError> {
+    if data.is_empty() || data.len() > 8 {
+        return Err(AtError::Malformed(command));
+    }
+    Ok(data.iter().fold(0, |acc, x| (acc << 8) | (*x as u64)))
+}
+
+impl<T: AsyncRead + AsyncWrite + Unpin> AtClient<T> {
+    pub fn new(transport: XBeeTransport<T>) -> Self {
+        AtClient {
+            transport,
+            frame_ids: FrameIdAllocator::new(),
+            timeout: DEFAULT_AT_TIMEOUT,
+            unsolicited: Vec::new(),
+        }
+    }
+
+    pub fn into_transport(self) -> XBeeTransport<T> {
+        self.transport
+    }
+
+    async fn send(&mut self, command: AtCommand, param: &[u8]) -> Result<u8, AtError> {
+        let id = self
+            .frame_ids
+            .allocate([0x00; 8], false, self.timeout)
+            .ok_or(AtError::NoFrameIds)?;
+        let packet = Packet::new_local_at(id, command.code(), param);
+        match self.transport.send(packet).await {
+            Ok(()) => Ok(id),
+            Err(e) => {
+                self.frame_ids.release(id);
+                Err(AtError::Transport(e))
+            }
+        }
+    }
+
+    // Waits for the next response carrying `id`, or None once the deadline passes
+    async fn next_response(
+        &mut self,
+        id: u8,
+        deadline: Instant,
+    ) -> Result<Option<Packet>, AtError> {
+        loop {
+            match tokio::time::timeout_at(deadline, self.transport.next()).await {
+                Err(_elapsed) => return Ok(None),
+                Ok(None) => {
+                    return Err(AtError::Transport(io::Error::new(
+                        io::ErrorKind::UnexpectedEof,
+                        "serial port closed",
+                    )))
+                }
+                Ok(Some(frame)) => {
+                    let packet = frame?;
+                    if packet.frame_type == FrameTypes::LocalAtResponse && packet.frame_id == id {
+                        return Ok(Some(packet));
+                    }
+                    self.unsolicited.push(packet);
+                }
+            }
+        }
+    }
+
+    fn check_status(command: AtCommand, packet: &Packet) -> Result<(), AtError> {
+        match CommandStatus::new(packet.command_status) {
+            CommandStatus::Ok => Ok(()),
+            status => Err(AtError::Status { command, status }),
+        }
+    }
+
+    pub async fn execute(&mut self, command: AtCommand, param: &[u8]) -> Result<Vec<u8>, AtError> {
+        let id = self.send(command, param).await?;
+        let deadline = Instant::now() + self.timeout;
+        let response = self.next_response(id, deadline).await;
+        self.frame_ids.release(id);
+        match response? {
+            Some(packet) => {
+                AtClient::<T>::check_status(command, &packet)?;
+                Ok(packet.data)
+            }
+            None => Err(AtError::Timeout(command)),
+        }
+    }
+
+    pub async fn query(&mut self, command: AtCommand) -> Result<Vec<u8>, AtError> {
+        self.execute(command, &[]).await
+    }
+
+    pub async fn pan_id(&mut self) -> Result<u64, AtError> {
+        let data = self.query(AtCommand::PanId).await?;
+        to_u64(AtCommand::PanId, &data)
+    }
+
+    pub async fn set_pan_id(&mut self, pan_id: u64) -> Result<(), AtError> {
+        self.execute(AtCommand::PanId, &pan_id.to_be_bytes())
+            .await?;
+        Ok(())
+    }
+
+    pub async fn channel(&mut self) -> Result<u8, AtError> {
+        let data = self.query(AtCommand::Channel).await?;
+        Ok(to_u64(AtCommand::Channel, &data)? as u8)
+    }
+
+    pub async fn serial_number(&mut self) -> Result<[u8; 8], AtError> {
+        let high = to_u64(
+            AtCommand::SerialHigh,
+            &self.query(AtCommand::SerialHigh).await?,
+        )?;
+        let low = to_u64(
+            AtCommand::SerialLow,
+            &self.query(AtCommand::SerialLow).await?,
+        )?;
+        Ok(((high << 32) | (low & 0xFFFF_FFFF)).to_be_bytes())
+    }
+
+    pub async fn node_identifier(&mut self) -> Result<String, AtError> {
+        let data = self.query(AtCommand::NodeIdentifier).await?;
+        Ok(String::from_utf8_lossy(&data).to_string())
+    }
+
+    pub async fn set_node_identifier(&mut self, name: &str) -> Result<(), AtError> {
+        self.execute(AtCommand::NodeIdentifier, name.as_bytes())
+            .await?;
+        Ok(())
+    }
+
+    // seconds the coordinator allows devices to join, 0xFF means always open
+    pub async fn join_window(&mut self) -> Result<u8, AtError> {
+        let data = self.query(AtCommand::NodeJoinTime).await?;
+        Ok(to_u64(AtCommand::NodeJoinTime, &data)? as u8)
+    }
+
+    pub async fn set_join_window(&mut self, seconds: u8) -> Result<(), AtError> {
+        self.execute(AtCommand::NodeJoinTime, &[seconds]).await?;
+        Ok(())
+    }
+
+    pub async fn api_mode(&mut self) -> Result<ApiMode, AtError> {
+        let data = self.query(AtCommand::ApiMode).await?;
+        ApiMode::new(to_u64(AtCommand::ApiMode, &data)? as u8)
+            .ok_or(AtError::Malformed(AtCommand::ApiMode))
+    }
+
+    pub async fn set_api_mode(&mut self, mode: ApiMode) -> Result<(), AtError> {
+        self.execute(AtCommand::ApiMode, &[mode as u8]).await?;
+        Ok(())
+    }
+
+    // BD returns an index into the standard rates, anything above 7 is the rate itself
+    pub async fn baud_rate(&mut self) -> Result<u32, AtError> {
+        let data = self.query(AtCommand::BaudRate).await?;
+        let value = to_u64(AtCommand::BaudRate, &data)? as u32;
+        match BAUD_RATES.get(value as usize) {
+            Some(rate) => Ok(*rate),
+            None => Ok(value),
+        }
+    }
+
+    pub async fn set_baud_rate(&mut self, rate: u32) -> Result<(), AtError> {
+        let value = match BAUD_RATES.iter().position(|x| *x == rate) {
+            Some(p) => p as u32,
+            None => rate,
+        };
+        self.execute(AtCommand::BaudRate, &value.to_be_bytes())
+            .await?;
+        Ok(())
+    }
+
+    pub async fn encryption_enabled(&mut self) -> Result<bool, AtError> {
+        let data = self.query(AtCommand::EncryptionEnable).await?;
+        Ok(to_u64(AtCommand::EncryptionEnable, &data)? != 0)
+    }
+
+    // KY is write only, the radio never reports the key back
+    pub async fn set_encryption(
+        &mut self,
+        enabled: bool,
+        key: Option<[u8; 16]>,
+    ) -> Result<(), AtError> {
+        if let Some(k) = key {
+            self.execute(AtCommand::EncryptionKey, &k).await?;
+        }
+        self.execute(AtCommand::EncryptionEnable, &[enabled as u8])
+            .await?;
+        Ok(())
+    }
+
+    pub async fn write_settings(&mut self) -> Result<(), AtError> {
+        self.execute(AtCommand::Write, &[]).await?;
+        Ok(())
+    }
+
+    pub async fn apply_changes(&mut self) -> Result<(), AtError> {
+        self.execute(AtCommand::ApplyChanges, &[]).await?;
+        Ok(())
+    }
+
+    // Every node answers ND with its own response frame, all sharing our frame id.
+    pub async fn node_discover(
+        &mut self,
+        timeout: Duration,
+    ) -> Result<Vec<DiscoveredNode>, AtError> {
+        let id = self.send(AtCommand::NodeDiscover, &[]).await?;
+        let deadline = Instant::now() + timeout;
+        let mut nodes = Vec::new();
+        loop {
+            match self.next_response(id, deadline).await {
+                Ok(Some(packet)) => {
+                    if let Err(e) = AtClient::<T>::check_status(AtCommand::NodeDiscover, &packet) {
+                        self.frame_ids.release(id);
+                        return Err(e);
+                    }
+                    // an empty response marks the end of discovery
+                    if packet.data.is_empty() {
+                        break;
+                    }
+                    match DiscoveredNode::from_data(&packet.data) {
+                        Some(n) => nodes.push(n),
+                        None => eprintln!("malformed node discovery response {:X?}", packet.data),
+                    }
+                }
+                Ok(None) => break,
+                Err(e) => {
+                    self.frame_ids.release(id);
+                    return Err(e);
+                }
+            }
+        }
+        self.frame_ids.release(id);
+        Ok(nodes)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::simulator::*;
+    use tokio::io::DuplexStream;
+
+    fn client(coordinator: Coordinator) -> AtClient<DuplexStream> {
+        let (host, radio) = tokio::io::duplex(1024);
+        tokio::spawn(coordinator.run(radio));
+        let mut client = AtClient::new(new_transport(host));
+        client.timeout = Duration::from_millis(500);
+        client
+    }
+
+    // MY, SH, SL, NI, PARENT, DEVICE_TYPE, STATUS, PROFILE_ID, MANUFACTURER_ID
+    fn node_data(name: &[u8]) -> Vec<u8> {
+        let mut data = vec![0x12, 0x34, 1, 2, 3, 4, 5, 6, 7, 8];
+        data.extend_from_slice(name);
+        data.extend_from_slice(&[0x00, 0xFF, 0xFE, 0x01, 0x00, 0xC1, 0x05, 0x10, 0x1E]);
+        data
+    }
+
+    #[tokio::test]
+    async fn queries_and_sets_parameters() {
+        let mut client = client(Coordinator::new(FaultConfig::default()));
+        assert_eq!(client.pan_id().await.unwrap(), 0);
+        assert_eq!(client.channel().await.unwrap(), 0x0F);
+        assert_eq!(client.node_identifier().await.unwrap(), "COORDINATOR");
+
+        client.set_pan_id(0x1234).await.unwrap();
+        assert_eq!(client.pan_id().await.unwrap(), 0x1234);
+        client.set_baud_rate(115200).await.unwrap();
+        assert_eq!(client.baud_rate().await.unwrap(), 115200);
+        assert!(client.unsolicited.is_empty());
+    }
+
+    #[tokio::test]
+    async fn maps_a_non_zero_status_to_an_error() {
+        let mut coordinator = Coordinator::new(FaultConfig::default());
+        coordinator.settings.remove(&AtCommand::Channel.code());
+        let mut client = client(coordinator);
+        match client.channel().await {
+            Err(AtError::Status { command, status }) => {
+                assert_eq!(command, AtCommand::Channel);
+                assert_eq!(status, CommandStatus::InvalidCommand);
+            }
+            other => panic!("unexpected {:?}", other),
+        }
+        // the client is still usable afterwards
+        assert_eq!(client.pan_id().await.unwrap(), 0);
+    }
+
+    #[tokio::test]
+    async fn times_out_without_a_response() {
+        let mut client = client(Coordinator::new(FaultConfig {
+            drop_rate: 1.0,
+            ..FaultConfig::default()
+        }));
+        client.timeout = Duration::from_millis(100);
+        assert!(matches!(
+            client.pan_id().await,
+            Err(AtError::Timeout(AtCommand::PanId))
+        ));
+    }
+
+    #[tokio::test]
+    async fn collects_discovered_nodes_until_the_timeout() {
+        let mut coordinator = Coordinator::new(FaultConfig::default());
+        let addresses = [[1; 8], [2; 8], [3; 8]];
+        coordinator.add_device(VirtualDevice::new_bridge(addresses[0], [0x00, 0x01]));
+        coordinator.add_device(VirtualDevice::new_power_meter(addresses[1], [0x00, 0x02]));
+        coordinator.add_device(VirtualDevice::new_bridge(addresses[2], [0x00, 0x03]));
+        let mut client = client(coordinator);
+
+        let timeout = Duration::from_millis(200);
+        let start = Instant::now();
+        let nodes = client.node_discover(timeout).await.unwrap();
+        // the simulator sends no closing empty response, so only the timeout ends it
+        assert!(start.elapsed() >= timeout);
+        let found: Vec<[u8; 8]> = nodes.iter().map(|x| x.address).collect();
+        assert_eq!(found, addresses.to_vec());
+        assert_eq!(nodes[1].network_address, [0x00, 0x02]);
+        assert_eq!(nodes[1].profile_id, 0xC105);
+        assert_eq!(nodes[1].manufacturer_id, 0x101E);
+
+        // the frame id went back to the allocator
+        assert_eq!(client.pan_id().await.unwrap(), 0);
+    }
+
+    #[test]
+    fn parses_node_discovery_payloads() {
+        let node = DiscoveredNode::from_data(&node_data(b"ROUTER")).unwrap();
+        assert_eq!(node.network_address, [0x12, 0x34]);
+        assert_eq!(node.address, [1, 2, 3, 4, 5, 6, 7, 8]);
+        assert_eq!(node.node_identifier, "ROUTER");
+        assert_eq!(node.parent_network_address, [0xFF, 0xFE]);
+        assert_eq!(node.device_type, 0x01);
+        assert_eq!(node.profile_id, 0xC105);
+    }
+
+    #[test]
+    fn rejects_truncated_node_discovery_payloads() {
+        let data = node_data(b"ROUTER");
+        // cut inside the address, inside the identifier and inside the trailer
+        for len in [9, 14, data.len() - 1] {
+            assert_eq!(DiscoveredNode::from_data(&data[..len]), None);
+        }
+        assert_eq!(DiscoveredNode::from_data(&[]), None);
+    }
+}
@@ -0,0 +1,104 @@
+use crate::samples::time_as_millis;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant, SystemTime};
+
+// Wall clock drift between two checks smaller than this is left to NTP slewing
+pub const STEP_THRESHOLD: Duration = Duration::from_secs(1);
+// systemd-timesyncd creates this once the clock has been synchronized
+pub const NTP_SYNC_FLAG: &str = "/run/systemd/timesync/synchronized";
+
+#[derive(Clone, Serialize, Deserialize, PartialEq, Debug, Default)]
+pub struct ClockQuality {
+    pub synchronized: bool,
+    pub steps: u64,
+    // signed size of the last step in millis, positive when the clock jumped forward
+    pub last_step: Option<i128>,
+    pub last_step_at: Option<u128>,
+}
+
+// Watches the gateway's wall clock against the monotonic clock. Between two
+// checks both should advance by the same amount, anything else is a step.
+#[derive(Clone, Debug)]
+pub struct ClockMonitor {
+    pub quality: ClockQuality,
+    sync_flag: PathBuf,
+    wall: SystemTime,
+    monotonic: Instant,
+}
+
+impl Default for ClockMonitor {
+    fn default() -> Self {
+        ClockMonitor::new(NTP_SYNC_FLAG)
+    }
+}
+
+impl ClockMonitor {
+    pub fn new<P: AsRef<Path>>(sync_flag: P) -> Self {
+        let mut monitor = ClockMonitor {
+            quality: ClockQuality::default(),
+            sync_flag: sync_flag.as_ref().to_path_buf(),
+            wall: SystemTime::now(),
+            monotonic: Instant::now(),
+        };
+        monitor.quality.synchronized = monitor.sync_flag.exists();
+        monitor
+    }
+
+    // Returns the step in millis if the clock moved since the last check
+    pub fn check(&mut self) -> Option<i128> {
+        let wall = SystemTime::now();
+        let monotonic = Instant::now();
+        let expected = self.wall + monotonic.duration_since(self.monotonic);
+        let step = time_as_millis(wall) as i128 - time_as_millis(expected) as i128;
+        self.wall = wall;
+        self.monotonic = monotonic;
+
+        let synchronized = self.sync_flag.exists();
+        if synchronized != self.quality.synchronized {
+            println!("clock synchronized: {}", synchronized);
+            self.quality.synchronized = synchronized;
+        }
+
+        if step.unsigned_abs() < STEP_THRESHOLD.as_millis() {
+            return None;
+        }
+        eprintln!("clock stepped by {} ms", step);
+        self.quality.steps += 1;
+        self.quality.last_step = Some(step);
+        self.quality.last_step_at = Some(time_as_millis(wall));
+        Some(step)
+    }
+
+    pub fn quality(&self) -> ClockQuality {
+        self.quality.clone()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn follows_the_sync_flag() {
+        let flag = std::env::temp_dir().join(format!("clock-sync-{}", std::process::id()));
+        let mut monitor = ClockMonitor::new(&flag);
+        assert!(!monitor.quality().synchronized);
+
+        std::fs::write(&flag, b"").unwrap();
+        assert_eq!(monitor.check(), None);
+        assert!(monitor.quality().synchronized);
+        assert_eq!(monitor.quality().steps, 0);
+        std::fs::remove_file(&flag).unwrap();
+    }
+
+    #[test]
+    fn counts_steps() {
+        let mut monitor = ClockMonitor::new("/nonexistent");
+        // as if the wall clock had been an hour behind at the last check
+        monitor.wall -= Duration::from_secs(3600);
+        let step = monitor.check().unwrap();
+        assert!((3_599_000..=3_601_000).contains(&step));
+        assert_eq!(monitor.quality().steps, 1);
+        assert_eq!(monitor.quality().last_step, Some(step));
+    }
+}
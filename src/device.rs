@@ -1,6 +1,12 @@
+use crate::frame_id::*;
+use crate::message::*;
+use crate::modbus::ModbusError;
 use crate::packet::*;
+use crate::samples::*;
+use crate::store::SampleStore;
 use crate::task::*;
-use std::time::Instant;
+use std::path::Path;
+use std::time::{Instant, SystemTime};
 
 #[derive(Clone, PartialEq, Debug, Serialize, Deserialize)]
 pub enum DeviceTypes {
@@ -25,6 +31,11 @@ pub struct Device {
     pub network_address: [u8; 2],
     pub messages_sent: Vec<SentMessage>,
     pub last_heard_from: Instant,
+    pub crc_failures: u64,
+    // Modbus slave ids seen on a bridge's RS-485 side, kept sorted
+    pub slaves: Vec<u8>,
+    // empty for meters that still use the default 24 channels
+    pub channels: Vec<ChannelConfig>,
 }
 
 #[derive(Clone, Serialize, Deserialize, Debug)]
@@ -33,13 +44,24 @@ pub struct DeviceSummary {
     pub address: [u8; 8],
     pub network_address: [u8; 2],
     pub secs_since_heard_from: u64,
+    #[serde(default)]
+    pub crc_failures: u64,
+    #[serde(default)]
+    pub slaves: Vec<u8>,
+    #[serde(default)]
+    pub channels: Vec<ChannelConfig>,
 }
 
 pub struct DeviceDB {
     pub devices: Vec<Device>,
+    pub frame_ids: FrameIdAllocator,
     db: sled::Db,
+    // channel configs as JSON, keyed by device address like the device records
+    channels: sled::Tree,
 }
 
+const CHANNEL_TREE: &str = "channels";
+
 impl DeviceTypes {
     pub fn new(val: u8) -> Self {
         match val {
@@ -52,19 +74,8 @@ impl DeviceTypes {
 
 impl DeviceDB {
     pub fn new() -> Self {
-        match sled::open("devices") {
-            Ok(db) => {
-                let mut devicedb = DeviceDB {
-                    devices: Vec::new(),
-                    db: db,
-                };
-                devicedb.db.iter().for_each(|x| {
-                    devicedb
-                        .devices
-                        .push(Device::from_ivec((x.unwrap().1).as_ref()))
-                });
-                devicedb
-            }
+        match DeviceDB::open("devices") {
+            Ok(devicedb) => devicedb,
             Err(e) => {
                 eprintln!("Error Opening Device DB");
                 eprintln!("{:}", e);
@@ -73,6 +84,36 @@ impl DeviceDB {
         }
     }
 
+    pub fn open<P: AsRef<Path>>(path: P) -> sled::Result<Self> {
+        DeviceDB::from_db(sled::open(path)?)
+    }
+
+    pub fn from_db(db: sled::Db) -> sled::Result<Self> {
+        let channels = db.open_tree(CHANNEL_TREE)?;
+        let mut devicedb = DeviceDB {
+            devices: Vec::new(),
+            frame_ids: FrameIdAllocator::new(),
+            db,
+            channels,
+        };
+        devicedb.db.iter().for_each(|x| {
+            devicedb
+                .devices
+                .push(Device::from_ivec((x.unwrap().1).as_ref()))
+        });
+        for device in devicedb.devices.iter_mut() {
+            match devicedb.channels.get(device.address) {
+                Ok(Some(v)) => match serde_json::from_slice(v.as_ref()) {
+                    Ok(c) => device.channels = c,
+                    Err(e) => eprintln!("bad channel config for {:X?}: {}", device.address, e),
+                },
+                Ok(None) => {}
+                Err(e) => eprintln!("{}", e),
+            }
+        }
+        Ok(devicedb)
+    }
+
     pub fn add_device(&mut self, device: Device) {
         for d in self.devices.iter() {
             if d.eq(&device) {
@@ -134,7 +175,223 @@ impl DeviceDB {
         }
     }
 
-    pub fn add_sent_message(&mut self, task: Task, id: u8, resent: bool) {
+    // Every outgoing task goes through here so its frame id comes from the
+    // allocator. Returns the packet to write to the radio, or None while all
+    // ids are in flight and the task has to wait.
+    pub fn send_task(&mut self, task: Task, resent: bool) -> Option<Packet> {
+        let mut packet = self.addressed(&task.packet);
+        let id =
+            Device::add_identifier(&mut packet, task.device_type.clone(), &mut self.frame_ids)?;
+        self.add_sent_message(task, id, resent);
+        Some(packet)
+    }
+
+    // Unicast transmits go out through their device so they carry the network
+    // address cached at send time rather than whatever the task was built with
+    fn addressed(&self, packet: &Packet) -> Packet {
+        if packet.frame_type != FrameTypes::TransmitRequest || packet.is_broadcast {
+            return packet.clone();
+        }
+        match self.devices.iter().find(|x| x.address == packet.address) {
+            Some(d) => d.new_transmit(&packet.data, TransmitOptions::from_byte(packet.options)),
+            None => packet.clone(),
+        }
+    }
+
+    // Frees the ids of requests nobody answered in time and hands back their
+    // sent messages, to go out again through send_task or be dropped
+    pub fn expire_frame_ids(&mut self) -> Vec<SentMessage> {
+        let expired = self.frame_ids.expire(Instant::now());
+        let mut messages = Vec::new();
+        if expired.is_empty() {
+            return messages;
+        }
+        for device in self.devices.iter_mut() {
+            let (timed_out, waiting): (Vec<SentMessage>, Vec<SentMessage>) = device
+                .messages_sent
+                .drain(..)
+                .partition(|x| expired.iter().any(|f| f.id == x.id));
+            device.messages_sent = waiting;
+            messages.extend(timed_out);
+        }
+        for f in expired.iter() {
+            eprintln!("frame {} to {:X?} timed out", f.id, f.address);
+        }
+        messages
+    }
+
+    // The count is kept in the device record so it survives restarts
+    pub fn record_crc_failure(&mut self, address: &[u8; 8]) -> u64 {
+        match self.devices.iter_mut().find(|x| x.address == *address) {
+            Some(d) => {
+                d.crc_failures += 1;
+                match self.db.insert(d.address, d.to_ivec()) {
+                    Ok(_t) => {}
+                    Err(_e) => println!("Failed to update device"),
+                }
+                d.crc_failures
+            }
+            None => 0,
+        }
+    }
+
+    // Returns false if the bridge is unknown or already had the slave
+    pub fn add_slave(&mut self, address: &[u8; 8], slave: u8) -> bool {
+        let device = match self.devices.iter_mut().find(|x| x.address == *address) {
+            Some(d) => d,
+            None => return false,
+        };
+        match device.slaves.binary_search(&slave) {
+            Ok(_p) => return false,
+            Err(p) => device.slaves.insert(p, slave),
+        }
+        match self.db.insert(device.address, device.to_ivec()) {
+            Ok(_t) => {}
+            Err(_e) => println!("Failed to update device"),
+        }
+        true
+    }
+
+    pub fn remove_slave(&mut self, address: &[u8; 8], slave: u8) -> bool {
+        let device = match self.devices.iter_mut().find(|x| x.address == *address) {
+            Some(d) => d,
+            None => return false,
+        };
+        match device.slaves.binary_search(&slave) {
+            Ok(p) => device.slaves.remove(p),
+            Err(_p) => return false,
+        };
+        match self.db.insert(device.address, device.to_ivec()) {
+            Ok(_t) => {}
+            Err(_e) => println!("Failed to update device"),
+        }
+        true
+    }
+
+    // An empty config goes back to the default 24 channels
+    pub fn set_channels(&mut self, address: &[u8; 8], channels: Vec<ChannelConfig>) -> bool {
+        let device = match self.devices.iter_mut().find(|x| x.address == *address) {
+            Some(d) => d,
+            None => return false,
+        };
+        let result = match channels.is_empty() {
+            true => self.channels.remove(address).map(|_x| ()),
+            false => match serde_json::to_vec(&channels) {
+                Ok(v) => self.channels.insert(address, v).map(|_x| ()),
+                Err(e) => {
+                    eprintln!("{}", e);
+                    return false;
+                }
+            },
+        };
+        match result {
+            Ok(_t) => {
+                device.channels = channels;
+                true
+            }
+            Err(_e) => {
+                println!("Failed to update channels");
+                false
+            }
+        }
+    }
+
+    pub fn decode_meter_sample(
+        &mut self,
+        sent: Packet,
+        received: Packet,
+    ) -> Result<MeterSample, SampleError> {
+        let mut sample = MeterSample::new(sent, received)?;
+        if let Some(d) = self
+            .devices
+            .iter()
+            .find(|x| x.address == sample.hardware_id)
+        {
+            if !d.channels.is_empty() {
+                sample.apply_channels(&d.channels);
+            }
+        }
+        Ok(sample)
+    }
+
+    // Bridge replies that fail the Modbus CRC are counted against the bridge
+    // they came from instead of ending up in the sample store.
+    pub fn decode_bridge_sample(
+        &mut self,
+        sent: Packet,
+        received: Packet,
+    ) -> Result<BridgeSample, SampleError> {
+        let address = received.address;
+        let sample = BridgeSample::new(sent, received);
+        if let Err(SampleError::Modbus(ModbusError::CrcMismatch { .. })) = sample {
+            let count = self.record_crc_failure(&address);
+            eprintln!("CRC failure {} from bridge {:X?}", count, address);
+        }
+        if let Ok(s) = &sample {
+            if self.add_slave(&address, s.slave) {
+                println!("new slave {} on bridge {:X?}", s.slave, address);
+            }
+        }
+        sample
+    }
+
+    // Every device response goes through here on its way to the sample store.
+    // Bridges answer both pulse and Modbus requests, told apart by what was sent.
+    pub fn decode_sample(
+        &mut self,
+        sent: Packet,
+        received: Packet,
+        device_type: DeviceTypes,
+    ) -> Result<Sample, SampleError> {
+        match device_type {
+            DeviceTypes::PowerMeter => self.decode_meter_sample(sent, received).map(Sample::Meter),
+            DeviceTypes::Bridge if sent.data.first() == Some(&PULSE_COMMAND) => {
+                PulseSample::new(sent, received).map(Sample::Pulse)
+            }
+            DeviceTypes::Bridge => self
+                .decode_bridge_sample(sent, received)
+                .map(Sample::Bridge),
+            DeviceTypes::None => Ok(Sample::None),
+        }
+    }
+
+    // Messages for the device database, the returned message is the reply
+    pub fn handle_message(&mut self, message: Message, store: &SampleStore) -> Message {
+        match message {
+            Message::Packet {
+                received,
+                sent,
+                device_type,
+            } => {
+                let address = received.address;
+                match self.decode_sample(sent, received, device_type) {
+                    Ok(Sample::None) => Message::new_error_message("No Sample".to_string()),
+                    Ok(sample) => match store.insert(&sample) {
+                        Ok(_k) => Message::new_error_message("Sample Stored".to_string()),
+                        Err(e) => {
+                            eprintln!("{}", e);
+                            Message::new_error_message(format!("{}", e))
+                        }
+                    },
+                    Err(e) => {
+                        eprintln!("dropping response from {:X?}: {}", address, e);
+                        Message::new_error_message(format!("{}", e))
+                    }
+                }
+            }
+            Message::ChannelConfig { address, channels } => {
+                match self.set_channels(&address, channels) {
+                    true => Message::new_error_message("Channels Stored".to_string()),
+                    false => Message::new_error_message("Device Not Found".to_string()),
+                }
+            }
+            _ => Message::new_error_message("Unexpected Message".to_string()),
+        }
+    }
+
+    fn add_sent_message(&mut self, mut task: Task, id: u8, resent: bool) {
+        // samples take their sent time from the packet handed back on response
+        task.packet.timestamp = time_as_millis(SystemTime::now());
         match task.packet.is_broadcast {
             true => {
                 for device in self.devices.iter_mut() {
@@ -177,6 +434,29 @@ impl DeviceDB {
     }
 
     pub fn clear_sent_message(&mut self, packet: &mut Packet) -> (DeviceTypes, Packet) {
+        match packet.frame_type {
+            FrameTypes::TransmitStatus => {
+                let delivered = packet.delivery_status == 0x00;
+                if let Some(f) = self.frame_ids.acknowledge(packet.frame_id, delivered) {
+                    // the cached network address may be stale if the device rejoined
+                    if let Some(d) = self.devices.iter_mut().find(|x| x.address == f.address) {
+                        d.network_address = UNKNOWN_NETWORK_ADDRESS;
+                    }
+                    // the id is free again, so its request must not claim the next response
+                    for device in self.devices.iter_mut() {
+                        device.messages_sent.retain(|x| x.id != f.id);
+                    }
+                }
+            }
+            FrameTypes::RemoteAtResponse => {
+                self.frame_ids.respond(packet.frame_id);
+            }
+            FrameTypes::ReceivePacket if !packet.data.is_empty() => {
+                self.frame_ids.respond(packet.get_packet_identifier());
+            }
+            _ => {}
+        }
+
         match self
             .devices
             .iter()
@@ -196,6 +476,16 @@ impl DeviceDB {
 
                     FrameTypes::ReceivePacket => {
                         self.devices[p].last_heard_from = Instant::now();
+                        if self.devices[p].network_address != packet.network_address {
+                            self.devices[p].network_address = packet.network_address;
+                            match self
+                                .db
+                                .insert(self.devices[p].address, self.devices[p].to_ivec())
+                            {
+                                Ok(_t) => {}
+                                Err(_e) => println!("Failed to update device"),
+                            }
+                        }
 
                         let ret_packet;
                         let dev = self.devices[p].device_type.clone();
@@ -203,11 +493,18 @@ impl DeviceDB {
                         match self.devices[p].device_type {
                             _ => {
                                 //for other devices we use the packet identifier
-                                let pos = self.devices[p]
+                                let pos = match self.devices[p]
                                     .messages_sent
                                     .iter_mut()
                                     .position(|x| x.id == packet.get_packet_identifier())
-                                    .unwrap();
+                                {
+                                    Some(pos) => pos,
+                                    None => {
+                                        // the request already timed out and gave up its id
+                                        println!("no sent message for response");
+                                        return (dev, Packet::new_empty());
+                                    }
+                                };
                                 let ret = self.devices[p].messages_sent.remove(pos);
                                 ret_packet = ret.task.packet;
                                 println!("cleared sent message")
@@ -272,13 +569,53 @@ impl Device {
             }
         }
     }
-    pub fn add_identifier(packet: &mut Packet, device_type: DeviceTypes, id: u8) {
+    // The id is taken from the allocator and stays reserved until the response,
+    // a failed Transmit Status or the timeout frees it
+    pub fn add_identifier(
+        packet: &mut Packet,
+        device_type: DeviceTypes,
+        frame_ids: &mut FrameIdAllocator,
+    ) -> Option<u8> {
+        let id = frame_ids.allocate(packet.address, packet.is_broadcast, DEFAULT_FRAME_TIMEOUT)?;
         match device_type {
             _ => {
                 packet.set_frame_id(id);
                 packet.insert_packet_identifer(id);
             }
         }
+        Some(id)
+    }
+
+    pub fn summary(&self) -> DeviceSummary {
+        DeviceSummary {
+            device_type: self.device_type.clone(),
+            address: self.address,
+            network_address: self.network_address,
+            secs_since_heard_from: self.last_heard_from.elapsed().as_secs(),
+            crc_failures: self.crc_failures,
+            slaves: self.slaves.clone(),
+            channels: self.channels.clone(),
+        }
+    }
+
+    pub fn network_address_known(&self) -> bool {
+        self.network_address != UNKNOWN_NETWORK_ADDRESS && self.network_address != [0x00, 0x00]
+    }
+
+    // Addresses the device by its cached 16-bit network address when we have one,
+    // which saves the radio a network address discovery before every transmit.
+    pub fn new_transmit(&self, data: &[u8], options: TransmitOptions) -> Packet {
+        let network = match self.network_address_known() {
+            true => self.network_address,
+            false => UNKNOWN_NETWORK_ADDRESS,
+        };
+        Packet::new_transmit_with(
+            &self.address,
+            network,
+            data,
+            options,
+            BroadcastRadius::default(),
+        )
     }
 
     pub fn new(d_type: DeviceTypes, address: [u8; 8], network: [u8; 2]) -> Self {
@@ -288,6 +625,9 @@ impl Device {
             network_address: network,
             messages_sent: Vec::new(),
             last_heard_from: Instant::now(),
+            crc_failures: 0,
+            slaves: Vec::new(),
+            channels: Vec::new(),
         }
     }
 
@@ -296,6 +636,9 @@ impl Device {
         vec.push(self.device_type.clone() as u8);
         vec.extend_from_slice(&self.address);
         vec.extend_from_slice(&self.network_address);
+        vec.push(self.slaves.len() as u8);
+        vec.extend_from_slice(&self.slaves);
+        vec.extend_from_slice(&self.crc_failures.to_be_bytes());
 
         sled::IVec::from(vec)
     }
@@ -314,6 +657,9 @@ impl Device {
             network_address: [0; 2],
             messages_sent: Vec::new(),
             last_heard_from: Instant::now(),
+            crc_failures: 0,
+            slaves: Vec::new(),
+            channels: Vec::new(),
         };
 
         device
@@ -328,6 +674,214 @@ impl Device {
         device.address.copy_from_slice(add_vec.as_slice());
         device.network_address.copy_from_slice(net_vec.as_slice());
 
+        // records written before slave inventories end here
+        if !data_vec.is_empty() {
+            let count = (data_vec.remove(0) as usize).min(data_vec.len());
+            device.slaves = data_vec.drain(..count).collect();
+        }
+        // and these before CRC failure counts
+        if data_vec.len() >= 8 {
+            let mut crc_failures = [0u8; 8];
+            crc_failures.copy_from_slice(&data_vec[..8]);
+            device.crc_failures = u64::from_be_bytes(crc_failures);
+        }
+
         device
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::modbus::*;
+
+    const BRIDGE: [u8; 8] = [9, 9, 9, 9, 9, 9, 9, 9];
+
+    fn open() -> (sled::Db, DeviceDB, SampleStore) {
+        let db = sled::Config::new().temporary(true).open().unwrap();
+        let devicedb = DeviceDB::from_db(db.clone()).unwrap();
+        let store = sled::Config::new().temporary(true).open().unwrap();
+        let store = SampleStore::from_db(store).unwrap();
+        (db, devicedb, store)
+    }
+
+    fn bridge_packets(response: &[u8]) -> Message {
+        let sent = ModbusMessage::new_read_message(5, 3, 0, 1).as_bytes();
+        let mut data = response.to_vec();
+        let crc = crc_helper(data.clone());
+        data.push((crc & 0x0FF) as u8);
+        data.push((crc >> 8) as u8);
+        Message::new_packet(
+            Packet::new_receive(&BRIDGE, [0x12, 0x34], 0x01, &data),
+            Packet::new_transmit(&BRIDGE, &sent),
+            DeviceTypes::Bridge,
+        )
+    }
+
+    #[test]
+    fn device_record_round_trip() {
+        let mut device = Device::new(DeviceTypes::Bridge, BRIDGE, [0x12, 0x34]);
+        device.slaves = vec![1, 5];
+        device.crc_failures = 3;
+        let decoded = Device::from_ivec(device.to_ivec().as_ref());
+        assert_eq!(decoded.slaves, vec![1, 5]);
+        assert_eq!(decoded.crc_failures, 3);
+
+        // records from before slave inventories and CRC counts
+        let mut old = vec![DeviceTypes::Bridge as u8];
+        old.extend_from_slice(&BRIDGE);
+        old.extend_from_slice(&[0x12, 0x34]);
+        let decoded = Device::from_ivec(&old);
+        assert_eq!(decoded.network_address, [0x12, 0x34]);
+        assert!(decoded.slaves.is_empty());
+        assert_eq!(decoded.crc_failures, 0);
+    }
+
+    #[test]
+    fn stores_bridge_samples_and_counts_crc_failures() {
+        let (db, mut devicedb, store) = open();
+        devicedb.add_device(Device::new(DeviceTypes::Bridge, BRIDGE, [0x12, 0x34]));
+
+        devicedb.handle_message(bridge_packets(&[5, 3, 2, 0xBE, 0xEF]), &store);
+        let samples = store.get_samples(&SampleTypes::Bridge, 10).unwrap();
+        assert_eq!(samples.len(), 1);
+        assert_eq!(devicedb.devices[0].slaves, vec![5]);
+
+        let mut bad = bridge_packets(&[5, 3, 2, 0xBE, 0xEF]);
+        if let Message::Packet { received, .. } = &mut bad {
+            let last = received.data.len() - 1;
+            received.data[last] ^= 0xFF;
+        }
+        devicedb.handle_message(bad, &store);
+        assert_eq!(devicedb.devices[0].crc_failures, 1);
+        assert_eq!(
+            store.get_samples(&SampleTypes::Bridge, 10).unwrap().len(),
+            1
+        );
+
+        // the count comes back with the device record
+        let reopened = DeviceDB::from_db(db).unwrap();
+        assert_eq!(reopened.devices[0].crc_failures, 1);
+    }
+
+    #[test]
+    fn unicast_transmits_use_the_cached_network_address() {
+        let (_db, mut devicedb, _store) = open();
+        devicedb.add_device(Device::new(DeviceTypes::Bridge, BRIDGE, [0x12, 0x34]));
+        let data = ModbusMessage::new_read_message(5, 3, 0, 1).as_bytes();
+        let options = TransmitOptions {
+            extended_timeout: true,
+            ..TransmitOptions::default()
+        };
+        let packet = Packet::new_transmit_with(
+            &BRIDGE,
+            UNKNOWN_NETWORK_ADDRESS,
+            &data,
+            options,
+            BroadcastRadius::default(),
+        );
+        let task = Task::new(packet, TaskTypes::Single, 0, 0, DeviceTypes::Bridge);
+
+        let sent = devicedb.send_task(task.clone(), false).unwrap();
+        assert_eq!(sent.network_address, [0x12, 0x34]);
+        assert_eq!(sent.options, options.as_byte());
+        assert!(sent.is_valid());
+
+        // a failed delivery forgets the address, the next send asks the radio to find it
+        let mut status = Packet::new_transmit_status(sent.frame_id, [0x12, 0x34], 3, 0x21, 0x00);
+        devicedb.clear_sent_message(&mut status);
+        let sent = devicedb.send_task(task, false).unwrap();
+        assert_eq!(sent.network_address, UNKNOWN_NETWORK_ADDRESS);
+    }
+
+    #[test]
+    fn slave_112_is_not_a_pulse_request() {
+        let (_db, mut devicedb, _store) = open();
+        devicedb.add_device(Device::new(DeviceTypes::Bridge, BRIDGE, [0x12, 0x34]));
+        let sent = ModbusMessage::new_read_message(112, 3, 0, 1).as_bytes();
+        assert_eq!(sent[0], b'p');
+        let mut data = vec![112, 3, 2, 0xBE, 0xEF];
+        let crc = crc_helper(data.clone());
+        data.push((crc & 0x0FF) as u8);
+        data.push((crc >> 8) as u8);
+        let sample = devicedb.decode_sample(
+            Packet::new_transmit(&BRIDGE, &sent),
+            Packet::new_receive(&BRIDGE, [0x12, 0x34], 0x01, &data),
+            DeviceTypes::Bridge,
+        );
+        match sample {
+            Ok(Sample::Bridge(s)) => assert_eq!(s.slave, 112),
+            _ => panic!("slave 112 was not decoded as Modbus"),
+        }
+    }
+
+    #[test]
+    fn failed_delivery_frees_the_sent_message_with_its_id() {
+        let (_db, mut devicedb, _store) = open();
+        devicedb.add_device(Device::new(DeviceTypes::Bridge, BRIDGE, [0x12, 0x34]));
+        let read = |slave| {
+            let data = ModbusMessage::new_read_message(slave, 3, 0, 1).as_bytes();
+            let packet = Packet::new_transmit(&BRIDGE, &data);
+            Task::new(packet, TaskTypes::Single, 0, 0, DeviceTypes::Bridge)
+        };
+
+        let first = devicedb.send_task(read(5), false).unwrap();
+        let id = first.frame_id;
+        let mut status = Packet::new_transmit_status(id, [0x12, 0x34], 3, 0x21, 0x00);
+        devicedb.clear_sent_message(&mut status);
+        assert!(devicedb.devices[0].messages_sent.is_empty());
+
+        // take every other id so the next request gets the freed one
+        for _i in 0..254 {
+            devicedb
+                .frame_ids
+                .allocate(BRIDGE, false, DEFAULT_FRAME_TIMEOUT);
+        }
+        let second = devicedb.send_task(read(6), false).unwrap();
+        assert_eq!(second.frame_id, id);
+
+        let mut response = Packet::new_receive(&BRIDGE, [0x12, 0x34], 0x01, &[id, 6, 3, 2, 0, 1]);
+        let (_dev, sent) = devicedb.clear_sent_message(&mut response);
+        assert_eq!(sent.data[0], 6);
+        assert!(devicedb.devices[0].messages_sent.is_empty());
+    }
+
+    #[test]
+    fn applies_channel_config_to_meter_samples() {
+        const METER: [u8; 8] = [7, 7, 7, 7, 7, 7, 7, 7];
+        let (db, mut devicedb, store) = open();
+        devicedb.add_device(Device::new(DeviceTypes::PowerMeter, METER, [0x56, 0x78]));
+
+        let mut channels = vec![ChannelConfig::new("mains"), ChannelConfig::new("spare")];
+        channels[0].ct_ratio = 2.0;
+        channels[1].enabled = false;
+        let reply = devicedb.handle_message(Message::new_channel_config(METER, channels), &store);
+        assert!(matches!(reply, Message::ErrorMessage(m) if m == "Channels Stored"));
+        let reply =
+            devicedb.handle_message(Message::new_channel_config(BRIDGE, Vec::new()), &store);
+        assert!(matches!(reply, Message::ErrorMessage(m) if m == "Device Not Found"));
+
+        // first channel reads 0x010, 16 * 20 W before the CT ratio
+        let mut data = vec![0x01, 0x00, 0x20];
+        data.resize(36, 0);
+        let meter_packets = Message::new_packet(
+            Packet::new_receive(&METER, [0x56, 0x78], 0x01, &data),
+            Packet::new_transmit(&METER, &[POWER_COMMAND]),
+            DeviceTypes::PowerMeter,
+        );
+        devicedb.handle_message(meter_packets, &store);
+        let samples = store.get_samples(&SampleTypes::Meter, 10).unwrap();
+        match &samples[0].1 {
+            Sample::Meter(m) => {
+                assert_eq!(m.values, vec![640]);
+                assert_eq!(m.channels.len(), 1);
+                assert_eq!(m.channels[0].label, "mains");
+            }
+            other => panic!("expected a meter sample, got {:?}", other),
+        }
+
+        // the config is kept with the device
+        let reopened = DeviceDB::from_db(db).unwrap();
+        assert_eq!(reopened.devices[0].channels.len(), 2);
+    }
+}
@@ -0,0 +1,164 @@
+use std::time::{Duration, Instant};
+
+// Frame id 0 tells the radio not to send a Transmit Status, so it is never handed out
+const FIRST_FRAME_ID: u8 = 1;
+
+pub const DEFAULT_FRAME_TIMEOUT: Duration = Duration::from_secs(30);
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct InFlight {
+    pub id: u8,
+    pub address: [u8; 8],
+    pub broadcast: bool,
+    pub sent: Instant,
+    pub deadline: Instant,
+}
+
+#[derive(Clone, Debug)]
+pub struct FrameIdAllocator {
+    in_flight: Vec<Option<InFlight>>,
+    next: u8,
+}
+
+impl Default for FrameIdAllocator {
+    fn default() -> Self {
+        FrameIdAllocator::new()
+    }
+}
+
+impl FrameIdAllocator {
+    pub fn new() -> Self {
+        FrameIdAllocator {
+            in_flight: vec![None; 256],
+            next: FIRST_FRAME_ID,
+        }
+    }
+
+    // Hands out the next free id after the last one allocated, wrapping from 255
+    // back to 1. Returns None when all 255 ids are waiting on the radio.
+    pub fn allocate(&mut self, address: [u8; 8], broadcast: bool, timeout: Duration) -> Option<u8> {
+        let mut id = self.next;
+        for _i in 0..255 {
+            if self.in_flight[id as usize].is_none() {
+                let now = Instant::now();
+                self.in_flight[id as usize] = Some(InFlight {
+                    id,
+                    address,
+                    broadcast,
+                    sent: now,
+                    deadline: now + timeout,
+                });
+                self.next = next_id(id);
+                return Some(id);
+            }
+            id = next_id(id);
+        }
+        None
+    }
+
+    pub fn release(&mut self, id: u8) -> Option<InFlight> {
+        self.in_flight[id as usize].take()
+    }
+
+    // A Transmit Status only frees the id when delivery failed, otherwise the
+    // id stays reserved until the device's response comes back with it.
+    pub fn acknowledge(&mut self, id: u8, delivered: bool) -> Option<InFlight> {
+        match delivered {
+            true => None,
+            false => self.release(id),
+        }
+    }
+
+    // Broadcast ids collect responses from every device and are left to time out
+    pub fn respond(&mut self, id: u8) -> Option<InFlight> {
+        match &self.in_flight[id as usize] {
+            Some(f) if !f.broadcast => self.release(id),
+            _ => None,
+        }
+    }
+
+    pub fn expire(&mut self, now: Instant) -> Vec<InFlight> {
+        let mut expired = Vec::new();
+        for slot in self.in_flight.iter_mut() {
+            if slot.as_ref().is_some_and(|x| x.deadline <= now) {
+                expired.push(slot.take().unwrap());
+            }
+        }
+        expired
+    }
+
+    pub fn get(&self, id: u8) -> Option<&InFlight> {
+        self.in_flight[id as usize].as_ref()
+    }
+
+    pub fn in_flight_count(&self) -> usize {
+        self.in_flight.iter().filter(|x| x.is_some()).count()
+    }
+}
+
+fn next_id(id: u8) -> u8 {
+    match id {
+        255 => FIRST_FRAME_ID,
+        _ => id + 1,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const DEVICE: [u8; 8] = [1, 2, 3, 4, 5, 6, 7, 8];
+
+    #[test]
+    fn allocates_in_order_and_skips_zero() {
+        let mut ids = FrameIdAllocator::new();
+        assert_eq!(ids.allocate(DEVICE, false, DEFAULT_FRAME_TIMEOUT), Some(1));
+        assert_eq!(ids.allocate(DEVICE, false, DEFAULT_FRAME_TIMEOUT), Some(2));
+        ids.release(1);
+        // freed ids are only reused once the counter comes back round
+        assert_eq!(ids.allocate(DEVICE, false, DEFAULT_FRAME_TIMEOUT), Some(3));
+        assert_eq!(ids.in_flight_count(), 2);
+    }
+
+    #[test]
+    fn runs_out_after_255() {
+        let mut ids = FrameIdAllocator::new();
+        for _i in 0..255 {
+            assert!(ids.allocate(DEVICE, false, DEFAULT_FRAME_TIMEOUT).is_some());
+        }
+        assert_eq!(ids.allocate(DEVICE, false, DEFAULT_FRAME_TIMEOUT), None);
+        ids.release(200);
+        assert_eq!(
+            ids.allocate(DEVICE, false, DEFAULT_FRAME_TIMEOUT),
+            Some(200)
+        );
+        assert!(ids.get(0).is_none());
+    }
+
+    #[test]
+    fn status_and_response_free_ids() {
+        let mut ids = FrameIdAllocator::new();
+        let id = ids.allocate(DEVICE, false, DEFAULT_FRAME_TIMEOUT).unwrap();
+        assert_eq!(ids.acknowledge(id, true), None);
+        assert!(ids.get(id).is_some());
+        assert_eq!(ids.respond(id).map(|x| x.address), Some(DEVICE));
+        assert!(ids.get(id).is_none());
+
+        let id = ids.allocate(DEVICE, false, DEFAULT_FRAME_TIMEOUT).unwrap();
+        assert!(ids.acknowledge(id, false).is_some());
+        assert_eq!(ids.in_flight_count(), 0);
+    }
+
+    #[test]
+    fn broadcasts_wait_for_the_timeout() {
+        let mut ids = FrameIdAllocator::new();
+        let id = ids.allocate(DEVICE, true, Duration::from_secs(5)).unwrap();
+        assert_eq!(ids.respond(id), None);
+        let sent = ids.get(id).unwrap().sent;
+        assert!(ids.expire(sent + Duration::from_secs(4)).is_empty());
+        let expired = ids.expire(sent + Duration::from_secs(5));
+        assert_eq!(expired.len(), 1);
+        assert_eq!(expired[0].id, id);
+        assert_eq!(ids.in_flight_count(), 0);
+    }
+}
@@ -1,9 +1,24 @@
+pub mod at_command;
+pub mod clock;
 pub mod message;
 pub mod packet;
+pub mod pulse;
+pub mod query;
+pub mod read_plan;
+pub mod register_map;
+pub mod retention;
+pub mod rollup;
 pub mod samples;
 pub mod device;
+pub mod frame_id;
 pub mod task;
+pub mod validation;
 pub mod modbus;
+pub mod modbus_tcp;
+pub mod serial;
+#[cfg(test)]
+pub mod simulator;
+pub mod store;
 
 #[macro_use]
 extern crate serde_derive;
@@ -12,9 +27,16 @@ use reqwest::*;
 use std::thread;
 use std::time;
 use std::env;
+use std::sync::Mutex;
 use crate::message::*;
+use crate::clock::*;
+use crate::retention::EvictionReport;
 use crate::samples::*;
 use crate::device::*;
+use crate::register_map::*;
+use crate::pulse::*;
+use crate::rollup::*;
+use crate::validation::*;
 use futures::future::join_all;
 
 struct Config {
@@ -22,6 +44,12 @@ struct Config {
     dc_url_base: String,
     num_samples: u16,
     delay: u64,
+    register_maps: Vec<RegisterMap>,
+    pulse_factors: Vec<PulseFactors>,
+    rollup: Option<RollupInterval>,
+    // seconds the datacollector keeps raw samples after their rollup went out
+    archive_retention: Option<u64>,
+    validation_limits: ValidationLimits,
 }
 
 async fn get_devices(config: &Config, client: &Client) -> Vec<DeviceSummary> {
@@ -54,6 +82,97 @@ async fn get_devices(config: &Config, client: &Client) -> Vec<DeviceSummary> {
     }
 }
 
+// Passes the datacollector's eviction totals on so lost data shows up upstream.
+// They are cumulative, see EvictionReport, and only go out when they changed
+// since the last report ingest accepted.
+async fn report_evictions(config: &Config, client: &Client, reported: &mut Vec<EvictionReport>) {
+    let req = format!("{}/get/evictions", config.dc_url_base);
+
+    match client.get(req).send().await {
+        Ok(o) => {
+            match o.json::<Message>().await {
+                Ok(Message::Evictions(reports)) => {
+                    if reports == *reported {
+                        return
+                    }
+                    for r in reports.iter().filter(|x| x.counters.total() > 0) {
+                        eprintln!("{:?} samples evicted in total: {:?}", r.sample_type, r.counters);
+                    }
+                    let req = format!("{}/evictions/totals", config.ingest_url_base);
+                    match client.post(req).json(&reports).send().await {
+                        Ok(r) => {
+                            match r.status() {
+                                StatusCode::OK => {
+                                    *reported = reports;
+                                },
+                                _ => {
+                                    eprintln!("{}", r.status());
+                                }
+                            }
+                        },
+                        Err(e) => {
+                            eprintln!("{}", e);
+                        }
+                    }
+                },
+                Ok(r) => {
+                    eprintln!("Unexpected response type {:?}", r);
+                },
+                Err(e) => {
+                    eprintln!("{}", e);
+                }
+            }
+        },
+        Err(e) => {
+            eprintln!("Error: {}", e);
+        }
+    }
+}
+
+// Archived raw samples are dropped on the datacollector once they are older
+// than the retention, otherwise the archive grows without bound.
+async fn prune_archive(config: &Config, client: &Client) {
+    let secs = match (config.rollup, config.archive_retention) {
+        (Some(_i), Some(r)) => r,
+        _ => return,
+    };
+    let req = format!("{}/prune-archive/{}", config.dc_url_base, secs);
+
+    match client.post(req).send().await {
+        Ok(o) => {
+            match o.json::<Message>().await {
+                Ok(Message::ErrorMessage(s)) => {
+                    println!("prune archive: {}", s);
+                },
+                Ok(r) => {
+                    eprintln!("Unexpected response type {:?}", r);
+                },
+                Err(e) => {
+                    eprintln!("{}", e);
+                }
+            }
+        },
+        Err(e) => {
+            eprintln!("Error: {}", e);
+        }
+    }
+}
+
+// Lets the backend weigh gateway timestamps by how far the clock can be trusted
+async fn report_clock(config: &Config, client: &Client, quality: ClockQuality) {
+    let req = format!("{}/clock", config.ingest_url_base);
+    match client.post(req).json(&Message::new_clock_status(quality)).send().await {
+        Ok(r) => {
+            if r.status() != StatusCode::OK {
+                eprintln!("{}", r.status());
+            }
+        },
+        Err(e) => {
+            eprintln!("{}", e);
+        }
+    }
+}
+
 async fn clear_samples(url: &str, keys: Vec<Vec<u8>>, client: &Client) {
     let mut done = false;
 
@@ -91,202 +210,224 @@ async fn clear_samples(url: &str, keys: Vec<Vec<u8>>, client: &Client) {
     }
 }
 
-async fn get_pulse_samples(config: &Config, client: &Client, address: &[u8; 8]) {
-    let req = format!("{}/samples/pulse/{}", config.dc_url_base, config.num_samples);
-    let mut addr = Vec::new();
-    addr.extend_from_slice(address);
-    match client.post(req).json(&addr).send().await {
-        Ok(t) => {
-            match t.json::<Message>().await {
-                Ok(res) => {
-                    match res {
-                        Message::ErrorMessage(e) => {
-                            println!("{}",e);
-                        },
-                        Message::Samples(samples) => {
-                            let mut data: Vec<PulseSample> = Vec::new();
-                            let mut keys: Vec<Vec<u8>> = Vec::new();
-                            if samples.len() == 0 {
-                                eprintln!("Error: expected samples but found none");
-                                return
-                            }
-                            for sample in samples {
-                                match sample.1 {
-                                    Sample::Pulse(p) => {
-                                        data.push(p);
-                                        keys.push(sample.0);
-                                    }
-                                    _ => {
-                                        eprintln!("Error: Unexpected sample type");
-                                    }
-                                }
-                            }
+// What differs between the sample types on their way to ingest
+trait Upload: Sized + serde::Serialize {
+    // names the datacollector and ingest routes
+    const KIND: &'static str;
+    type Rollup: serde::Serialize;
 
-                            println!("got {} pulse samples for {:x?}", keys.len(), address);
-                            let req = format!("{}/samples/pulse", config.ingest_url_base);
-                            match client.post(req).json(&data).send().await {
-                                Ok(r) => {
-                                    match r.status() {
-                                        StatusCode::OK => {
-                                            let req = format!("{}/clear-samples/pulse", config.dc_url_base);
-                                            clear_samples(&req, keys, client).await;
-                                        },
-                                        _ => {
-                                            eprintln!("{}", r.status());
-                                        }
-                                    }
-                                },
-                                Err(e) => {
-                                    eprintln!("{}",e);
-                                },
-                            }
-                        },
-                        _ => {
-                            eprintln!("Error: Unexpected response type");
-                        }
-                    }
-                },
-                Err(e) => {
-                    eprintln!("{}", e);
-                }
+    fn from_sample(sample: Sample) -> Option<Self>;
+    fn set_flags(&mut self, flags: Vec<QualityFlag>);
+    fn rollup(data: &[Self], interval: RollupInterval) -> Vec<Self::Rollup>;
+
+    fn samples_path() -> String {
+        format!("samples/{}", Self::KIND)
+    }
+
+    // runs on every sample before sorting and validation
+    fn prepare(_sample: &mut Sample, _config: &Config) {}
+}
+
+impl Upload for PulseSample {
+    const KIND: &'static str = "pulse";
+    type Rollup = PulseRollup;
+
+    fn from_sample(sample: Sample) -> Option<Self> {
+        match sample {
+            Sample::Pulse(p) => Some(p),
+            _ => None,
+        }
+    }
+
+    fn set_flags(&mut self, flags: Vec<QualityFlag>) {
+        self.flags = flags;
+    }
+
+    fn rollup(data: &[Self], interval: RollupInterval) -> Vec<Self::Rollup> {
+        rollup_pulse(data, interval)
+    }
+}
+
+impl Upload for MeterSample {
+    const KIND: &'static str = "meter";
+    type Rollup = MeterRollup;
+
+    fn from_sample(sample: Sample) -> Option<Self> {
+        match sample {
+            Sample::Meter(p) => Some(p),
+            _ => None,
+        }
+    }
+
+    fn set_flags(&mut self, flags: Vec<QualityFlag>) {
+        self.flags = flags;
+    }
+
+    fn rollup(data: &[Self], interval: RollupInterval) -> Vec<Self::Rollup> {
+        rollup_meter(data, interval)
+    }
+
+    // v2: values went from u16 to u32 to fit energy readings, and
+    // voltage, current and power factor samples come through here too
+    fn samples_path() -> String {
+        "v2/samples/meter".to_string()
+    }
+}
+
+impl Upload for BridgeSample {
+    const KIND: &'static str = "bridge";
+    type Rollup = BridgeRollup;
+
+    fn from_sample(sample: Sample) -> Option<Self> {
+        match sample {
+            Sample::Bridge(p) => Some(p),
+            _ => None,
+        }
+    }
+
+    fn set_flags(&mut self, flags: Vec<QualityFlag>) {
+        self.flags = flags;
+    }
+
+    fn rollup(data: &[Self], interval: RollupInterval) -> Vec<Self::Rollup> {
+        rollup_bridge(data, interval)
+    }
+
+    // maps can set the device time, so they go before sorting and validation
+    fn prepare(sample: &mut Sample, config: &Config) {
+        if let Sample::Bridge(p) = sample {
+            for map in config.register_maps.iter() {
+                map.apply(p);
             }
-        },
-        Err(e) => {
-            eprintln!("{}", e);
         }
     }
 }
 
-async fn get_power_samples_json (config: &Config, client: &Client, address: &[u8; 8]) {
-    let req = format!("{}/samples/meter/{}", config.dc_url_base, config.num_samples);
+// Fetches, validates and uploads one batch of samples from a device, then
+// clears them from the datacollector. before_upload sees the batch after
+// validation. Returns false when samples were fetched but not uploaded, the
+// validator is already back where it was and they come back next time.
+async fn upload_samples<T: Upload, F: FnOnce(&mut Vec<T>)>(config: &Config, client: &Client, validator: &Mutex<Validator>, address: &[u8; 8], before_upload: F) -> bool {
+    let req = format!("{}/samples/{}/{}", config.dc_url_base, T::KIND, config.num_samples);
     let mut addr = Vec::new();
     addr.extend_from_slice(address);
-    match client.post(req).json(&addr).send().await {
+    let samples = match client.post(req).json(&addr).send().await {
         Ok(t) => {
             match t.json::<Message>().await {
-                Ok(res) => {
-                    match res {
-                        Message::ErrorMessage(e) => {
-                            eprintln!("{}",e);
-                        },
-                        Message::Samples(samples) => {
-                            let mut data: Vec<MeterSample> = Vec::new();
-                            let mut keys: Vec<Vec<u8>> = Vec::new();
-                            if samples.len() == 0 {
-                                eprintln!("Error: expected samples but found none");
-                                return
-                            }
-                            for sample in samples {
-                                match sample.1 {
-                                    Sample::Meter(p) => {
-                                        data.push(p);
-                                        keys.push(sample.0);
-                                    }
-                                    _ => {
-                                        eprintln!("Error: Unexpected sample type");
-                                    }
-                                }
-                            }
-
-                            let req = format!("{}/samples/meter", config.ingest_url_base);
-                            match client.post(req).json(&data).send().await {
-                                Ok(r) => {
-                                    match r.status() {
-                                        StatusCode::OK => {
-                                            let req = format!("{}/clear-samples/meter", config.dc_url_base);
-                                            clear_samples(&req, keys, &client).await;
-                                        },
-                                        _ => {
-                                            eprintln!("{}", r.status());
-                                        }
-                                    }
-                                },
-                                Err(e) => {
-                                    eprintln!("{}",e);
-                                },
-                            }
-                        }
-                        _ => {
-                            eprintln!("Error: Unexpected response type");
-                        }
-                    }
+                Ok(Message::Samples(samples)) => samples,
+                Ok(Message::ErrorMessage(e)) => {
+                    println!("{}", e);
+                    return true
+                },
+                Ok(_r) => {
+                    eprintln!("Error: Unexpected response type");
+                    return true
                 },
                 Err(e) => {
-                    eprintln!("{:?}", e);
+                    eprintln!("{}", e);
+                    return true
                 }
             }
         },
         Err(e) => {
-            eprintln!("{:?}", e);
+            eprintln!("{}", e);
+            return true
         }
-    }
-}
+    };
 
-async fn get_bridge_samples(config: &Config, client: &Client, address: &[u8; 8]) {
-    let req = format!("{}/samples/bridge/{}", config.dc_url_base, config.num_samples);
-    let mut addr = Vec::new();
-    addr.extend_from_slice(address);
-    match client.post(req).json(&addr).send().await {
-        Ok(t) => {
-            match t.json::<Message>().await {
-                Ok(res) => {
-                    match res {
-                        Message::ErrorMessage(e) => {
-                            eprintln!("{}",e);
-                        },
-                        Message::Samples(samples) => {
-                            let mut data: Vec<BridgeSample> = Vec::new();
-                            let mut keys: Vec<Vec<u8>> = Vec::new();
-                            if samples.len() == 0 {
-                                eprintln!("Error: expected samples but found none");
-                                return
-                            }
-                            for sample in samples {
-                                match sample.1 {
-                                    Sample::Bridge(p) => {
-                                        data.push(p);
-                                        keys.push(sample.0);
-                                    }
-                                    _ => {
-                                        eprintln!("Error: Unexpected sample type");
-                                    }
-                                }
-                            }
+    if samples.is_empty() {
+        eprintln!("Error: expected samples but found none");
+        return true
+    }
+    let mut samples = match config.rollup {
+        Some(interval) => interval.complete(samples, time_as_millis(time::SystemTime::now())),
+        None => samples,
+    };
+    if samples.is_empty() {
+        println!("waiting for rollup windows to close on {:x?}", address);
+        return true
+    }
+    for sample in samples.iter_mut() {
+        T::prepare(&mut sample.1, config);
+    }
+    // validation compares each sample with the one before it
+    samples.sort_by_key(|x| x.1.timestamp());
+    let snapshot = validator.lock().unwrap().snapshot(samples.iter().map(|x| &x.1));
+    let mut data: Vec<T> = Vec::new();
+    let mut keys: Vec<Vec<u8>> = Vec::new();
+    for sample in samples {
+        let flags = validator.lock().unwrap().validate(&sample.1);
+        match T::from_sample(sample.1) {
+            Some(mut p) => {
+                p.set_flags(flags);
+                data.push(p);
+                keys.push(sample.0);
+            },
+            None => {
+                eprintln!("Error: Unexpected sample type");
+            }
+        }
+    }
+    println!("got {} {} samples for {:x?}", keys.len(), T::KIND, address);
+    before_upload(&mut data);
 
-                            let req = format!("{}/samples/bridge", config.ingest_url_base);
-                            match client.post(req).json(&data).send().await {
-                                Ok(r) => {
-                                    match r.status() {
-                                        StatusCode::OK => {
-                                            let req = format!("{}/clear-samples/bridge", config.dc_url_base);
-                                            clear_samples(&req, keys, &client).await;
-                                        },
-                                        _ => {
-                                            eprintln!("{}", r.status());
-                                        }
-                                    }
-                                },
-                                Err(e) => {
-                                    eprintln!("{}",e);
-                                },
-                            }
-                        }
-                        _ => {
-                            eprintln!("Error: Unexpected response type");
-                        }
-                    }
+    let (req, body) = match config.rollup {
+        Some(interval) => (format!("{}/rollups/{}", config.ingest_url_base, T::KIND), serde_json::to_value(T::rollup(&data, interval))),
+        None => (format!("{}/{}", config.ingest_url_base, T::samples_path()), serde_json::to_value(&data)),
+    };
+    let body = match body {
+        Ok(b) => b,
+        Err(e) => {
+            eprintln!("Error: {}", e);
+            validator.lock().unwrap().restore(snapshot);
+            return false
+        }
+    };
+    match client.post(req).json(&body).send().await {
+        Ok(r) => {
+            match r.status() {
+                StatusCode::OK => {
+                    // only the rollup went upstream, so the raw samples are archived
+                    let req = match (config.rollup, config.archive_retention) {
+                        (Some(_i), Some(_r)) => format!("{}/clear-samples/{}?archive=true", config.dc_url_base, T::KIND),
+                        _ => format!("{}/clear-samples/{}", config.dc_url_base, T::KIND),
+                    };
+                    clear_samples(&req, keys, client).await;
+                    true
                 },
-                Err(e) => {
-                    eprintln!("{:?}", e);
+                _ => {
+                    eprintln!("{}", r.status());
+                    validator.lock().unwrap().restore(snapshot);
+                    false
                 }
             }
         },
         Err(e) => {
-            eprintln!("{:?}", e);
-        }
+            eprintln!("{}", e);
+            validator.lock().unwrap().restore(snapshot);
+            false
+        },
     }
 }
 
+async fn get_pulse_samples(config: &Config, client: &Client, tracker: &Mutex<PulseTracker>, validator: &Mutex<Validator>, address: &[u8; 8]) {
+    let mut previous = None;
+    let uploaded = upload_samples::<PulseSample, _>(config, client, validator, address, |data| {
+        previous = Some(tracker.lock().unwrap().track(data));
+    }).await;
+    // the samples come back next time, so the counters go back to where they were
+    if let (false, Some(p)) = (uploaded, previous) {
+        tracker.lock().unwrap().restore(address, p);
+    }
+}
+
+async fn get_power_samples_json (config: &Config, client: &Client, validator: &Mutex<Validator>, address: &[u8; 8]) {
+    upload_samples::<MeterSample, _>(config, client, validator, address, |_data| {}).await;
+}
+
+async fn get_bridge_samples(config: &Config, client: &Client, validator: &Mutex<Validator>, address: &[u8; 8]) {
+    upload_samples::<BridgeSample, _>(config, client, validator, address, |_data| {}).await;
+}
 
 #[tokio::main]
 async fn main() {
@@ -296,6 +437,11 @@ async fn main() {
         dc_url_base: "".to_string(),
         num_samples: 0,
         delay: 0,
+        register_maps: Vec::new(),
+        pulse_factors: Vec::new(),
+        rollup: None,
+        archive_retention: None,
+        validation_limits: ValidationLimits::default(),
     };
 
     match env::var("SAMPLE_INGEST_URL") {
@@ -354,25 +500,129 @@ async fn main() {
         }
     }
 
+    // optional, bridge samples are uploaded raw without it
+    match env::var("REGISTER_MAPS") {
+        Ok(val) => {
+            match RegisterMap::load(&val) {
+                Ok(maps) => {
+                    config.register_maps = maps;
+                },
+                Err(e) => {
+                    eprintln!("Error: {}", e);
+                    std::process::exit(1);
+                }
+            }
+        },
+        Err(_e) => {}
+    }
+
+    // optional, pulses are reported as raw counts without it
+    match env::var("PULSE_FACTORS") {
+        Ok(val) => {
+            match PulseFactors::load(&val) {
+                Ok(factors) => {
+                    config.pulse_factors = factors;
+                },
+                Err(e) => {
+                    eprintln!("Error: {}", e);
+                    std::process::exit(1);
+                }
+            }
+        },
+        Err(_e) => {}
+    }
+
+    // optional, full resolution samples are uploaded without it
+    match env::var("ROLLUP_INTERVAL") {
+        Ok(val) => {
+            match RollupInterval::parse(&val) {
+                Some(interval) => {
+                    config.rollup = Some(interval);
+                },
+                None => {
+                    eprintln!("Error: ROLLUP_INTERVAL must be 1m, 5m or 15m");
+                    std::process::exit(1);
+                }
+            }
+        },
+        Err(_e) => {}
+    }
+
+    // optional, uploaded samples are deleted straight away without it
+    match env::var("ARCHIVE_RETENTION_SECS") {
+        Ok(val) => {
+            match val.parse::<u64>() {
+                Ok(secs) => {
+                    config.archive_retention = Some(secs);
+                },
+                Err(e) => {
+                    eprintln!("Error {}", e);
+                    std::process::exit(1);
+                }
+            }
+        },
+        Err(_e) => {}
+    }
+    if config.archive_retention.is_some() && config.rollup.is_none() {
+        println!("ARCHIVE_RETENTION_SECS has no effect without ROLLUP_INTERVAL");
+    }
+
+    // optional, the defaults in ValidationLimits are used without it
+    match env::var("VALIDATION_LIMITS") {
+        Ok(val) => {
+            match ValidationLimits::load(&val) {
+                Ok(limits) => {
+                    config.validation_limits = limits;
+                },
+                Err(e) => {
+                    eprintln!("Error: {}", e);
+                    std::process::exit(1);
+                }
+            }
+        },
+        Err(_e) => {}
+    }
+
+    // counter state survives restarts so no pulses go uncounted
+    let tracker = match sled::open("pulse_state").and_then(|db| db.open_tree("counters")) {
+        Ok(tree) => match PulseTracker::from_tree(tree, config.pulse_factors.clone()) {
+            Ok(t) => Mutex::new(t),
+            Err(e) => {
+                eprintln!("Error: {}", e);
+                std::process::exit(1);
+            }
+        },
+        Err(e) => {
+            eprintln!("Error: {}", e);
+            std::process::exit(1);
+        }
+    };
+    let validator = Mutex::new(Validator::new(config.validation_limits.clone()));
+
     let client = reqwest::Client::builder().connection_verbose(true)
     .connect_timeout(time::Duration::from_millis(500))
     .timeout(time::Duration::from_millis(2000))
     .pool_idle_timeout(Some(time::Duration::from_secs(10)))
     .pool_max_idle_per_host(3)
     .build().unwrap();
+    let mut clock = ClockMonitor::default();
+    let mut reported_evictions = Vec::new();
     loop {
+        clock.check();
+        report_clock(&config, &client, clock.quality()).await;
         let device_list = get_devices(&config, &client).await;
+        report_evictions(&config, &client, &mut reported_evictions).await;
 
         let mut p_futures = Vec::new();
         let mut m_futures = Vec::new();
         let mut b_futures = Vec::new();
         device_list.iter().for_each(|x| match x.device_type {
             DeviceTypes::Bridge => {
-                p_futures.push(get_pulse_samples(&config, &client, &x.address));
-                b_futures.push(get_bridge_samples(&config, &client, &x.address));
+                p_futures.push(get_pulse_samples(&config, &client, &tracker, &validator, &x.address));
+                b_futures.push(get_bridge_samples(&config, &client, &validator, &x.address));
             },
             DeviceTypes::PowerMeter => {
-                m_futures.push(get_power_samples_json(&config, &client, &x.address));
+                m_futures.push(get_power_samples_json(&config, &client, &validator, &x.address));
             },
             _ => {},
         });
@@ -381,6 +631,7 @@ async fn main() {
         join_all(p_futures).await;
         join_all(b_futures).await;
         join_all(m_futures).await;
+        prune_archive(&config, &client).await;
 
         thread::sleep(time::Duration::from_millis(config.delay));
     }
@@ -1,5 +1,8 @@
+use crate::clock::ClockQuality;
 use crate::device::*;
 use crate::packet::*;
+use crate::query::*;
+use crate::retention::EvictionReport;
 use crate::samples::*;
 use crate::task::*;
 use std::sync::mpsc::*;
@@ -24,6 +27,14 @@ pub enum Message {
         sample_type: SampleTypes,
         keys: Vec<Vec<u8>>,
     },
+    ChannelConfig {
+        address: [u8; 8],
+        channels: Vec<ChannelConfig>,
+    },
+    ClockStatus(ClockQuality),
+    QuerySamples(SampleQuery),
+    SamplePage(SamplePage),
+    Evictions(Vec<EvictionReport>),
 }
 
 pub struct MessageCarrier {
@@ -59,6 +70,26 @@ impl Message {
         }
     }
 
+    pub fn new_channel_config(address: [u8; 8], channels: Vec<ChannelConfig>) -> Self {
+        Message::ChannelConfig { address, channels }
+    }
+
+    pub fn new_query_samples(query: SampleQuery) -> Self {
+        Message::QuerySamples(query)
+    }
+
+    pub fn new_sample_page(page: SamplePage) -> Self {
+        Message::SamplePage(page)
+    }
+
+    pub fn new_evictions(reports: Vec<EvictionReport>) -> Self {
+        Message::Evictions(reports)
+    }
+
+    pub fn new_clock_status(quality: ClockQuality) -> Self {
+        Message::ClockStatus(quality)
+    }
+
     pub fn new_error_message(error_message: String) -> Self {
         Message::ErrorMessage(error_message)
     }
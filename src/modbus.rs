@@ -1,4 +1,10 @@
-#[derive(Clone, Debug, Serialize, Deserialize)]
+use crate::samples::{time_as_millis, BridgeSample};
+use std::convert::TryInto;
+use std::time::{Duration, Instant, SystemTime};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio_serial::{SerialPortBuilderExt, SerialStream};
+
+#[derive(Clone, Debug, Hash, Serialize, Deserialize, PartialEq)]
 pub enum FunctionTypes {
     ReadCoilStatus = 0x01,
     ReadInputStatus = 0x02,
@@ -6,8 +12,8 @@ pub enum FunctionTypes {
     ReadInputRegisters = 0x04,
     WriteSingleCoil = 0x05,
     WriteSingleRegister = 0x06,
-    WriteMultipleCoils = 0x15,
-    WriteMultipleRegisters = 0x16,
+    WriteMultipleCoils = 0x0F,
+    WriteMultipleRegisters = 0x10,
     None,
 }
 
@@ -29,8 +35,59 @@ impl DataTypes {
     }
 }
 
+// Slaves answer with the function code's high bit set and one of these codes
+// when they can't carry out a request.
+#[derive(Clone, Copy, Debug, Hash, Serialize, Deserialize, PartialEq)]
+pub enum ModbusException {
+    IllegalFunction,
+    IllegalDataAddress,
+    IllegalDataValue,
+    SlaveDeviceFailure,
+    Acknowledge,
+    SlaveDeviceBusy,
+    NegativeAcknowledge,
+    MemoryParityError,
+    GatewayPathUnavailable,
+    GatewayTargetFailedToRespond,
+    Other(u8),
+}
+
+impl ModbusException {
+    pub fn new(code: u8) -> Self {
+        match code {
+            0x01 => ModbusException::IllegalFunction,
+            0x02 => ModbusException::IllegalDataAddress,
+            0x03 => ModbusException::IllegalDataValue,
+            0x04 => ModbusException::SlaveDeviceFailure,
+            0x05 => ModbusException::Acknowledge,
+            0x06 => ModbusException::SlaveDeviceBusy,
+            0x07 => ModbusException::NegativeAcknowledge,
+            0x08 => ModbusException::MemoryParityError,
+            0x0A => ModbusException::GatewayPathUnavailable,
+            0x0B => ModbusException::GatewayTargetFailedToRespond,
+            c => ModbusException::Other(c),
+        }
+    }
+
+    pub fn code(&self) -> u8 {
+        match self {
+            ModbusException::IllegalFunction => 0x01,
+            ModbusException::IllegalDataAddress => 0x02,
+            ModbusException::IllegalDataValue => 0x03,
+            ModbusException::SlaveDeviceFailure => 0x04,
+            ModbusException::Acknowledge => 0x05,
+            ModbusException::SlaveDeviceBusy => 0x06,
+            ModbusException::NegativeAcknowledge => 0x07,
+            ModbusException::MemoryParityError => 0x08,
+            ModbusException::GatewayPathUnavailable => 0x0A,
+            ModbusException::GatewayTargetFailedToRespond => 0x0B,
+            ModbusException::Other(c) => *c,
+        }
+    }
+}
+
 impl FunctionTypes {
-    fn new_function_type(val: u8) -> Self {
+    pub fn new_function_type(val: u8) -> Self {
         match val {
             0x01 => FunctionTypes::ReadCoilStatus,
             0x02 => FunctionTypes::ReadInputStatus,
@@ -38,11 +95,92 @@ impl FunctionTypes {
             0x04 => FunctionTypes::ReadInputRegisters,
             0x05 => FunctionTypes::WriteSingleCoil,
             0x06 => FunctionTypes::WriteSingleRegister,
-            0x15 => FunctionTypes::WriteMultipleCoils,
-            0x16 => FunctionTypes::WriteMultipleRegisters,
+            0x0F => FunctionTypes::WriteMultipleCoils,
+            0x10 => FunctionTypes::WriteMultipleRegisters,
             _ => FunctionTypes::None,
         }
     }
+
+    pub fn is_write(&self) -> bool {
+        matches!(
+            self,
+            FunctionTypes::WriteSingleCoil
+                | FunctionTypes::WriteSingleRegister
+                | FunctionTypes::WriteMultipleCoils
+                | FunctionTypes::WriteMultipleRegisters
+        )
+    }
+
+    // Most values one request can carry per the Modbus application protocol,
+    // None for functions without a quantity field
+    pub fn max_quantity(&self) -> Option<u16> {
+        match self {
+            FunctionTypes::ReadCoilStatus | FunctionTypes::ReadInputStatus => Some(2000),
+            FunctionTypes::ReadHoldingRegisters | FunctionTypes::ReadInputRegisters => Some(125),
+            FunctionTypes::WriteMultipleCoils => Some(1968),
+            FunctionTypes::WriteMultipleRegisters => Some(123),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum ModbusError {
+    CrcMismatch {
+        expected: [u8; 2],
+        received: [u8; 2],
+    },
+    Truncated,
+    // zero or more than the function allows
+    InvalidQuantity(u16),
+}
+
+impl std::fmt::Display for ModbusError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            ModbusError::CrcMismatch { expected, received } => write!(
+                f,
+                "CRC mismatch: expected {:02X?}, received {:02X?}",
+                expected, received
+            ),
+            ModbusError::Truncated => write!(f, "truncated modbus frame"),
+            ModbusError::InvalidQuantity(q) => write!(f, "invalid quantity {}", q),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum TransportError {
+    Io(std::io::Error),
+    Modbus(ModbusError),
+    Timeout,
+    // only writes can go to the broadcast address
+    NotBroadcastable(FunctionTypes),
+}
+
+impl std::fmt::Display for TransportError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            TransportError::Io(e) => write!(f, "{}", e),
+            TransportError::Modbus(e) => write!(f, "{}", e),
+            TransportError::Timeout => write!(f, "timed out waiting for response"),
+            TransportError::NotBroadcastable(function) => {
+                write!(f, "{:?} can't be broadcast", function)
+            }
+        }
+    }
+}
+
+impl From<std::io::Error> for TransportError {
+    fn from(e: std::io::Error) -> Self {
+        TransportError::Io(e)
+    }
+}
+
+impl From<ModbusError> for TransportError {
+    fn from(e: ModbusError) -> Self {
+        TransportError::Modbus(e)
+    }
 }
 
 #[derive(Clone, Debug)]
@@ -54,48 +192,174 @@ pub struct ModbusMessage {
     pub data_type: DataTypes,
     pub start_address: u16,
     pub num_data_points: u16,
+    pub exception: Option<ModbusException>,
 }
 
-fn calculate_crc(message: ModbusMessage) -> [u8; 2] {
-    let mut ret: [u8; 2] = [0; 2];
-    let mut data: Vec<u8> = Vec::new();
+// Byte order of a value spread over several registers, named after the
+// letters of a 32-bit value 0xAABBCCDD as it arrives on the wire. Meter
+// manuals mostly say "little endian" when they mean the low word comes first,
+// so "little" in a register map is CD AB and full byte reversal has its own name.
+#[derive(Clone, Copy, Debug, Default, Hash, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum WordOrder {
+    // AB CD, the Modbus default
+    #[default]
+    Big,
+    // CD AB, low word first
+    #[serde(alias = "little")]
+    WordSwapped,
+    // DC BA, every byte reversed
+    LittleEndian,
+    // BA DC, each register byte swapped
+    ByteSwapped,
+}
 
-    data.push(message.address);
-    data.push(message.function.clone() as u8);
-    data.push((message.start_address >> 8) as u8);
-    data.push((message.start_address & 0x0FF) as u8);
+#[derive(Clone, Copy, Debug, Hash, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ValueType {
+    U16,
+    I16,
+    U32,
+    I32,
+    F32,
+    F64,
+    String,
+}
 
-    match message.function {
-        FunctionTypes::WriteSingleCoil
-        | FunctionTypes::ReadCoilStatus
-        | FunctionTypes::WriteSingleRegister
-        | FunctionTypes::None => {}
-        FunctionTypes::ReadHoldingRegisters
-        | FunctionTypes::ReadInputRegisters
-        | FunctionTypes::ReadInputStatus
-        | FunctionTypes::WriteMultipleCoils
-        | FunctionTypes::WriteMultipleRegisters => {
-            data.push((message.num_data_points >> 8) as u8);
-            data.push((message.num_data_points & 0x0FF) as u8);
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub enum RegisterValue {
+    U16(u16),
+    I16(i16),
+    U32(u32),
+    I32(i32),
+    F32(f32),
+    F64(f64),
+    Text(String),
+}
+
+impl ValueType {
+    // Strings take whatever they are given, two characters per register
+    pub fn register_count(&self) -> Option<u16> {
+        match self {
+            ValueType::U16 | ValueType::I16 => Some(1),
+            ValueType::U32 | ValueType::I32 | ValueType::F32 => Some(2),
+            ValueType::F64 => Some(4),
+            ValueType::String => None,
         }
     }
+}
 
-    match message.function {
-        FunctionTypes::WriteSingleCoil
-        | FunctionTypes::WriteSingleRegister
-        | FunctionTypes::WriteMultipleCoils
-        | FunctionTypes::WriteMultipleRegisters => data.append(&mut message.data.clone()),
-        _ => {}
+// Coils and discrete inputs are packed eight to a byte, the first point in the
+// least significant bit of the first byte and the unused high bits of the last
+// byte zero. Stops early if the data runs out before count points.
+pub fn unpack_bits(data: &[u8], count: u16) -> Vec<bool> {
+    let mut bits = Vec::new();
+    for i in 0..count as usize {
+        match data.get(i / 8) {
+            Some(byte) => bits.push((byte >> (i % 8)) & 0x01 == 0x01),
+            None => break,
+        }
     }
+    bits
+}
 
-    let crc_int = crc_helper(data);
-    ret[0] = ((crc_int as u16) & 0x0FF) as u8;
-    ret[1] = (((crc_int as u16) >> 8) & 0x0FF) as u8;
+pub fn pack_bits(bits: &[bool]) -> Vec<u8> {
+    let mut data = vec![0u8; bits.len().div_ceil(8)];
+    for (i, bit) in bits.iter().enumerate() {
+        if *bit {
+            data[i / 8] |= 0x01 << (i % 8);
+        }
+    }
+    data
+}
+
+// Puts the bytes of a multi-register value into big-endian order
+pub fn order_bytes(registers: &[u16], order: WordOrder) -> Vec<u8> {
+    let mut words = registers.to_vec();
+    match order {
+        WordOrder::Big => {}
+        WordOrder::LittleEndian => {
+            words.reverse();
+            words.iter_mut().for_each(|x| *x = x.swap_bytes());
+        }
+        WordOrder::WordSwapped => words.reverse(),
+        WordOrder::ByteSwapped => words.iter_mut().for_each(|x| *x = x.swap_bytes()),
+    }
+    words.iter().flat_map(|x| x.to_be_bytes()).collect()
+}
+
+impl RegisterValue {
+    pub fn decode(registers: &[u16], value_type: ValueType, order: WordOrder) -> Option<Self> {
+        let count = match value_type.register_count() {
+            Some(c) => c as usize,
+            None => registers.len(),
+        };
+        if count == 0 || registers.len() < count {
+            return None;
+        }
+        let bytes = order_bytes(&registers[..count], order);
+        let value = match value_type {
+            ValueType::U16 => RegisterValue::U16(u16::from_be_bytes([bytes[0], bytes[1]])),
+            ValueType::I16 => RegisterValue::I16(i16::from_be_bytes([bytes[0], bytes[1]])),
+            ValueType::U32 => RegisterValue::U32(u32::from_be_bytes(bytes[..4].try_into().ok()?)),
+            ValueType::I32 => RegisterValue::I32(i32::from_be_bytes(bytes[..4].try_into().ok()?)),
+            ValueType::F32 => RegisterValue::F32(f32::from_be_bytes(bytes[..4].try_into().ok()?)),
+            ValueType::F64 => RegisterValue::F64(f64::from_be_bytes(bytes[..8].try_into().ok()?)),
+            ValueType::String => {
+                let text = String::from_utf8_lossy(&bytes);
+                RegisterValue::Text(text.trim_end_matches(['\0', ' ']).to_string())
+            }
+        };
+        Some(value)
+    }
+
+    pub fn as_f64(&self) -> Option<f64> {
+        match self {
+            RegisterValue::U16(v) => Some(*v as f64),
+            RegisterValue::I16(v) => Some(*v as f64),
+            RegisterValue::U32(v) => Some(*v as f64),
+            RegisterValue::I32(v) => Some(*v as f64),
+            RegisterValue::F32(v) => Some(*v as f64),
+            RegisterValue::F64(v) => Some(*v),
+            RegisterValue::Text(_s) => None,
+        }
+    }
+}
+
+// Samples derive Hash, so floats are hashed by their bits
+impl std::hash::Hash for RegisterValue {
+    fn hash<H: std::hash::Hasher>(&self, state: &mut H) {
+        std::mem::discriminant(self).hash(state);
+        match self {
+            RegisterValue::U16(v) => v.hash(state),
+            RegisterValue::I16(v) => v.hash(state),
+            RegisterValue::U32(v) => v.hash(state),
+            RegisterValue::I32(v) => v.hash(state),
+            RegisterValue::F32(v) => v.to_bits().hash(state),
+            RegisterValue::F64(v) => v.to_bits().hash(state),
+            RegisterValue::Text(s) => s.hash(state),
+        }
+    }
+}
+
+fn check_quantity(function: &FunctionTypes, quantity: u16) -> Result<(), ModbusError> {
+    match function.max_quantity() {
+        Some(max) if quantity == 0 || quantity > max => Err(ModbusError::InvalidQuantity(quantity)),
+        _ => Ok(()),
+    }
+}
+
+fn calculate_crc(message: ModbusMessage) -> [u8; 2] {
+    let mut ret: [u8; 2] = [0; 2];
+
+    let crc_int = crc_helper(message.frame_bytes());
+    ret[0] = (crc_int & 0x0FF) as u8;
+    ret[1] = ((crc_int >> 8) & 0x0FF) as u8;
 
     ret
 }
 
-fn crc_helper(data: Vec<u8>) -> u16 {
+pub fn crc_helper(data: Vec<u8>) -> u16 {
     let mut len = data.len();
     let crc_table = [
         0x0000, 0xC0C1, 0xC181, 0x0140, 0xC301, 0x03C0, 0x0280, 0xC241, 0xC601, 0x06C0, 0x0780,
@@ -136,9 +400,34 @@ fn crc_helper(data: Vec<u8>) -> u16 {
     crc_word
 } // End: CRC16
 
+// Works out the full length of an RTU response from its first few bytes, or
+// None if more bytes are needed before we can tell.
+pub fn rtu_response_length(partial: &[u8]) -> Option<usize> {
+    if partial.len() < 2 {
+        return None;
+    }
+    if partial[1] & 0x80 != 0 {
+        return Some(5);
+    }
+    match FunctionTypes::new_function_type(partial[1]) {
+        FunctionTypes::ReadCoilStatus
+        | FunctionTypes::ReadInputStatus
+        | FunctionTypes::ReadHoldingRegisters
+        | FunctionTypes::ReadInputRegisters => partial.get(2).map(|x| 3 + *x as usize + 2),
+        FunctionTypes::WriteSingleCoil
+        | FunctionTypes::WriteSingleRegister
+        | FunctionTypes::WriteMultipleCoils
+        | FunctionTypes::WriteMultipleRegisters => Some(8),
+        FunctionTypes::None => Some(partial.len()),
+    }
+}
+
 impl ModbusMessage {
-    pub fn sent_from_data(mut src: Vec<u8>) -> Self {
-        //writing multiple coils or registers not supported yet
+    pub fn sent_from_data(mut src: Vec<u8>) -> Result<Self, ModbusError> {
+        //address, function, start address and CRC are in every request we send
+        if src.len() < 6 {
+            return Err(ModbusError::Truncated);
+        }
         let add = src.remove(0);
         let func = FunctionTypes::new_function_type(src.remove(0));
         let mut start_address: u16 = (src.remove(0) as u16) << 8;
@@ -167,14 +456,40 @@ impl ModbusMessage {
             | FunctionTypes::ReadHoldingRegisters
             | FunctionTypes::ReadInputRegisters
             | FunctionTypes::ReadInputStatus => {
+                if src.len() < 4 {
+                    return Err(ModbusError::Truncated);
+                }
                 num_data_points = (src.remove(0) as u16) << 8;
                 num_data_points |= src.remove(0) as u16;
+                check_quantity(&func, num_data_points)?;
             }
             FunctionTypes::WriteSingleCoil | FunctionTypes::WriteSingleRegister => {
+                if src.len() < 4 {
+                    return Err(ModbusError::Truncated);
+                }
                 data_vec.push(src.remove(0));
                 data_vec.push(src.remove(0));
             }
-            FunctionTypes::WriteMultipleCoils | FunctionTypes::WriteMultipleRegisters => {}
+            FunctionTypes::WriteMultipleCoils | FunctionTypes::WriteMultipleRegisters => {
+                if src.len() < 5 || src.len() < src[2] as usize + 5 {
+                    return Err(ModbusError::Truncated);
+                }
+                num_data_points = (src.remove(0) as u16) << 8;
+                num_data_points |= src.remove(0) as u16;
+                check_quantity(&func, num_data_points)?;
+                let byte_count = src.remove(0);
+                //the byte count has to cover every value the quantity asks for
+                let needed = match func {
+                    FunctionTypes::WriteMultipleCoils => (num_data_points as u32).div_ceil(8),
+                    _ => num_data_points as u32 * 2,
+                };
+                if (byte_count as u32) < needed {
+                    return Err(ModbusError::Truncated);
+                }
+                for _i in 0..byte_count {
+                    data_vec.push(src.remove(0));
+                }
+            }
             _ => {}
         }
 
@@ -189,15 +504,34 @@ impl ModbusMessage {
             crc: crc_slice,
             num_data_points: num_data_points,
             data_type: data_type,
+            exception: None,
         };
         println!("sent message: {:?}", ret);
-        ret
+        Ok(ret)
     }
 
-    pub fn received_from_data(mut message_vec: Vec<u8>) -> Self {
-        //writing multiple coils or registers not supported
+    pub fn received_from_data(mut message_vec: Vec<u8>) -> Result<Self, ModbusError> {
+        if message_vec.len() < 5 {
+            return Err(ModbusError::Truncated);
+        }
+        //check the CRC over the bytes we actually got, not a frame rebuilt from them
+        let crc_int = crc_helper(message_vec[..message_vec.len() - 2].to_vec());
+        let expected = [(crc_int & 0x0FF) as u8, (crc_int >> 8) as u8];
+        let received_crc = [
+            message_vec[message_vec.len() - 2],
+            message_vec[message_vec.len() - 1],
+        ];
+        if expected != received_crc {
+            return Err(ModbusError::CrcMismatch {
+                expected,
+                received: received_crc,
+            });
+        }
+
         let add = message_vec.remove(0);
-        let func = FunctionTypes::new_function_type(message_vec.remove(0));
+        let func_code = message_vec.remove(0);
+        let func = FunctionTypes::new_function_type(func_code & 0x7F);
+        let mut exception = None;
         let mut data_type = DataTypes::None;
         match func {
             FunctionTypes::ReadCoilStatus
@@ -216,23 +550,35 @@ impl ModbusMessage {
         let mut data_vec = Vec::new();
         let mut num_data_points = 0;
         match func {
+            _ if func_code & 0x80 != 0 => {
+                exception = Some(ModbusException::new(message_vec.remove(0)));
+            }
             FunctionTypes::ReadCoilStatus
             | FunctionTypes::ReadHoldingRegisters
             | FunctionTypes::ReadInputRegisters
             | FunctionTypes::ReadInputStatus => {
                 let byte_count = message_vec.remove(0);
+                if message_vec.len() < byte_count as usize + 2 {
+                    return Err(ModbusError::Truncated);
+                }
                 for _i in 0..byte_count {
                     data_vec.push(message_vec.remove(0));
                 }
             }
             FunctionTypes::WriteSingleCoil | FunctionTypes::WriteSingleRegister => {
+                if message_vec.len() < 6 {
+                    return Err(ModbusError::Truncated);
+                }
                 start_address = (message_vec.remove(0) as u16) << 8;
                 start_address |= message_vec.remove(0) as u16;
                 data_vec.push(message_vec.remove(0));
                 data_vec.push(message_vec.remove(0));
             }
             FunctionTypes::WriteMultipleCoils | FunctionTypes::WriteMultipleRegisters => {
-                //this isn't supported yet
+                //the slave echoes the start address and quantity written
+                if message_vec.len() < 6 {
+                    return Err(ModbusError::Truncated);
+                }
                 start_address = (message_vec.remove(0) as u16) << 8;
                 start_address |= message_vec.remove(0) as u16;
                 num_data_points = (message_vec.remove(0) as u16) << 8;
@@ -241,8 +587,7 @@ impl ModbusMessage {
             _ => {}
         }
 
-        crc[0] = message_vec.remove(0);
-        crc[1] = message_vec.remove(0);
+        crc.copy_from_slice(&received_crc);
         let ret = ModbusMessage {
             start_address: start_address,
             address: add,
@@ -251,9 +596,21 @@ impl ModbusMessage {
             crc: crc,
             num_data_points: num_data_points,
             data_type: data_type,
+            exception,
         };
         print!("received: {:?}", ret);
-        ret
+        Ok(ret)
+    }
+
+    // Modbus TCP carries the PDU without the address and CRC, so we put them
+    // back to share the RTU parser.
+    pub fn received_from_pdu(unit_id: u8, pdu: &[u8]) -> Result<Self, ModbusError> {
+        let mut frame = vec![unit_id];
+        frame.extend_from_slice(pdu);
+        let crc_int = crc_helper(frame.clone());
+        frame.push((crc_int & 0x0FF) as u8);
+        frame.push((crc_int >> 8) as u8);
+        ModbusMessage::received_from_data(frame)
     }
 
     pub fn new_write_message(
@@ -286,6 +643,7 @@ impl ModbusMessage {
             crc: [0; 2],
             num_data_points: num_data_points,
             data_type: data_type,
+            exception: None,
         };
 
         let new_crc = calculate_crc(message.clone());
@@ -294,13 +652,37 @@ impl ModbusMessage {
         message
     }
 
+    pub fn new_write_multiple_coils(add: u8, start_address: u16, values: &[bool]) -> Self {
+        ModbusMessage::new_write_message(
+            add,
+            FunctionTypes::WriteMultipleCoils as u8,
+            start_address,
+            values.len() as u16,
+            pack_bits(values),
+        )
+    }
+
+    pub fn new_write_multiple_registers(add: u8, start_address: u16, values: &[u16]) -> Self {
+        let mut write_data = Vec::new();
+        for v in values {
+            write_data.push((v >> 8) as u8);
+            write_data.push((v & 0x0FF) as u8);
+        }
+        ModbusMessage::new_write_message(
+            add,
+            FunctionTypes::WriteMultipleRegisters as u8,
+            start_address,
+            values.len() as u16,
+            write_data,
+        )
+    }
+
     pub fn new_read_message(add: u8, func: u8, start_address: u16, num_data_points: u16) -> Self {
         ModbusMessage::new_write_message(add, func, start_address, num_data_points, Vec::new())
     }
 
-    pub fn as_bytes(&mut self) -> Vec<u8> {
-        //Currently This does not support writing multiple coils or registers
-
+    // the request as it goes on the wire, minus the trailing CRC
+    fn frame_bytes(&self) -> Vec<u8> {
         let mut data: Vec<u8> = Vec::new();
 
         data.push(self.address);
@@ -324,14 +706,465 @@ impl ModbusMessage {
         }
 
         match self.function {
-            FunctionTypes::WriteSingleCoil
-            | FunctionTypes::WriteSingleRegister
-            | FunctionTypes::WriteMultipleCoils
-            | FunctionTypes::WriteMultipleRegisters => data.append(&mut self.data.clone()),
+            FunctionTypes::WriteSingleCoil | FunctionTypes::WriteSingleRegister => {
+                data.append(&mut self.data.clone())
+            }
+            FunctionTypes::WriteMultipleCoils | FunctionTypes::WriteMultipleRegisters => {
+                data.push(self.data.len() as u8);
+                data.append(&mut self.data.clone());
+            }
             _ => {}
         }
 
-        data.extend_from_slice(&self.crc.clone());
         data
     }
+
+    // Register contents of a read response, or of a write request
+    pub fn registers(&self) -> Vec<u16> {
+        match self.data_type {
+            DataTypes::Register => self
+                .data
+                .chunks_exact(2)
+                .map(|x| u16::from_be_bytes([x[0], x[1]]))
+                .collect(),
+            _ => Vec::new(),
+        }
+    }
+
+    pub fn value_at(
+        &self,
+        index: usize,
+        value_type: ValueType,
+        order: WordOrder,
+    ) -> Option<RegisterValue> {
+        RegisterValue::decode(self.registers().get(index..)?, value_type, order)
+    }
+
+    pub fn u32_at(&self, index: usize, order: WordOrder) -> Option<u32> {
+        match self.value_at(index, ValueType::U32, order)? {
+            RegisterValue::U32(v) => Some(v),
+            _ => None,
+        }
+    }
+
+    pub fn i32_at(&self, index: usize, order: WordOrder) -> Option<i32> {
+        match self.value_at(index, ValueType::I32, order)? {
+            RegisterValue::I32(v) => Some(v),
+            _ => None,
+        }
+    }
+
+    pub fn f32_at(&self, index: usize, order: WordOrder) -> Option<f32> {
+        match self.value_at(index, ValueType::F32, order)? {
+            RegisterValue::F32(v) => Some(v),
+            _ => None,
+        }
+    }
+
+    pub fn f64_at(&self, index: usize, order: WordOrder) -> Option<f64> {
+        match self.value_at(index, ValueType::F64, order)? {
+            RegisterValue::F64(v) => Some(v),
+            _ => None,
+        }
+    }
+
+    pub fn string_at(&self, index: usize, registers: usize) -> Option<String> {
+        let all = self.registers();
+        match RegisterValue::decode(
+            all.get(index..index + registers)?,
+            ValueType::String,
+            WordOrder::Big,
+        )? {
+            RegisterValue::Text(s) => Some(s),
+            _ => None,
+        }
+    }
+
+    pub fn pdu_bytes(&self) -> Vec<u8> {
+        self.frame_bytes().split_off(1)
+    }
+
+    pub fn as_bytes(&mut self) -> Vec<u8> {
+        let mut data = self.frame_bytes();
+        data.extend_from_slice(&self.crc);
+        data
+    }
+}
+
+pub const BROADCAST_ADDRESS: u8 = 0;
+pub const DEFAULT_RTU_TIMEOUT: Duration = Duration::from_millis(500);
+pub const DEFAULT_RTU_RETRIES: u8 = 2;
+// Slaves need time to act on a broadcast before the next request goes out
+pub const DEFAULT_TURNAROUND_DELAY: Duration = Duration::from_millis(100);
+
+// 3.5 character times of silence separate RTU frames. A character is 11 bits on
+// the wire, and above 19200 baud the spec fixes the gap at 1.75ms.
+pub fn silent_interval(baud_rate: u32) -> Duration {
+    match baud_rate {
+        0 => Duration::from_micros(1750),
+        b if b > 19200 => Duration::from_micros(1750),
+        b => Duration::from_micros(38_500_000 / b as u64),
+    }
+}
+
+// Polls slaves on a directly attached RS-485 line. Takes any byte stream so it
+// can run over a pty pair in tests, see open for a real port.
+// silence is the 3.5 character gap kept before every request. USB adapters hand
+// received bytes over in bursts, so a reply only counts as finished once the
+// line has been quiet for frame_gap, twice the silence by default.
+pub struct RtuMaster<T> {
+    port: T,
+    pub hardware_id: [u8; 8],
+    pub silence: Duration,
+    pub frame_gap: Duration,
+    pub timeout: Duration,
+    pub retries: u8,
+    pub turnaround_delay: Duration,
+    last_activity: Instant,
+}
+
+impl RtuMaster<SerialStream> {
+    pub fn open(path: &str, baud_rate: u32, hardware_id: [u8; 8]) -> Result<Self, TransportError> {
+        let port = tokio_serial::new(path, baud_rate)
+            .parity(tokio_serial::Parity::None)
+            .stop_bits(tokio_serial::StopBits::One)
+            .open_native_async()
+            .map_err(|e| TransportError::Io(e.into()))?;
+        Ok(RtuMaster::new(port, baud_rate, hardware_id))
+    }
+}
+
+impl<T: AsyncRead + AsyncWrite + Unpin> RtuMaster<T> {
+    pub fn new(port: T, baud_rate: u32, hardware_id: [u8; 8]) -> Self {
+        let silence = silent_interval(baud_rate);
+        RtuMaster {
+            port,
+            hardware_id,
+            silence,
+            frame_gap: silence * 2,
+            timeout: DEFAULT_RTU_TIMEOUT,
+            retries: DEFAULT_RTU_RETRIES,
+            turnaround_delay: DEFAULT_TURNAROUND_DELAY,
+            last_activity: Instant::now(),
+        }
+    }
+
+    pub async fn transact(
+        &mut self,
+        request: &mut ModbusMessage,
+    ) -> Result<ModbusMessage, TransportError> {
+        let frame = request.as_bytes();
+        let mut attempt = 0;
+        loop {
+            self.send(&frame).await?;
+            let result = match self.receive().await {
+                Ok(response) => ModbusMessage::received_from_data(response).map_err(|e| e.into()),
+                Err(e) => Err(e),
+            };
+            match result {
+                Ok(response) if response.address == request.address => return Ok(response),
+                Ok(response) => {
+                    eprintln!(
+                        "expected response from slave {}, got {}",
+                        request.address, response.address
+                    );
+                }
+                Err(TransportError::Io(e)) => return Err(TransportError::Io(e)),
+                Err(e) if attempt >= self.retries => return Err(e),
+                Err(e) => eprintln!("retrying slave {}: {}", request.address, e),
+            }
+            if attempt >= self.retries {
+                return Err(TransportError::Timeout);
+            }
+            attempt += 1;
+            self.discard().await?;
+        }
+    }
+
+    // Nobody answers a broadcast, so it is only ever a write and never retried
+    pub async fn broadcast(&mut self, request: &mut ModbusMessage) -> Result<(), TransportError> {
+        if !request.function.is_write() {
+            return Err(TransportError::NotBroadcastable(request.function.clone()));
+        }
+        request.address = BROADCAST_ADDRESS;
+        let frame = request.as_bytes();
+        self.send(&frame).await?;
+        tokio::time::sleep(self.turnaround_delay).await;
+        self.last_activity = Instant::now();
+        Ok(())
+    }
+
+    pub async fn poll(
+        &mut self,
+        mut request: ModbusMessage,
+    ) -> Result<BridgeSample, TransportError> {
+        let sent = time_as_millis(SystemTime::now());
+        let mut sample = match request.address {
+            BROADCAST_ADDRESS => {
+                self.broadcast(&mut request).await?;
+                // written values come from the request, there is no response to read
+                BridgeSample::from_modbus(self.hardware_id, &request, &request)?
+            }
+            _ => {
+                let response = self.transact(&mut request).await?;
+                BridgeSample::from_modbus(self.hardware_id, &request, &response)?
+            }
+        };
+        sample.times.sent = Some(sent);
+        Ok(sample)
+    }
+
+    async fn send(&mut self, frame: &[u8]) -> Result<(), TransportError> {
+        let quiet_until = self.last_activity + self.silence;
+        tokio::time::sleep_until(quiet_until.into()).await;
+        self.port.write_all(frame).await?;
+        self.port.flush().await?;
+        self.last_activity = Instant::now();
+        Ok(())
+    }
+
+    // Reads until the frame is as long as its header says, or until the line
+    // goes quiet for frame_gap after the first byte.
+    async fn receive(&mut self) -> Result<Vec<u8>, TransportError> {
+        let deadline = tokio::time::Instant::now() + self.timeout;
+        let mut frame = Vec::new();
+        let mut buf = [0u8; 256];
+        loop {
+            let wait = match frame.is_empty() {
+                true => tokio::time::timeout_at(deadline, self.port.read(&mut buf)).await,
+                false => tokio::time::timeout(self.frame_gap, self.port.read(&mut buf)).await,
+            };
+            match wait {
+                Ok(read) => {
+                    let n = read?;
+                    if n == 0 {
+                        return Err(TransportError::Io(std::io::ErrorKind::UnexpectedEof.into()));
+                    }
+                    frame.extend_from_slice(&buf[..n]);
+                    self.last_activity = Instant::now();
+                }
+                Err(_elapsed) if frame.is_empty() => return Err(TransportError::Timeout),
+                Err(_elapsed) => return Ok(frame),
+            }
+            if let Some(length) = rtu_response_length(&frame) {
+                if frame.len() >= length {
+                    frame.truncate(length);
+                    return Ok(frame);
+                }
+            }
+        }
+    }
+
+    // Throws away whatever is left of a bad or late reply before retrying
+    async fn discard(&mut self) -> Result<(), TransportError> {
+        let mut buf = [0u8; 256];
+        while let Ok(read) = tokio::time::timeout(self.frame_gap, self.port.read(&mut buf)).await {
+            if read? == 0 {
+                break;
+            }
+            self.last_activity = Instant::now();
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // 0x11223344 as it arrives in each word order
+    const ORDERS: [(WordOrder, [u16; 2]); 4] = [
+        (WordOrder::Big, [0x1122, 0x3344]),
+        (WordOrder::WordSwapped, [0x3344, 0x1122]),
+        (WordOrder::LittleEndian, [0x4433, 0x2211]),
+        (WordOrder::ByteSwapped, [0x2211, 0x4433]),
+    ];
+
+    #[test]
+    fn decodes_every_word_order() {
+        for (order, registers) in ORDERS.iter() {
+            assert_eq!(
+                RegisterValue::decode(registers, ValueType::U32, *order),
+                Some(RegisterValue::U32(0x11223344)),
+                "{:?}",
+                order
+            );
+        }
+    }
+
+    #[test]
+    fn little_means_low_word_first() {
+        let order: WordOrder = serde_json::from_str("\"little\"").unwrap();
+        assert_eq!(order, WordOrder::WordSwapped);
+        let order: WordOrder = serde_json::from_str("\"little_endian\"").unwrap();
+        assert_eq!(order, WordOrder::LittleEndian);
+        assert_eq!(
+            serde_json::to_string(&WordOrder::WordSwapped).unwrap(),
+            "\"word_swapped\""
+        );
+    }
+
+    #[test]
+    fn decodes_typed_values() {
+        let registers = [0x4020, 0x0000, 0x0000, 0x0000];
+        assert_eq!(
+            RegisterValue::decode(&registers, ValueType::F32, WordOrder::Big),
+            Some(RegisterValue::F32(2.5))
+        );
+        assert_eq!(
+            RegisterValue::decode(&[0xFFFE], ValueType::I16, WordOrder::Big),
+            Some(RegisterValue::I16(-2))
+        );
+        assert_eq!(
+            RegisterValue::decode(&[0xFFFF, 0xFFFE], ValueType::I32, WordOrder::Big),
+            Some(RegisterValue::I32(-2))
+        );
+        assert_eq!(
+            RegisterValue::decode(&[0x3FF0, 0, 0, 0], ValueType::F64, WordOrder::Big),
+            Some(RegisterValue::F64(1.0))
+        );
+        assert_eq!(
+            RegisterValue::decode(&[0x4142, 0x4300], ValueType::String, WordOrder::Big),
+            Some(RegisterValue::Text("ABC".to_string()))
+        );
+        assert_eq!(
+            RegisterValue::decode(&[0x1122], ValueType::U32, WordOrder::Big),
+            None
+        );
+    }
+
+    #[test]
+    fn packs_partial_coil_bytes() {
+        // ten coils fill one byte and two bits of the next, the rest stay zero
+        let bits = [
+            true, false, true, true, false, false, true, false, true, true,
+        ];
+        assert_eq!(pack_bits(&bits), vec![0b0100_1101, 0b0000_0011]);
+        assert_eq!(unpack_bits(&[0b0100_1101, 0b1111_1111], 10), bits.to_vec());
+        assert_eq!(pack_bits(&[true]), vec![0x01]);
+        assert_eq!(pack_bits(&[]), Vec::<u8>::new());
+        for count in [1, 7, 8, 9, 15, 17] {
+            let bits: Vec<bool> = (0..count).map(|i| i % 3 == 0).collect();
+            let packed = pack_bits(&bits);
+            assert_eq!(packed.len(), (count as usize).div_ceil(8));
+            assert_eq!(unpack_bits(&packed, count), bits);
+        }
+    }
+
+    #[test]
+    fn unpacking_stops_when_the_data_runs_out() {
+        assert_eq!(unpack_bits(&[0xFF], 10).len(), 8);
+        assert!(unpack_bits(&[], 3).is_empty());
+    }
+
+    #[test]
+    fn writes_partial_coil_bytes() {
+        let bits = [true; 10];
+        let mut message = ModbusMessage::new_write_multiple_coils(5, 0x0013, &bits);
+        let bytes = message.as_bytes();
+        // slave, function, start, quantity, byte count, two data bytes, crc
+        assert_eq!(bytes[..9], [5, 0x0F, 0x00, 0x13, 0x00, 0x0A, 2, 0xFF, 0x03]);
+        let sent = ModbusMessage::sent_from_data(bytes).unwrap();
+        assert_eq!(sent.num_data_points, 10);
+        assert_eq!(unpack_bits(&sent.data, sent.num_data_points), bits.to_vec());
+    }
+
+    #[test]
+    fn rejects_quantities_past_the_protocol_limits() {
+        let read = |function, quantity| {
+            ModbusMessage::sent_from_data(
+                ModbusMessage::new_read_message(5, function, 0, quantity).as_bytes(),
+            )
+        };
+        assert!(read(3, 125).is_ok());
+        assert_eq!(read(3, 126).err(), Some(ModbusError::InvalidQuantity(126)));
+        assert_eq!(read(4, 0).err(), Some(ModbusError::InvalidQuantity(0)));
+        assert!(read(1, 2000).is_ok());
+        assert_eq!(
+            read(2, 2001).err(),
+            Some(ModbusError::InvalidQuantity(2001))
+        );
+
+        // a quantity whose byte count used to overflow u16
+        let mut frame = vec![5, 0x10, 0, 0, 0xFF, 0xFF, 2, 0, 1];
+        let crc = crc_helper(frame.clone());
+        frame.push((crc & 0x0FF) as u8);
+        frame.push((crc >> 8) as u8);
+        assert_eq!(
+            ModbusMessage::sent_from_data(frame).err(),
+            Some(ModbusError::InvalidQuantity(0xFFFF))
+        );
+
+        let mut registers = ModbusMessage::new_write_multiple_registers(5, 0, &[0; 124]);
+        assert_eq!(
+            ModbusMessage::sent_from_data(registers.as_bytes()).err(),
+            Some(ModbusError::InvalidQuantity(124))
+        );
+        let mut coils = ModbusMessage::new_write_multiple_coils(5, 0, &[true; 1968]);
+        assert!(ModbusMessage::sent_from_data(coils.as_bytes()).is_ok());
+    }
+
+    #[tokio::test]
+    async fn rtu_master_retries_after_silence() {
+        let (line, mut far_end) = tokio::io::duplex(256);
+        tokio::spawn(async move {
+            let mut request = [0u8; 8];
+            // the first request goes unanswered, the retry gets the reply
+            far_end.read_exact(&mut request).await.unwrap();
+            far_end.read_exact(&mut request).await.unwrap();
+            let mut response = vec![5, 3, 2, 0xBE, 0xEF];
+            let crc = crc_helper(response.clone());
+            response.push((crc & 0x0FF) as u8);
+            response.push((crc >> 8) as u8);
+            far_end.write_all(&response).await.unwrap();
+            // stay quiet but keep the line open until the master is done
+            while far_end.read_exact(&mut request).await.is_ok() {}
+        });
+        let mut master = RtuMaster::new(line, 9600, [0; 8]);
+        assert_eq!(master.silence, Duration::from_micros(4010));
+        assert_eq!(master.frame_gap, master.silence * 2);
+        master.timeout = Duration::from_millis(50);
+        let sample = master
+            .poll(ModbusMessage::new_read_message(5, 3, 10, 1))
+            .await
+            .unwrap();
+        assert_eq!(sample.values, vec![0xBEEF]);
+
+        master.retries = 0;
+        let result = master
+            .poll(ModbusMessage::new_read_message(5, 3, 10, 1))
+            .await;
+        assert!(matches!(result, Err(TransportError::Timeout)));
+    }
+
+    #[tokio::test]
+    async fn rtu_master_only_broadcasts_writes() {
+        let (line, mut far_end) = tokio::io::duplex(256);
+        let mut master = RtuMaster::new(line, 9600, [0; 8]);
+        master.turnaround_delay = Duration::from_millis(0);
+        let result = master
+            .poll(ModbusMessage::new_read_message(BROADCAST_ADDRESS, 3, 10, 1))
+            .await;
+        assert!(matches!(
+            result,
+            Err(TransportError::NotBroadcastable(
+                FunctionTypes::ReadHoldingRegisters
+            ))
+        ));
+
+        let sample = master
+            .poll(ModbusMessage::new_write_multiple_registers(
+                BROADCAST_ADDRESS,
+                10,
+                &[7],
+            ))
+            .await
+            .unwrap();
+        assert!(sample.write);
+        assert_eq!(sample.values, vec![7]);
+        // only the write reached the line
+        let mut frame = [0u8; 2];
+        far_end.read_exact(&mut frame).await.unwrap();
+        assert_eq!(frame, [BROADCAST_ADDRESS, 0x10]);
+    }
 }
@@ -0,0 +1,266 @@
+use crate::modbus::*;
+use crate::samples::*;
+use std::net::SocketAddr;
+use std::time::{Duration, SystemTime};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+use tokio::time::Instant;
+
+pub const DEFAULT_TCP_PORT: u16 = 502;
+pub const DEFAULT_TCP_TIMEOUT: Duration = Duration::from_secs(1);
+
+const MBAP_HEADER_LENGTH: usize = 7;
+const MODBUS_PROTOCOL_ID: u16 = 0;
+
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+pub enum TcpFraming {
+    // MBAP header in front of the PDU, no CRC
+    Mbap,
+    // plain RTU frames, CRC included, as sent by most serial to Ethernet gateways
+    RtuOverTcp,
+}
+
+pub struct ModbusTcpClient {
+    stream: TcpStream,
+    pub framing: TcpFraming,
+    pub hardware_id: [u8; 8],
+    pub timeout: Duration,
+    transaction_id: u16,
+}
+
+// Ethernet devices have no XBee address, so samples are keyed by where we reach them
+pub fn tcp_hardware_id(addr: &SocketAddr) -> [u8; 8] {
+    let mut id = [0u8; 8];
+    match addr {
+        SocketAddr::V4(a) => id[2..6].copy_from_slice(&a.ip().octets()),
+        SocketAddr::V6(a) => id[..6].copy_from_slice(&a.ip().octets()[10..]),
+    }
+    id[6..].copy_from_slice(&addr.port().to_be_bytes());
+    id
+}
+
+impl ModbusTcpClient {
+    pub async fn connect(addr: SocketAddr, framing: TcpFraming) -> Result<Self, TransportError> {
+        let stream = tokio::time::timeout(DEFAULT_TCP_TIMEOUT, TcpStream::connect(addr))
+            .await
+            .map_err(|_e| TransportError::Timeout)??;
+        stream.set_nodelay(true)?;
+        Ok(ModbusTcpClient {
+            stream,
+            framing,
+            hardware_id: tcp_hardware_id(&addr),
+            timeout: DEFAULT_TCP_TIMEOUT,
+            transaction_id: 0,
+        })
+    }
+
+    pub async fn transact(
+        &mut self,
+        request: &mut ModbusMessage,
+    ) -> Result<ModbusMessage, TransportError> {
+        let deadline = Instant::now() + self.timeout;
+        match self.framing {
+            TcpFraming::Mbap => self.transact_mbap(request, deadline).await,
+            TcpFraming::RtuOverTcp => self.transact_rtu(request, deadline).await,
+        }
+    }
+
+    pub async fn poll(
+        &mut self,
+        mut request: ModbusMessage,
+    ) -> Result<BridgeSample, TransportError> {
+        let sent = time_as_millis(SystemTime::now());
+        let response = self.transact(&mut request).await?;
+        let mut sample = BridgeSample::from_modbus(self.hardware_id, &request, &response)?;
+        sample.times.sent = Some(sent);
+        Ok(sample)
+    }
+
+    async fn read_exact_by(
+        &mut self,
+        buf: &mut [u8],
+        deadline: Instant,
+    ) -> Result<(), TransportError> {
+        match tokio::time::timeout_at(deadline, self.stream.read_exact(buf)).await {
+            Ok(r) => {
+                r?;
+                Ok(())
+            }
+            Err(_elapsed) => Err(TransportError::Timeout),
+        }
+    }
+
+    async fn transact_mbap(
+        &mut self,
+        request: &ModbusMessage,
+        deadline: Instant,
+    ) -> Result<ModbusMessage, TransportError> {
+        self.transaction_id = self.transaction_id.wrapping_add(1);
+        let pdu = request.pdu_bytes();
+
+        let mut frame = Vec::new();
+        frame.extend_from_slice(&self.transaction_id.to_be_bytes());
+        frame.extend_from_slice(&MODBUS_PROTOCOL_ID.to_be_bytes());
+        frame.extend_from_slice(&((pdu.len() + 1) as u16).to_be_bytes());
+        frame.push(request.address);
+        frame.extend_from_slice(&pdu);
+        self.stream.write_all(&frame).await?;
+
+        loop {
+            let mut header = [0u8; MBAP_HEADER_LENGTH];
+            self.read_exact_by(&mut header, deadline).await?;
+            let transaction_id = u16::from_be_bytes([header[0], header[1]]);
+            let length = u16::from_be_bytes([header[4], header[5]]) as usize;
+            if length < 2 {
+                return Err(TransportError::Modbus(ModbusError::Truncated));
+            }
+
+            let mut pdu = vec![0u8; length - 1];
+            self.read_exact_by(&mut pdu, deadline).await?;
+
+            // a late answer to a request that already timed out, keep waiting for ours
+            if transaction_id != self.transaction_id {
+                eprintln!(
+                    "discarding modbus tcp response for transaction {}",
+                    transaction_id
+                );
+                continue;
+            }
+            return Ok(ModbusMessage::received_from_pdu(header[6], &pdu)?);
+        }
+    }
+
+    async fn transact_rtu(
+        &mut self,
+        request: &mut ModbusMessage,
+        deadline: Instant,
+    ) -> Result<ModbusMessage, TransportError> {
+        self.stream.write_all(&request.as_bytes()).await?;
+
+        let mut frame = vec![0u8; 2];
+        self.read_exact_by(&mut frame, deadline).await?;
+        loop {
+            match rtu_response_length(&frame) {
+                Some(length) if length <= frame.len() => break,
+                Some(length) => {
+                    let mut rest = vec![0u8; length - frame.len()];
+                    self.read_exact_by(&mut rest, deadline).await?;
+                    frame.append(&mut rest);
+                }
+                None => {
+                    let mut next = [0u8; 1];
+                    self.read_exact_by(&mut next, deadline).await?;
+                    frame.push(next[0]);
+                }
+            }
+        }
+        Ok(ModbusMessage::received_from_data(frame)?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::simulator::*;
+    use tokio::net::TcpListener;
+
+    async fn listen() -> (TcpListener, SocketAddr) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        (listener, addr)
+    }
+
+    #[tokio::test]
+    async fn mbap_frames_and_stale_responses() {
+        let (listener, addr) = listen().await;
+        let server = tokio::spawn(async move {
+            let (mut stream, _peer) = listener.accept().await.unwrap();
+            let mut request = [0u8; 12];
+            stream.read_exact(&mut request).await.unwrap();
+            // transaction 1, protocol 0, six bytes of unit id and PDU
+            assert_eq!(request, [0, 1, 0, 0, 0, 6, 5, 3, 0, 10, 0, 1]);
+            // an answer for some older transaction first, then ours
+            stream
+                .write_all(&[0, 9, 0, 0, 0, 5, 5, 3, 2, 0xDE, 0xAD])
+                .await
+                .unwrap();
+            stream
+                .write_all(&[0, 1, 0, 0, 0, 5, 5, 3, 2, 0xBE, 0xEF])
+                .await
+                .unwrap();
+        });
+        let mut client = ModbusTcpClient::connect(addr, TcpFraming::Mbap)
+            .await
+            .unwrap();
+        let sample = client
+            .poll(ModbusMessage::new_read_message(5, 3, 10, 1))
+            .await
+            .unwrap();
+        assert_eq!(sample.values, vec![0xBEEF]);
+        assert_eq!(sample.hardware_id, tcp_hardware_id(&addr));
+        server.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn rejects_short_mbap_lengths() {
+        let (listener, addr) = listen().await;
+        tokio::spawn(async move {
+            let (mut stream, _peer) = listener.accept().await.unwrap();
+            let mut request = [0u8; 12];
+            stream.read_exact(&mut request).await.unwrap();
+            stream.write_all(&[0, 1, 0, 0, 0, 1, 5]).await.unwrap();
+        });
+        let mut client = ModbusTcpClient::connect(addr, TcpFraming::Mbap)
+            .await
+            .unwrap();
+        let result = client
+            .poll(ModbusMessage::new_read_message(5, 3, 10, 1))
+            .await;
+        assert!(matches!(
+            result,
+            Err(TransportError::Modbus(ModbusError::Truncated))
+        ));
+    }
+
+    async fn simulated(framing: TcpFraming) -> ModbusTcpClient {
+        let (listener, addr) = listen().await;
+        let mut server = ModbusTcpServer::new(framing);
+        let mut slave = ModbusSlave::default();
+        slave.holding_registers.insert(10, 0xBEEF);
+        server.add_slave(5, slave);
+        tokio::spawn(server.run(listener));
+        ModbusTcpClient::connect(addr, framing).await.unwrap()
+    }
+
+    #[tokio::test]
+    async fn talks_to_both_framings() {
+        for framing in [TcpFraming::Mbap, TcpFraming::RtuOverTcp] {
+            let mut client = simulated(framing).await;
+            let sample = client
+                .poll(ModbusMessage::new_read_message(5, 3, 10, 2))
+                .await
+                .unwrap();
+            assert_eq!(sample.values, vec![0xBEEF, 0]);
+
+            let sample = client
+                .poll(ModbusMessage::new_write_multiple_registers(5, 20, &[1, 2]))
+                .await
+                .unwrap();
+            assert!(sample.write);
+            assert_eq!(sample.values, vec![1, 2]);
+
+            // too many registers for one read comes back as an exception
+            let sample = client
+                .poll(ModbusMessage::new_read_message(5, 3, 0, 200))
+                .await
+                .unwrap();
+            assert_eq!(sample.exception, Some(ModbusException::IllegalDataValue));
+
+            client.timeout = Duration::from_millis(100);
+            let result = client
+                .poll(ModbusMessage::new_read_message(7, 3, 10, 1))
+                .await;
+            assert!(matches!(result, Err(TransportError::Timeout)));
+        }
+    }
+}
@@ -1,3 +1,6 @@
+use crate::samples::time_as_millis;
+use std::time::SystemTime;
+
 #[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
 pub enum FrameTypes {
     TransmitRequest = 0x10,
@@ -23,6 +26,21 @@ impl FrameTypes {
             _ => FrameTypes::None,
         }
     }
+
+    // Smallest frame length, frame type byte included, that holds every fixed
+    // field from_data reads for the type
+    pub fn min_length(&self) -> u16 {
+        match self {
+            FrameTypes::TransmitRequest => 14,
+            FrameTypes::TransmitStatus => 7,
+            FrameTypes::ReceivePacket => 12,
+            FrameTypes::RemoteAtRequest => 15,
+            FrameTypes::RemoteAtResponse => 15,
+            FrameTypes::LocalAtCommand => 4,
+            FrameTypes::LocalAtResponse => 5,
+            FrameTypes::None => 1,
+        }
+    }
 }
 
 #[derive(Clone, Serialize, Deserialize, Debug)]
@@ -42,6 +60,60 @@ pub struct Packet {
     pub broadcast_radius: u8,
     pub command: [u8; 2],
     pub command_status: u8,
+    // millis since the epoch when the frame was read off the radio, or when it
+    // was handed to the radio for packets we keep as sent messages. 0 if never.
+    #[serde(default)]
+    pub timestamp: u128,
+}
+
+pub const UNKNOWN_NETWORK_ADDRESS: [u8; 2] = [0xFF, 0xFE];
+
+#[derive(Debug, Clone, Copy, PartialEq, Default, Serialize, Deserialize)]
+pub struct TransmitOptions {
+    pub disable_retries: bool,
+    pub aps_encryption: bool,
+    pub extended_timeout: bool,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Default, Serialize, Deserialize)]
+pub enum BroadcastRadius {
+    #[default]
+    Maximum,
+    Hops(u8),
+}
+
+impl TransmitOptions {
+    pub fn as_byte(&self) -> u8 {
+        let mut options = 0x00;
+        if self.disable_retries {
+            options |= 0x01;
+        }
+        if self.aps_encryption {
+            options |= 0x20;
+        }
+        if self.extended_timeout {
+            options |= 0x40;
+        }
+        options
+    }
+
+    pub fn from_byte(options: u8) -> Self {
+        TransmitOptions {
+            disable_retries: options & 0x01 != 0,
+            aps_encryption: options & 0x20 != 0,
+            extended_timeout: options & 0x40 != 0,
+        }
+    }
+}
+
+impl BroadcastRadius {
+    pub fn as_byte(&self) -> u8 {
+        match self {
+            // a radius of 0 lets the radio use its maximum hop count (NH)
+            BroadcastRadius::Maximum => 0x00,
+            BroadcastRadius::Hops(h) => *h,
+        }
+    }
 }
 
 pub fn calculate_checksum(packet: Packet) -> u8 {
@@ -68,6 +140,8 @@ pub fn calculate_checksum(packet: Packet) -> u8 {
                     .command
                     .iter()
                     .for_each(|x| sum = sum.overflowing_add(*x).0);
+            } else {
+                sum = sum.overflowing_add(packet.broadcast_radius).0;
             }
         }
         FrameTypes::TransmitStatus => {
@@ -119,10 +193,17 @@ pub fn calculate_checksum(packet: Packet) -> u8 {
         }
         FrameTypes::LocalAtCommand | FrameTypes::LocalAtResponse => {
             sum = sum.overflowing_add(packet.frame_id).0;
+            packet
+                .command
+                .iter()
+                .for_each(|x| sum = sum.overflowing_add(*x).0);
             packet
                 .data
                 .iter()
                 .for_each(|x| sum = sum.overflowing_add(*x).0);
+            if packet.frame_type == FrameTypes::LocalAtResponse {
+                sum = sum.overflowing_add(packet.command_status).0;
+            }
         }
         _ => sum = 0, //other frame types currently unsupported
     };
@@ -147,10 +228,19 @@ impl Packet {
             broadcast_radius: 0x00,
             command: [0x00, 0x00],
             command_status: 0x00,
+            timestamp: 0,
         }
     }
 
     pub fn new_broadcast(data: &[u8]) -> Self {
+        Packet::new_broadcast_with(data, TransmitOptions::default(), BroadcastRadius::default())
+    }
+
+    pub fn new_broadcast_with(
+        data: &[u8],
+        options: TransmitOptions,
+        radius: BroadcastRadius,
+    ) -> Self {
         let mut temp_length: u16 = 14;
         temp_length += data.len() as u16;
         let mut data_vec: Vec<u8> = Vec::new();
@@ -162,22 +252,39 @@ impl Packet {
             frame_type: FrameTypes::TransmitRequest,
             frame_id: 0x00,
             address: [0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0xFF, 0xFF],
-            network_address: [0xFF, 0xFE],
-            options: 0x00,
+            network_address: UNKNOWN_NETWORK_ADDRESS,
+            options: options.as_byte(),
             delivery_status: 0x00,
             data: data_vec,
             checksum: 0x00,
             discovery_status: 0x00,
             retry_count: 0x00,
-            broadcast_radius: 0x00,
+            broadcast_radius: radius.as_byte(),
             command: [0x00, 0x00],
             command_status: 0x00,
+            timestamp: 0,
         };
         packet.checksum = calculate_checksum(packet.clone());
         packet
     }
 
     pub fn new_transmit(dest: &[u8; 8], data: &[u8]) -> Self {
+        Packet::new_transmit_with(
+            dest,
+            UNKNOWN_NETWORK_ADDRESS,
+            data,
+            TransmitOptions::default(),
+            BroadcastRadius::default(),
+        )
+    }
+
+    pub fn new_transmit_with(
+        dest: &[u8; 8],
+        network: [u8; 2],
+        data: &[u8],
+        options: TransmitOptions,
+        radius: BroadcastRadius,
+    ) -> Self {
         let mut temp_length: u16 = 14;
         temp_length += data.len() as u16;
         let mut data_vec: Vec<u8> = Vec::new();
@@ -188,17 +295,18 @@ impl Packet {
             length: temp_length,
             frame_type: FrameTypes::TransmitRequest,
             frame_id: 0x00,
-            address: dest.clone(),
-            network_address: [0xFF, 0xFE],
-            options: 0x00,
+            address: *dest,
+            network_address: network,
+            options: options.as_byte(),
             delivery_status: 0x00,
             data: data_vec,
             checksum: 0x00,
             discovery_status: 0x00,
             retry_count: 0x00,
-            broadcast_radius: 0x00,
+            broadcast_radius: radius.as_byte(),
             command: [0x00, 0x00],
             command_status: 0x00,
+            timestamp: 0,
         };
         packet.checksum = calculate_checksum(packet.clone());
 
@@ -230,6 +338,7 @@ impl Packet {
             broadcast_radius: 0x00,
             command: command_temp.clone(),
             command_status: 0x00,
+            timestamp: 0,
         };
 
         packet.checksum = calculate_checksum(packet.clone());
@@ -238,7 +347,7 @@ impl Packet {
     }
 
     pub fn new_local_at(id: u8, command: u16, param: &[u8]) -> Self {
-        let mut temp_length: u16 = 8;
+        let mut temp_length: u16 = 4;
         temp_length += param.len() as u16;
 
         let mut data_vec: Vec<u8> = Vec::new();
@@ -262,6 +371,143 @@ impl Packet {
             broadcast_radius: 0x00,
             command: command_temp.clone(),
             command_status: 0x00,
+            timestamp: 0,
+        };
+
+        packet.checksum = calculate_checksum(packet.clone());
+
+        packet
+    }
+
+    pub fn new_local_at_response(id: u8, command: u16, status: u8, data: &[u8]) -> Self {
+        let mut temp_length: u16 = 5;
+        temp_length += data.len() as u16;
+
+        let mut data_vec: Vec<u8> = Vec::new();
+        data_vec.extend_from_slice(data);
+
+        let command_temp: [u8; 2] = [(command >> 8) as u8, (command & 0x0FF) as u8];
+
+        let mut packet = Packet {
+            is_broadcast: false,
+            length: temp_length,
+            frame_type: FrameTypes::LocalAtResponse,
+            frame_id: id,
+            address: [0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00],
+            network_address: [0x00, 0x00],
+            options: 0x00,
+            delivery_status: 0x00,
+            data: data_vec,
+            checksum: 0x00,
+            discovery_status: 0x00,
+            retry_count: 0x00,
+            broadcast_radius: 0x00,
+            command: command_temp,
+            command_status: status,
+            timestamp: 0,
+        };
+
+        packet.checksum = calculate_checksum(packet.clone());
+
+        packet
+    }
+
+    pub fn new_transmit_status(
+        id: u8,
+        network: [u8; 2],
+        retry_count: u8,
+        delivery_status: u8,
+        discovery_status: u8,
+    ) -> Self {
+        let mut packet = Packet {
+            is_broadcast: false,
+            length: 7,
+            frame_type: FrameTypes::TransmitStatus,
+            frame_id: id,
+            address: [0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00],
+            network_address: network,
+            options: 0x00,
+            delivery_status,
+            data: Vec::new(),
+            checksum: 0x00,
+            discovery_status,
+            retry_count,
+            broadcast_radius: 0x00,
+            command: [0x00, 0x00],
+            command_status: 0x00,
+            timestamp: 0,
+        };
+
+        packet.checksum = calculate_checksum(packet.clone());
+
+        packet
+    }
+
+    pub fn new_receive(source: &[u8; 8], network: [u8; 2], options: u8, data: &[u8]) -> Self {
+        let mut temp_length: u16 = 12;
+        temp_length += data.len() as u16;
+
+        let mut data_vec: Vec<u8> = Vec::new();
+        data_vec.extend_from_slice(data);
+
+        let mut packet = Packet {
+            is_broadcast: false,
+            length: temp_length,
+            frame_type: FrameTypes::ReceivePacket,
+            frame_id: 0x00,
+            address: *source,
+            network_address: network,
+            options,
+            delivery_status: 0x00,
+            data: data_vec,
+            checksum: 0x00,
+            discovery_status: 0x00,
+            retry_count: 0x00,
+            broadcast_radius: 0x00,
+            command: [0x00, 0x00],
+            command_status: 0x00,
+            timestamp: 0,
+        };
+
+        packet.checksum = calculate_checksum(packet.clone());
+
+        packet
+    }
+
+    pub fn new_remote_at_response(
+        id: u8,
+        source: &[u8; 8],
+        network: [u8; 2],
+        command: u16,
+        status: u8,
+        data: &[u8],
+    ) -> Self {
+        let mut temp_length: u16 = 15;
+        temp_length += data.len() as u16;
+
+        let mut data_vec: Vec<u8> = Vec::new();
+        data_vec.extend_from_slice(data);
+
+        let command_temp: [u8; 2] = [(command >> 8) as u8, (command & 0x0FF) as u8];
+
+        // the status byte of a remote AT response is parsed into options by from_data
+        let mut packet = Packet {
+            is_broadcast: false,
+            length: temp_length,
+            frame_type: FrameTypes::RemoteAtResponse,
+            frame_id: id,
+            address: *source,
+            network_address: network,
+            options: status,
+            delivery_status: 0x00,
+            data: data_vec,
+            checksum: 0x00,
+            discovery_status: 0x00,
+            retry_count: 0x00,
+            broadcast_radius: 0x00,
+            command: command_temp,
+            command_status: 0x00,
+            timestamp: 0,
         };
 
         packet.checksum = calculate_checksum(packet.clone());
@@ -285,10 +531,10 @@ impl Packet {
             FrameTypes::TransmitStatus => self.frame_id,
             FrameTypes::TransmitRequest => self.data[1],
             FrameTypes::ReceivePacket => self.data[0],
-            FrameTypes::RemoteAtRequest => 255,
-            FrameTypes::RemoteAtResponse => 255,
-            FrameTypes::LocalAtCommand => 255,
-            FrameTypes::LocalAtResponse => 255,
+            FrameTypes::RemoteAtRequest
+            | FrameTypes::RemoteAtResponse
+            | FrameTypes::LocalAtCommand
+            | FrameTypes::LocalAtResponse => self.frame_id,
             _ => 0,
         }
     }
@@ -303,13 +549,24 @@ impl Packet {
             _ => match raw.remove(0) {
                 0x7E => {
                     let mut packet = Packet::new_empty();
+                    packet.timestamp = time_as_millis(SystemTime::now());
 
                     let len_high = raw.remove(0);
                     let len_low = raw.remove(0);
                     packet.length = ((len_high as u16) << 8) | (len_low as u16);
                     let mut length_remaining = packet.length;
 
+                    // the length and the checksum have to be there before any field is read
+                    if raw.len() < packet.length as usize + 1 {
+                        return Err(format!("frame shorter than its length {}", packet.length));
+                    }
                     packet.frame_type = FrameTypes::new_frame_type(raw.remove(0));
+                    if packet.length < packet.frame_type.min_length() {
+                        return Err(format!(
+                            "length {} too short for {:?}",
+                            packet.length, packet.frame_type
+                        ));
+                    }
                     length_remaining -= 1;
 
                     match packet.frame_type {
@@ -378,6 +635,44 @@ impl Packet {
                             }
                             packet.checksum = raw.remove(0);
                         }
+                        FrameTypes::RemoteAtRequest => {
+                            packet.frame_id = raw.remove(0);
+                            length_remaining -= 1;
+
+                            let mut address: Vec<u8> = Vec::new();
+                            packet
+                                .address
+                                .iter()
+                                .for_each(|_x| address.push(raw.remove(0)));
+                            packet.address.copy_from_slice(address.as_slice());
+                            length_remaining -= packet.address.len() as u16;
+
+                            let mut network_address: Vec<u8> = Vec::new();
+                            packet
+                                .network_address
+                                .iter()
+                                .for_each(|_x| network_address.push(raw.remove(0)));
+                            packet
+                                .network_address
+                                .copy_from_slice(network_address.as_slice());
+                            length_remaining -= packet.network_address.len() as u16;
+
+                            packet.options = raw.remove(0);
+                            length_remaining -= 1;
+
+                            let mut command: Vec<u8> = Vec::new();
+                            packet
+                                .command
+                                .iter()
+                                .for_each(|_x| command.push(raw.remove(0)));
+                            packet.command.copy_from_slice(command.as_slice());
+                            length_remaining -= packet.command.len() as u16;
+
+                            for _i in 0..length_remaining {
+                                packet.data.push(raw.remove(0));
+                            }
+                            packet.checksum = raw.remove(0);
+                        }
                         FrameTypes::TransmitStatus => {
                             packet.frame_id = raw.remove(0);
                             let mut network_address: Vec<u8> = Vec::new();
@@ -406,6 +701,25 @@ impl Packet {
                             length_remaining -= packet.command.len() as u16;
 
                             packet.command_status = raw.remove(0);
+                            length_remaining -= 1;
+
+                            for _i in 0..length_remaining {
+                                packet.data.push(raw.remove(0));
+                            }
+
+                            packet.checksum = raw.remove(0);
+                        }
+                        FrameTypes::LocalAtCommand => {
+                            packet.frame_id = raw.remove(0);
+                            length_remaining -= 1;
+
+                            let mut command: Vec<u8> = Vec::new();
+                            packet
+                                .command
+                                .iter()
+                                .for_each(|_x| command.push(raw.remove(0)));
+                            packet.command.copy_from_slice(command.as_slice());
+                            length_remaining -= packet.command.len() as u16;
 
                             for _i in 0..length_remaining {
                                 packet.data.push(raw.remove(0));
@@ -508,8 +822,62 @@ impl Packet {
                 bytes.append(&mut self.data.clone());
                 bytes.push(self.checksum);
             }
+            FrameTypes::LocalAtResponse => {
+                bytes.push(0x7E);
+                let len_high: u8 = (self.length >> 8) as u8;
+                let len_low: u8 = (self.length & 0x00FF) as u8;
+                bytes.push(len_high);
+                bytes.push(len_low);
+                bytes.push(self.frame_type as u8);
+                bytes.push(self.frame_id);
+                self.command.iter().for_each(|x| bytes.push(*x));
+                bytes.push(self.command_status);
+                bytes.append(&mut self.data.clone());
+                bytes.push(self.checksum);
+            }
+            FrameTypes::TransmitStatus => {
+                bytes.push(0x7E);
+                let len_high: u8 = (self.length >> 8) as u8;
+                let len_low: u8 = (self.length & 0x00FF) as u8;
+                bytes.push(len_high);
+                bytes.push(len_low);
+                bytes.push(self.frame_type as u8);
+                bytes.push(self.frame_id);
+                self.network_address.iter().for_each(|x| bytes.push(*x));
+                bytes.push(self.retry_count);
+                bytes.push(self.delivery_status);
+                bytes.push(self.discovery_status);
+                bytes.push(self.checksum);
+            }
+            FrameTypes::ReceivePacket => {
+                bytes.push(0x7E);
+                let len_high: u8 = (self.length >> 8) as u8;
+                let len_low: u8 = (self.length & 0x00FF) as u8;
+                bytes.push(len_high);
+                bytes.push(len_low);
+                bytes.push(self.frame_type as u8);
+                self.address.iter().for_each(|x| bytes.push(*x));
+                self.network_address.iter().for_each(|x| bytes.push(*x));
+                bytes.push(self.options);
+                bytes.append(&mut self.data.clone());
+                bytes.push(self.checksum);
+            }
+            FrameTypes::RemoteAtResponse => {
+                bytes.push(0x7E);
+                let len_high: u8 = (self.length >> 8) as u8;
+                let len_low: u8 = (self.length & 0x00FF) as u8;
+                bytes.push(len_high);
+                bytes.push(len_low);
+                bytes.push(self.frame_type as u8);
+                bytes.push(self.frame_id);
+                self.address.iter().for_each(|x| bytes.push(*x));
+                self.network_address.iter().for_each(|x| bytes.push(*x));
+                self.command.iter().for_each(|x| bytes.push(*x));
+                bytes.push(self.options);
+                bytes.append(&mut self.data.clone());
+                bytes.push(self.checksum);
+            }
             _ => bytes.push(0x00), //need to support more frame types
-                                   //FrameTypes::RemoteAtRequest =>
         }
         bytes
     }
@@ -522,3 +890,76 @@ impl Packet {
         self.checksum == calculate_checksum(self.clone())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // every byte after the length, checksum included, sums to 0xFF
+    fn frame_sum(packet: &Packet) -> u8 {
+        packet.as_bytes()[3..]
+            .iter()
+            .fold(0u8, |a, x| a.wrapping_add(*x))
+    }
+
+    #[test]
+    fn transmit_options_bits() {
+        assert_eq!(TransmitOptions::default().as_byte(), 0x00);
+        let options = TransmitOptions {
+            disable_retries: true,
+            aps_encryption: true,
+            extended_timeout: true,
+        };
+        assert_eq!(options.as_byte(), 0x61);
+        let options = TransmitOptions {
+            extended_timeout: true,
+            ..TransmitOptions::default()
+        };
+        assert_eq!(options.as_byte(), 0x40);
+        for byte in [0x00, 0x01, 0x20, 0x40, 0x61] {
+            assert_eq!(TransmitOptions::from_byte(byte).as_byte(), byte);
+        }
+    }
+
+    #[test]
+    fn builds_broadcasts_with_options_and_radius() {
+        let options = TransmitOptions {
+            disable_retries: true,
+            ..TransmitOptions::default()
+        };
+        let packet = Packet::new_broadcast_with(&[1, 2, 3], options, BroadcastRadius::Hops(2));
+        assert!(packet.is_broadcast);
+        assert_eq!(packet.address, [0, 0, 0, 0, 0, 0, 0xFF, 0xFF]);
+        assert_eq!(packet.network_address, UNKNOWN_NETWORK_ADDRESS);
+        assert_eq!(packet.length, 17);
+
+        let bytes = packet.as_bytes();
+        assert_eq!(bytes[15], 2); // radius
+        assert_eq!(bytes[16], 0x01); // options
+        assert_eq!(&bytes[17..20], &[1, 2, 3]);
+        assert_eq!(frame_sum(&packet), 0xFF);
+    }
+
+    #[test]
+    fn checksum_covers_the_broadcast_radius() {
+        let maximum = Packet::new_broadcast(&[1, 2, 3]);
+        let hops = Packet::new_broadcast_with(
+            &[1, 2, 3],
+            TransmitOptions::default(),
+            BroadcastRadius::Hops(4),
+        );
+        assert_eq!(maximum.checksum.wrapping_sub(hops.checksum), 4);
+        assert_eq!(frame_sum(&maximum), 0xFF);
+        assert_eq!(frame_sum(&hops), 0xFF);
+
+        let unicast = Packet::new_transmit_with(
+            &[1; 8],
+            [0x12, 0x34],
+            &[5],
+            TransmitOptions::default(),
+            BroadcastRadius::Hops(1),
+        );
+        assert!(unicast.is_valid());
+        assert_eq!(frame_sum(&unicast), 0xFF);
+    }
+}
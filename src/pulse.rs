@@ -0,0 +1,334 @@
+use crate::samples::*;
+use std::collections::HashMap;
+use std::convert::TryInto;
+use std::path::Path;
+
+pub const PULSE_CHANNELS: usize = 6;
+// Counters are u16 on the bridge. A drop smaller than half the range is taken
+// as a wrap, anything bigger means the counter started over from zero.
+const COUNTER_RANGE: u32 = 1 << 16;
+
+#[derive(Clone, Copy, Hash, Serialize, Deserialize, PartialEq, Debug)]
+pub enum PulseEvent {
+    // first sample seen for the channel, nothing to compare against
+    Initial,
+    Normal,
+    Rollover,
+    Reset,
+    // older than a sample already tracked, left out of the deltas
+    Stale,
+}
+
+#[derive(Clone, Serialize, Deserialize, PartialEq, Debug)]
+pub struct PulseCount {
+    pub event: PulseEvent,
+    pub delta: Option<u32>,
+    // pulses per second since the previous sample
+    pub rate: Option<f64>,
+    // delta * factor when the channel has one
+    pub consumption: Option<f64>,
+    pub unit: String,
+}
+
+#[derive(Clone, Serialize, Deserialize, PartialEq, Debug)]
+pub struct PulseFactor {
+    // units per pulse, e.g. Wh or litres
+    pub factor: f64,
+    pub unit: String,
+}
+
+#[derive(Clone, Serialize, Deserialize, PartialEq, Debug)]
+pub struct PulseFactors {
+    pub address: [u8; 8],
+    // indexed by channel, missing channels are reported as raw pulses
+    pub channels: Vec<PulseFactor>,
+}
+
+#[derive(Clone, PartialEq, Debug)]
+pub struct CounterState {
+    pub timestamp: u128,
+    pub pulses: [u16; PULSE_CHANNELS],
+}
+
+// Samples are tracked in timestamp order, SampleStore hands out the oldest
+// first. One that still shows up older than the last tracked sample is Stale:
+// it gets no delta, and since counters only go up its pulses are counted by
+// the next sample's delta instead.
+// With a tree the last state per device survives restarts, otherwise the first
+// sample after a restart is Initial again and its pulses are never counted.
+#[derive(Clone, Debug, Default)]
+pub struct PulseTracker {
+    pub last: HashMap<[u8; 8], CounterState>,
+    pub factors: Vec<PulseFactors>,
+    tree: Option<sled::Tree>,
+}
+
+// PulseSample derives Hash, so the floats are hashed by their bits
+impl std::hash::Hash for PulseCount {
+    fn hash<H: std::hash::Hasher>(&self, state: &mut H) {
+        self.event.hash(state);
+        self.delta.hash(state);
+        self.rate.map(|x| x.to_bits()).hash(state);
+        self.consumption.map(|x| x.to_bits()).hash(state);
+        self.unit.hash(state);
+    }
+}
+
+impl CounterState {
+    fn to_ivec(&self) -> sled::IVec {
+        let mut vec = self.timestamp.to_be_bytes().to_vec();
+        for p in self.pulses.iter() {
+            vec.extend_from_slice(&p.to_be_bytes());
+        }
+        sled::IVec::from(vec)
+    }
+
+    fn from_ivec(value: &[u8]) -> Option<Self> {
+        if value.len() != 16 + PULSE_CHANNELS * 2 {
+            return None;
+        }
+        let mut pulses = [0u16; PULSE_CHANNELS];
+        for (i, p) in value[16..].chunks(2).enumerate() {
+            pulses[i] = u16::from_be_bytes([p[0], p[1]]);
+        }
+        Some(CounterState {
+            timestamp: u128::from_be_bytes(value[..16].try_into().ok()?),
+            pulses,
+        })
+    }
+}
+
+impl PulseFactors {
+    pub fn load<P: AsRef<Path>>(path: P) -> std::io::Result<Vec<Self>> {
+        let json = std::fs::read_to_string(path)?;
+        serde_json::from_str(&json)
+            .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidData, e))
+    }
+}
+
+// Pulses counted between two readings of the same counter
+pub fn counter_delta(previous: u16, current: u16) -> (u32, PulseEvent) {
+    if current >= previous {
+        return ((current - previous) as u32, PulseEvent::Normal);
+    }
+    let wrapped = COUNTER_RANGE - previous as u32 + current as u32;
+    match wrapped <= COUNTER_RANGE / 2 {
+        true => (wrapped, PulseEvent::Rollover),
+        false => (current as u32, PulseEvent::Reset),
+    }
+}
+
+impl PulseTracker {
+    pub fn new(factors: Vec<PulseFactors>) -> Self {
+        PulseTracker {
+            last: HashMap::new(),
+            factors,
+            tree: None,
+        }
+    }
+
+    // Loads the last state of every device from the tree and keeps it up to date
+    pub fn from_tree(tree: sled::Tree, factors: Vec<PulseFactors>) -> sled::Result<Self> {
+        let mut last = HashMap::new();
+        for entry in tree.iter() {
+            let (key, value) = entry?;
+            let address: [u8; 8] = match key.as_ref().try_into() {
+                Ok(a) => a,
+                Err(_e) => continue,
+            };
+            match CounterState::from_ivec(&value) {
+                Some(state) => last.insert(address, state),
+                None => continue,
+            };
+        }
+        Ok(PulseTracker {
+            last,
+            factors,
+            tree: Some(tree),
+        })
+    }
+
+    fn set_last(&mut self, address: &[u8; 8], state: Option<CounterState>) {
+        let result = match (&self.tree, &state) {
+            (Some(t), Some(s)) => t.insert(address, s.to_ivec()).map(|_x| ()),
+            (Some(t), None) => t.remove(address).map(|_x| ()),
+            (None, _) => Ok(()),
+        };
+        if let Err(e) = result {
+            eprintln!("failed to save pulse counters for {:X?}: {}", address, e);
+        }
+        match state {
+            Some(s) => self.last.insert(*address, s),
+            None => self.last.remove(address),
+        };
+    }
+
+    fn factor(&self, address: &[u8; 8], channel: usize) -> Option<&PulseFactor> {
+        self.factors
+            .iter()
+            .find(|x| x.address == *address)
+            .and_then(|x| x.channels.get(channel))
+    }
+
+    // Fills in counts for each sample in timestamp order and returns what the
+    // tracker knew before, so a failed upload can put it back with restore.
+    // Samples are expected to come from a single device.
+    pub fn track(&mut self, samples: &mut [PulseSample]) -> Option<CounterState> {
+        samples.sort_by_key(|x| x.timestamp);
+        let address = match samples.first() {
+            Some(s) => s.hardware_id,
+            None => return None,
+        };
+        let previous = self.last.get(&address).cloned();
+        let mut last = previous.clone();
+        for sample in samples.iter_mut() {
+            let mut counts = Vec::new();
+            for (i, pulses) in sample.pulses.iter().enumerate() {
+                let (delta, event) = match &last {
+                    None => (None, PulseEvent::Initial),
+                    Some(l) if l.timestamp >= sample.timestamp => (None, PulseEvent::Stale),
+                    Some(l) => {
+                        let (delta, event) = counter_delta(l.pulses[i], *pulses);
+                        (Some(delta), event)
+                    }
+                };
+                let rate = match (&last, delta) {
+                    (Some(l), Some(d)) => {
+                        Some(d as f64 * 1000.0 / (sample.timestamp - l.timestamp) as f64)
+                    }
+                    _ => None,
+                };
+                let factor = self.factor(&sample.hardware_id, i);
+                counts.push(PulseCount {
+                    event,
+                    delta,
+                    rate,
+                    consumption: match (delta, factor) {
+                        (Some(d), Some(f)) => Some(d as f64 * f.factor),
+                        _ => None,
+                    },
+                    unit: match factor {
+                        Some(f) => f.unit.clone(),
+                        None => "pulses".to_string(),
+                    },
+                });
+            }
+            if counts.iter().any(|x| x.event == PulseEvent::Reset) {
+                eprintln!("pulse counter reset on {:X?}", sample.hardware_id);
+            }
+            if !counts.iter().any(|x| x.event == PulseEvent::Stale) {
+                last = Some(CounterState {
+                    timestamp: sample.timestamp,
+                    pulses: sample.pulses,
+                });
+            }
+            sample.counts = counts;
+        }
+        if last.is_some() {
+            self.set_last(&address, last);
+        }
+        previous
+    }
+
+    pub fn restore(&mut self, address: &[u8; 8], previous: Option<CounterState>) {
+        self.set_last(address, previous);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const BRIDGE: [u8; 8] = [1, 2, 3, 4, 5, 6, 7, 8];
+
+    fn sample(timestamp: u128, first: u16) -> PulseSample {
+        let mut sample = PulseSample::new_empty();
+        sample.hardware_id = BRIDGE;
+        sample.timestamp = timestamp;
+        sample.pulses[0] = first;
+        sample
+    }
+
+    fn temporary_tree() -> sled::Tree {
+        let db = sled::Config::new().temporary(true).open().unwrap();
+        db.open_tree("counters").unwrap()
+    }
+
+    #[test]
+    fn counter_deltas() {
+        assert_eq!(counter_delta(10, 25), (15, PulseEvent::Normal));
+        assert_eq!(counter_delta(7, 7), (0, PulseEvent::Normal));
+        assert_eq!(counter_delta(65530, 4), (10, PulseEvent::Rollover));
+        assert_eq!(counter_delta(65535, 0), (1, PulseEvent::Rollover));
+        assert_eq!(counter_delta(30000, 12), (12, PulseEvent::Reset));
+    }
+
+    #[test]
+    fn tracks_rates_and_consumption() {
+        let mut tracker = PulseTracker::new(vec![PulseFactors {
+            address: BRIDGE,
+            channels: vec![PulseFactor {
+                factor: 0.5,
+                unit: "Wh".to_string(),
+            }],
+        }]);
+        let mut samples = vec![sample(12_000, 120), sample(10_000, 100)];
+        tracker.track(&mut samples);
+        assert_eq!(samples[0].counts[0].event, PulseEvent::Initial);
+        let count = &samples[1].counts[0];
+        assert_eq!(count.delta, Some(20));
+        assert_eq!(count.rate, Some(10.0));
+        assert_eq!(count.consumption, Some(10.0));
+        assert_eq!(count.unit, "Wh");
+        assert_eq!(samples[1].counts[1].unit, "pulses");
+    }
+
+    #[test]
+    fn late_samples_do_not_lose_pulses() {
+        let mut tracker = PulseTracker::new(Vec::new());
+        tracker.track(&mut [sample(1_000, 100)]);
+        tracker.track(&mut [sample(3_000, 130)]);
+
+        // arrives after the one at 3s, then the next one still counts from 130
+        let mut late = [sample(2_000, 110)];
+        tracker.track(&mut late);
+        assert_eq!(late[0].counts[0].event, PulseEvent::Stale);
+        assert_eq!(late[0].counts[0].delta, None);
+        let mut next = [sample(4_000, 135)];
+        tracker.track(&mut next);
+        assert_eq!(next[0].counts[0].delta, Some(5));
+    }
+
+    #[test]
+    fn restores_after_a_failed_upload() {
+        let mut tracker = PulseTracker::new(Vec::new());
+        tracker.track(&mut [sample(1_000, 100)]);
+        let previous = tracker.track(&mut [sample(2_000, 150)]);
+        tracker.restore(&BRIDGE, previous);
+
+        let mut retried = [sample(2_000, 150)];
+        tracker.track(&mut retried);
+        assert_eq!(retried[0].counts[0].delta, Some(50));
+    }
+
+    #[test]
+    fn keeps_counters_across_restarts() {
+        let tree = temporary_tree();
+        let mut tracker = PulseTracker::from_tree(tree.clone(), Vec::new()).unwrap();
+        tracker.track(&mut [sample(1_000, 65530)]);
+
+        let mut restarted = PulseTracker::from_tree(tree.clone(), Vec::new()).unwrap();
+        let mut next = [sample(2_000, 4)];
+        restarted.track(&mut next);
+        assert_eq!(next[0].counts[0].event, PulseEvent::Rollover);
+        assert_eq!(next[0].counts[0].delta, Some(10));
+
+        // a device that never uploaded goes back to having no state
+        let mut tracker = PulseTracker::from_tree(tree.clone(), Vec::new()).unwrap();
+        tracker.restore(&BRIDGE, None);
+        assert!(PulseTracker::from_tree(tree, Vec::new())
+            .unwrap()
+            .last
+            .is_empty());
+    }
+}
@@ -0,0 +1,223 @@
+use crate::samples::*;
+
+pub const DEFAULT_QUERY_LIMIT: usize = 100;
+pub const MAX_QUERY_LIMIT: usize = 1000;
+
+#[derive(Clone, Copy, Serialize, Deserialize, PartialEq, Debug, Default)]
+pub enum SortOrder {
+    #[default]
+    OldestFirst,
+    NewestFirst,
+}
+
+// Everything but sample_type is optional. from is inclusive and to exclusive,
+// both in millis since the epoch like sample timestamps.
+#[derive(Clone, Serialize, Deserialize, PartialEq, Debug)]
+pub struct SampleQuery {
+    pub sample_type: SampleTypes,
+    #[serde(default)]
+    pub hardware_id: Option<[u8; 8]>,
+    #[serde(default)]
+    pub from: Option<u128>,
+    #[serde(default)]
+    pub to: Option<u128>,
+    // only looked at for meter samples
+    #[serde(default)]
+    pub meter_data_type: Option<MeterDataTypes>,
+    #[serde(default)]
+    pub order: SortOrder,
+    #[serde(default)]
+    pub offset: usize,
+    #[serde(default = "default_limit")]
+    pub limit: usize,
+}
+
+#[derive(Clone, Serialize, Deserialize, PartialEq, Debug)]
+pub struct SamplePage {
+    pub samples: Vec<(Vec<u8>, Sample)>,
+    // matches before paging
+    pub total: usize,
+    // offset for the next page, None on the last one
+    pub next_offset: Option<usize>,
+}
+
+fn default_limit() -> usize {
+    DEFAULT_QUERY_LIMIT
+}
+
+impl SampleQuery {
+    pub fn new(sample_type: SampleTypes) -> Self {
+        SampleQuery {
+            sample_type,
+            hardware_id: None,
+            from: None,
+            to: None,
+            meter_data_type: None,
+            order: SortOrder::OldestFirst,
+            offset: 0,
+            limit: DEFAULT_QUERY_LIMIT,
+        }
+    }
+
+    // The last hour of one device, newest first
+    pub fn recent(sample_type: SampleTypes, hardware_id: [u8; 8], now: u128) -> Self {
+        let mut query = SampleQuery::new(sample_type);
+        query.hardware_id = Some(hardware_id);
+        query.from = Some(now.saturating_sub(60 * 60 * 1000));
+        query.order = SortOrder::NewestFirst;
+        query
+    }
+
+    pub fn matches(&self, sample: &Sample) -> bool {
+        if sample.sample_type() != self.sample_type {
+            return false;
+        }
+        if self.hardware_id.is_some() && sample.hardware_id() != self.hardware_id {
+            return false;
+        }
+        let timestamp = sample.timestamp();
+        if self.from.is_some_and(|x| timestamp < x) || self.to.is_some_and(|x| timestamp >= x) {
+            return false;
+        }
+        match (sample, &self.meter_data_type) {
+            (Sample::Meter(m), Some(t)) => m.data_type == *t,
+            _ => true,
+        }
+    }
+
+    // Sorts the matches by timestamp, ties broken by key so pages are stable,
+    // and cuts out the requested page
+    pub fn page(&self, mut matches: Vec<(Vec<u8>, Sample)>) -> SamplePage {
+        matches.sort_by(|a, b| (a.1.timestamp(), &a.0).cmp(&(b.1.timestamp(), &b.0)));
+        if self.order == SortOrder::NewestFirst {
+            matches.reverse();
+        }
+        let total = matches.len();
+        let limit = self.limit.min(MAX_QUERY_LIMIT);
+        let samples: Vec<(Vec<u8>, Sample)> =
+            matches.into_iter().skip(self.offset).take(limit).collect();
+        let end = self.offset + samples.len();
+        SamplePage {
+            samples,
+            total,
+            next_offset: match end < total {
+                true => Some(end),
+                false => None,
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const METER: [u8; 8] = [1; 8];
+    const OTHER: [u8; 8] = [2; 8];
+
+    fn meter(hardware_id: [u8; 8], data_type: MeterDataTypes, timestamp: u128) -> Sample {
+        let mut sample = MeterSample::new_empty();
+        sample.hardware_id = hardware_id;
+        sample.data_type = data_type;
+        sample.timestamp = timestamp;
+        Sample::Meter(sample)
+    }
+
+    fn timestamps(page: &SamplePage) -> Vec<u128> {
+        page.samples.iter().map(|x| x.1.timestamp()).collect()
+    }
+
+    #[test]
+    fn filters_by_device_type_and_time() {
+        let mut query = SampleQuery::new(SampleTypes::Meter);
+        let power = meter(METER, MeterDataTypes::Power, 1_000);
+        assert!(query.matches(&power));
+        assert!(!query.matches(&Sample::Pulse(PulseSample::new_empty())));
+
+        query.hardware_id = Some(METER);
+        assert!(!query.matches(&meter(OTHER, MeterDataTypes::Power, 1_000)));
+
+        query.meter_data_type = Some(MeterDataTypes::Power);
+        assert!(query.matches(&power));
+        assert!(!query.matches(&meter(METER, MeterDataTypes::Current, 1_000)));
+
+        // from is inclusive, to exclusive
+        query.from = Some(1_000);
+        query.to = Some(2_000);
+        assert!(query.matches(&power));
+        assert!(query.matches(&meter(METER, MeterDataTypes::Power, 1_999)));
+        assert!(!query.matches(&meter(METER, MeterDataTypes::Power, 999)));
+        assert!(!query.matches(&meter(METER, MeterDataTypes::Power, 2_000)));
+    }
+
+    #[test]
+    fn pages_in_either_order() {
+        let matches: Vec<(Vec<u8>, Sample)> = [3_000u128, 1_000, 5_000, 2_000, 4_000]
+            .iter()
+            .map(|t| {
+                (
+                    t.to_be_bytes().to_vec(),
+                    meter(METER, MeterDataTypes::Power, *t),
+                )
+            })
+            .collect();
+        let mut query = SampleQuery::new(SampleTypes::Meter);
+        query.limit = 2;
+
+        let page = query.page(matches.clone());
+        assert_eq!(timestamps(&page), vec![1_000, 2_000]);
+        assert_eq!(page.total, 5);
+        assert_eq!(page.next_offset, Some(2));
+
+        query.offset = 4;
+        let page = query.page(matches.clone());
+        assert_eq!(timestamps(&page), vec![5_000]);
+        assert_eq!(page.next_offset, None);
+
+        query.order = SortOrder::NewestFirst;
+        query.offset = 0;
+        let page = query.page(matches.clone());
+        assert_eq!(timestamps(&page), vec![5_000, 4_000]);
+
+        // a page that ends exactly on the last match is the last page
+        query.offset = 3;
+        let page = query.page(matches.clone());
+        assert_eq!(timestamps(&page), vec![2_000, 1_000]);
+        assert_eq!(page.next_offset, None);
+
+        query.offset = 10;
+        let page = query.page(matches);
+        assert!(page.samples.is_empty());
+        assert_eq!(page.total, 5);
+        assert_eq!(page.next_offset, None);
+    }
+
+    #[test]
+    fn caps_the_page_size() {
+        let matches: Vec<(Vec<u8>, Sample)> = (0..MAX_QUERY_LIMIT as u128 + 5)
+            .map(|t| {
+                (
+                    t.to_be_bytes().to_vec(),
+                    meter(METER, MeterDataTypes::Power, t),
+                )
+            })
+            .collect();
+        let mut query = SampleQuery::new(SampleTypes::Meter);
+        query.limit = usize::MAX;
+        let page = query.page(matches);
+        assert_eq!(page.samples.len(), MAX_QUERY_LIMIT);
+        assert_eq!(page.next_offset, Some(MAX_QUERY_LIMIT));
+    }
+
+    // Samples with the same timestamp keep their order from page to page
+    #[test]
+    fn breaks_ties_by_key() {
+        let matches: Vec<(Vec<u8>, Sample)> = [3u8, 1, 2]
+            .iter()
+            .map(|k| (vec![*k], meter(METER, MeterDataTypes::Power, 1_000)))
+            .collect();
+        let page = SampleQuery::new(SampleTypes::Meter).page(matches);
+        let keys: Vec<Vec<u8>> = page.samples.iter().map(|x| x.0.clone()).collect();
+        assert_eq!(keys, vec![vec![1], vec![2], vec![3]]);
+    }
+}
@@ -0,0 +1,302 @@
+use crate::modbus::*;
+use crate::samples::*;
+
+pub const MAX_READ_REGISTERS: u16 = 125;
+pub const MAX_READ_BITS: u16 = 2000;
+// Reading a few unwanted points is cheaper than another round trip over the radio
+pub const DEFAULT_MAX_GAP: u16 = 8;
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct ReadRequest {
+    pub slave: u8,
+    pub function: FunctionTypes,
+    pub start_address: u16,
+    pub count: u16,
+}
+
+// Where a piece of a request ended up: points [offset, offset + count) of read
+// number `read` fill the request starting at `position`
+#[derive(Clone, Debug, PartialEq)]
+pub struct ReadSlice {
+    pub read: usize,
+    pub offset: usize,
+    pub position: usize,
+    pub count: usize,
+}
+
+#[derive(Clone, Debug)]
+pub struct ReadPlan {
+    pub requests: Vec<ReadRequest>,
+    pub reads: Vec<ReadRequest>,
+    pub slices: Vec<Vec<ReadSlice>>,
+}
+
+impl ReadRequest {
+    pub fn new(slave: u8, function: FunctionTypes, start_address: u16, count: u16) -> Self {
+        ReadRequest {
+            slave,
+            function,
+            start_address,
+            count,
+        }
+    }
+
+    pub fn limit(&self) -> u16 {
+        match self.function {
+            FunctionTypes::ReadCoilStatus | FunctionTypes::ReadInputStatus => MAX_READ_BITS,
+            _ => MAX_READ_REGISTERS,
+        }
+    }
+
+    fn end(&self) -> u32 {
+        self.start_address as u32 + self.count as u32
+    }
+
+    pub fn as_message(&self) -> ModbusMessage {
+        ModbusMessage::new_read_message(
+            self.slave,
+            self.function.clone() as u8,
+            self.start_address,
+            self.count,
+        )
+    }
+}
+
+impl ReadPlan {
+    // Groups requests by slave and function, then merges ranges that overlap or
+    // sit within max_gap points of each other as long as the result stays inside
+    // the per-frame limit. Requests over the limit are split first.
+    pub fn new(requests: &[ReadRequest], max_gap: u16) -> Self {
+        let mut pieces: Vec<ReadRequest> = Vec::new();
+        for r in requests.iter().filter(|x| x.count > 0) {
+            let mut start = r.start_address as u32;
+            while start < r.end() {
+                let count = (r.end() - start).min(r.limit() as u32) as u16;
+                pieces.push(ReadRequest::new(
+                    r.slave,
+                    r.function.clone(),
+                    start as u16,
+                    count,
+                ));
+                start += count as u32;
+            }
+        }
+        pieces.sort_by_key(|x| (x.slave, x.function.clone() as u8, x.start_address));
+
+        let mut reads: Vec<ReadRequest> = Vec::new();
+        for p in pieces {
+            match reads.last_mut() {
+                Some(last)
+                    if last.slave == p.slave
+                        && last.function == p.function
+                        && p.start_address as u32 <= last.end() + max_gap as u32
+                        && p.end().max(last.end()) - last.start_address as u32
+                            <= last.limit() as u32 =>
+                {
+                    last.count = (p.end().max(last.end()) - last.start_address as u32) as u16;
+                }
+                _ => reads.push(p),
+            }
+        }
+
+        let mut slices = Vec::new();
+        for r in requests.iter() {
+            let mut parts = Vec::new();
+            for (i, read) in reads.iter().enumerate() {
+                if read.slave != r.slave || read.function != r.function {
+                    continue;
+                }
+                let start = (r.start_address as u32).max(read.start_address as u32);
+                let end = r.end().min(read.end());
+                if start >= end {
+                    continue;
+                }
+                parts.push(ReadSlice {
+                    read: i,
+                    offset: (start - read.start_address as u32) as usize,
+                    position: (start - r.start_address as u32) as usize,
+                    count: (end - start) as usize,
+                });
+            }
+            // a request can overlap two merged reads, keep the first read of each point
+            let mut covered = 0;
+            parts.retain_mut(|x| {
+                if x.position + x.count <= covered {
+                    return false;
+                }
+                if x.position < covered {
+                    let skip = covered - x.position;
+                    x.offset += skip;
+                    x.position += skip;
+                    x.count -= skip;
+                }
+                covered = x.position + x.count;
+                true
+            });
+            slices.push(parts);
+        }
+
+        ReadPlan {
+            requests: requests.to_vec(),
+            reads,
+            slices,
+        }
+    }
+
+    pub fn messages(&self) -> Vec<ModbusMessage> {
+        self.reads.iter().map(|x| x.as_message()).collect()
+    }
+
+    // Takes the values returned for each planned read, None where a read failed,
+    // and puts together the values for each original request
+    pub fn assemble(&self, results: &[Option<Vec<u16>>]) -> Vec<Option<Vec<u16>>> {
+        let mut assembled = Vec::new();
+        for (r, parts) in self.requests.iter().zip(self.slices.iter()) {
+            let mut values = vec![0u16; r.count as usize];
+            let mut complete = !parts.is_empty() || r.count == 0;
+            for part in parts.iter() {
+                match results.get(part.read) {
+                    Some(Some(v)) if v.len() >= part.offset + part.count => {
+                        values[part.position..part.position + part.count]
+                            .copy_from_slice(&v[part.offset..part.offset + part.count]);
+                    }
+                    _ => complete = false,
+                }
+            }
+            match complete {
+                true => assembled.push(Some(values)),
+                false => assembled.push(None),
+            }
+        }
+        assembled
+    }
+
+    // Same as assemble, for samples that came back in the order of self.reads
+    // with None where a read failed
+    pub fn split_samples(&self, samples: &[Option<BridgeSample>]) -> Vec<Option<BridgeSample>> {
+        let results: Vec<Option<Vec<u16>>> = samples
+            .iter()
+            .map(|x| match x {
+                Some(s) if s.exception.is_none() => Some(s.values.clone()),
+                _ => None,
+            })
+            .collect();
+        let mut split = Vec::new();
+        for ((r, values), parts) in self
+            .requests
+            .iter()
+            .zip(self.assemble(&results))
+            .zip(self.slices.iter())
+        {
+            let sample = parts
+                .first()
+                .and_then(|x| samples.get(x.read))
+                .and_then(|x| x.as_ref());
+            match (values, sample) {
+                (Some(values), Some(sample)) => {
+                    let mut s = sample.clone();
+                    s.start_address = r.start_address;
+                    s.values = values;
+                    s.named_values = Vec::new();
+                    s.typed_values = Vec::new();
+                    split.push(Some(s));
+                }
+                _ => split.push(None),
+            }
+        }
+        split
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn holding(start_address: u16, count: u16) -> ReadRequest {
+        ReadRequest::new(5, FunctionTypes::ReadHoldingRegisters, start_address, count)
+    }
+
+    #[test]
+    fn merges_nearby_reads() {
+        let requests = [
+            holding(21, 2),
+            holding(0, 4),
+            holding(10, 2),
+            ReadRequest::new(5, FunctionTypes::ReadInputRegisters, 0, 2),
+            ReadRequest::new(6, FunctionTypes::ReadHoldingRegisters, 0, 2),
+        ];
+        let plan = ReadPlan::new(&requests, DEFAULT_MAX_GAP);
+        assert_eq!(
+            plan.reads,
+            vec![
+                holding(0, 12),
+                holding(21, 2),
+                ReadRequest::new(5, FunctionTypes::ReadInputRegisters, 0, 2),
+                ReadRequest::new(6, FunctionTypes::ReadHoldingRegisters, 0, 2),
+            ]
+        );
+        assert_eq!(
+            plan.slices[2],
+            vec![ReadSlice {
+                read: 0,
+                offset: 10,
+                position: 0,
+                count: 2
+            }]
+        );
+
+        // no gap allowed, only overlapping and adjacent ranges merge
+        let plan = ReadPlan::new(&[holding(0, 4), holding(4, 2), holding(7, 1)], 0);
+        assert_eq!(plan.reads, vec![holding(0, 6), holding(7, 1)]);
+    }
+
+    #[test]
+    fn splits_reads_over_the_limit() {
+        let plan = ReadPlan::new(&[holding(0, 300)], DEFAULT_MAX_GAP);
+        assert_eq!(
+            plan.reads,
+            vec![holding(0, 125), holding(125, 125), holding(250, 50)]
+        );
+        assert_eq!(plan.slices[0].len(), 3);
+        assert_eq!(plan.slices[0][2].position, 250);
+
+        let coils = ReadRequest::new(5, FunctionTypes::ReadCoilStatus, 0, 2500);
+        let plan = ReadPlan::new(&[coils], DEFAULT_MAX_GAP);
+        assert_eq!(plan.reads.len(), 2);
+        assert_eq!(plan.reads[0].count, MAX_READ_BITS);
+
+        // merging stops at the limit
+        let plan = ReadPlan::new(&[holding(0, 100), holding(100, 100)], DEFAULT_MAX_GAP);
+        assert_eq!(plan.reads, vec![holding(0, 100), holding(100, 100)]);
+    }
+
+    #[test]
+    fn assembles_each_request() {
+        let requests = [holding(0, 2), holding(4, 3), holding(100, 1)];
+        let plan = ReadPlan::new(&requests, DEFAULT_MAX_GAP);
+        assert_eq!(plan.reads, vec![holding(0, 7), holding(100, 1)]);
+        let assembled = plan.assemble(&[Some(vec![0, 1, 2, 3, 4, 5, 6]), None]);
+        assert_eq!(assembled, vec![Some(vec![0, 1]), Some(vec![4, 5, 6]), None]);
+    }
+
+    #[test]
+    fn splits_samples_back_to_requests() {
+        let requests = [holding(0, 2), holding(4, 1), holding(100, 1)];
+        let plan = ReadPlan::new(&requests, DEFAULT_MAX_GAP);
+        let mut sample = BridgeSample::new_empty();
+        sample.slave = 5;
+        sample.values = vec![10, 11, 12, 13, 14];
+
+        // the second read failed
+        let split = plan.split_samples(&[Some(sample), None]);
+        assert_eq!(split.len(), 3);
+        let first = split[0].as_ref().unwrap();
+        assert_eq!(
+            (first.start_address, first.values.clone()),
+            (0, vec![10, 11])
+        );
+        let second = split[1].as_ref().unwrap();
+        assert_eq!((second.start_address, second.values.clone()), (4, vec![14]));
+        assert_eq!(split[2], None);
+    }
+}
@@ -0,0 +1,443 @@
+use crate::device::DeviceTypes;
+use crate::modbus::*;
+use crate::packet::*;
+use crate::read_plan::*;
+use crate::samples::*;
+use crate::store::*;
+use crate::task::*;
+use std::path::Path;
+use tokio::io::{AsyncRead, AsyncWrite};
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct RegisterDefinition {
+    pub name: String,
+    pub address: u16,
+    #[serde(default = "default_function")]
+    pub function: FunctionTypes,
+    pub data_type: ValueType,
+    // only used by strings, two characters per register
+    #[serde(default)]
+    pub length: u16,
+    #[serde(default)]
+    pub word_order: WordOrder,
+    #[serde(default = "default_scale")]
+    pub scale: f64,
+    #[serde(default)]
+    pub offset: f64,
+    #[serde(default)]
+    pub unit: String,
+    // the value is the device's own clock in unix seconds, used as the
+    // sample's device time
+    #[serde(default)]
+    pub device_time: bool,
+}
+
+// A map belongs to one slave on one bridge. bridge is the bridge's XBee
+// address, or tcp_hardware_id for slaves reached over Ethernet.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct RegisterMap {
+    pub name: String,
+    pub bridge: [u8; 8],
+    pub slave: u8,
+    pub registers: Vec<RegisterDefinition>,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct NamedValue {
+    pub name: String,
+    pub value: RegisterValue,
+    // value * scale + offset, strings have none
+    pub scaled: Option<f64>,
+    pub unit: String,
+}
+
+#[derive(Debug)]
+pub enum RegisterMapError {
+    Io(std::io::Error),
+    Parse(serde_json::Error),
+    Invalid(String),
+}
+
+fn default_function() -> FunctionTypes {
+    FunctionTypes::ReadHoldingRegisters
+}
+
+fn default_scale() -> f64 {
+    1.0
+}
+
+impl std::fmt::Display for RegisterMapError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            RegisterMapError::Io(e) => write!(f, "{}", e),
+            RegisterMapError::Parse(e) => write!(f, "{}", e),
+            RegisterMapError::Invalid(s) => write!(f, "invalid register map: {}", s),
+        }
+    }
+}
+
+impl From<std::io::Error> for RegisterMapError {
+    fn from(e: std::io::Error) -> Self {
+        RegisterMapError::Io(e)
+    }
+}
+
+impl From<serde_json::Error> for RegisterMapError {
+    fn from(e: serde_json::Error) -> Self {
+        RegisterMapError::Parse(e)
+    }
+}
+
+// BridgeSample derives Hash, so the scaled float is hashed by its bits
+impl std::hash::Hash for NamedValue {
+    fn hash<H: std::hash::Hasher>(&self, state: &mut H) {
+        self.name.hash(state);
+        self.value.hash(state);
+        self.scaled.map(|x| x.to_bits()).hash(state);
+        self.unit.hash(state);
+    }
+}
+
+impl RegisterDefinition {
+    pub fn register_count(&self) -> u16 {
+        self.data_type.register_count().unwrap_or(self.length)
+    }
+
+    pub fn data_type(&self) -> DataTypes {
+        match self.function {
+            FunctionTypes::ReadCoilStatus => DataTypes::Coil,
+            FunctionTypes::ReadInputStatus => DataTypes::Input,
+            _ => DataTypes::Register,
+        }
+    }
+
+    pub fn decode(&self, registers: &[u16]) -> Option<NamedValue> {
+        let count = self.register_count() as usize;
+        let value =
+            RegisterValue::decode(registers.get(..count)?, self.data_type, self.word_order)?;
+        Some(NamedValue {
+            name: self.name.clone(),
+            scaled: value.as_f64().map(|x| x * self.scale + self.offset),
+            value,
+            unit: self.unit.clone(),
+        })
+    }
+}
+
+impl RegisterMap {
+    pub fn from_json(json: &str) -> Result<Self, RegisterMapError> {
+        let map: RegisterMap = serde_json::from_str(json)?;
+        map.validate()?;
+        Ok(map)
+    }
+
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Vec<Self>, RegisterMapError> {
+        let maps: Vec<RegisterMap> = serde_json::from_str(&std::fs::read_to_string(path)?)?;
+        for map in maps.iter() {
+            map.validate()?;
+        }
+        Ok(maps)
+    }
+
+    pub fn validate(&self) -> Result<(), RegisterMapError> {
+        for r in self.registers.iter() {
+            match r.function {
+                FunctionTypes::ReadCoilStatus | FunctionTypes::ReadInputStatus
+                    if r.data_type != ValueType::U16 =>
+                {
+                    return Err(RegisterMapError::Invalid(format!(
+                        "{} reads single bits and must be u16",
+                        r.name
+                    )));
+                }
+                FunctionTypes::ReadCoilStatus
+                | FunctionTypes::ReadInputStatus
+                | FunctionTypes::ReadHoldingRegisters
+                | FunctionTypes::ReadInputRegisters => {}
+                _ => {
+                    return Err(RegisterMapError::Invalid(format!(
+                        "{} does not use a read function",
+                        r.name
+                    )));
+                }
+            }
+            if r.device_time && r.data_type == ValueType::String {
+                return Err(RegisterMapError::Invalid(format!(
+                    "{} holds the device time and must be a number",
+                    r.name
+                )));
+            }
+            if r.register_count() == 0 {
+                return Err(RegisterMapError::Invalid(format!(
+                    "{} needs a length",
+                    r.name
+                )));
+            }
+        }
+        Ok(())
+    }
+
+    // One read per definition, see plan for what actually goes out
+    pub fn reads(&self) -> Vec<ReadRequest> {
+        self.registers
+            .iter()
+            .map(|r| {
+                ReadRequest::new(
+                    self.slave,
+                    r.function.clone(),
+                    r.address,
+                    r.register_count(),
+                )
+            })
+            .collect()
+    }
+
+    pub fn plan(&self, max_gap: u16) -> ReadPlan {
+        ReadPlan::new(&self.reads(), max_gap)
+    }
+
+    // DeviceDB::send_task fills in the bridge's cached network address
+    pub fn poll_tasks(&self, min: usize, sec: usize) -> Vec<Task> {
+        self.plan(DEFAULT_MAX_GAP)
+            .messages()
+            .iter_mut()
+            .map(|m| {
+                Task::new(
+                    Packet::new_transmit(&self.bridge, &m.as_bytes()),
+                    TaskTypes::Periodic,
+                    min,
+                    sec,
+                    DeviceTypes::Bridge,
+                )
+            })
+            .collect()
+    }
+
+    // Wired counterpart of poll_tasks for a map whose bridge is the master's
+    // hardware id. Runs the plan on the line and stores one sample per
+    // definition, the same records a radio bridge ends up with, so they are
+    // uploaded like any other bridge sample. Failed reads are logged and left
+    // out. Returns how many samples were stored.
+    pub async fn poll_rtu<T: AsyncRead + AsyncWrite + Unpin>(
+        &self,
+        master: &mut RtuMaster<T>,
+        store: &SampleStore,
+    ) -> sled::Result<usize> {
+        if master.hardware_id != self.bridge {
+            return Ok(0);
+        }
+        let plan = self.plan(DEFAULT_MAX_GAP);
+        let mut samples = Vec::new();
+        for message in plan.messages() {
+            match master.poll(message).await {
+                Ok(sample) => samples.push(Some(sample)),
+                Err(e) => {
+                    eprintln!("{}: slave {}: {}", self.name, self.slave, e);
+                    samples.push(None);
+                }
+            }
+        }
+        let mut stored = 0;
+        for sample in plan.split_samples(&samples).into_iter().flatten() {
+            store.insert(&Sample::Bridge(sample))?;
+            stored += 1;
+        }
+        Ok(stored)
+    }
+
+    // Adds the decoded values to the sample and takes its device time from the
+    // map when there is one, before validation looks at the timestamp
+    pub fn apply(&self, sample: &mut BridgeSample) {
+        let mut named = self.decode(sample);
+        for value in named.iter() {
+            if !self
+                .registers
+                .iter()
+                .any(|r| r.device_time && r.name == value.name)
+            {
+                continue;
+            }
+            match value.value.as_f64() {
+                Some(seconds) if seconds >= 0.0 => {
+                    sample.times.device = Some(seconds as u128 * 1000);
+                    sample.timestamp = sample.times.best();
+                }
+                _ => eprintln!("{}: bad device time in {}", self.name, value.name),
+            }
+        }
+        sample.named_values.append(&mut named);
+    }
+
+    pub fn matches(&self, sample: &BridgeSample) -> bool {
+        sample.hardware_id == self.bridge && sample.slave == self.slave
+    }
+
+    // Every definition read with the sample's function that falls entirely
+    // inside the sample's range
+    pub fn decode(&self, sample: &BridgeSample) -> Vec<NamedValue> {
+        let mut named = Vec::new();
+        if sample.write || sample.exception.is_some() || !self.matches(sample) {
+            return named;
+        }
+        let end = sample.start_address as usize + sample.values.len();
+        for r in self.registers.iter() {
+            if r.function != sample.function
+                || r.address < sample.start_address
+                || r.address as usize + r.register_count() as usize > end
+            {
+                continue;
+            }
+            let first = (r.address - sample.start_address) as usize;
+            if let Some(value) = r.decode(&sample.values[first..]) {
+                named.push(value);
+            }
+        }
+        named
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const BRIDGE: [u8; 8] = [0x00, 0x13, 0xA2, 0x00, 0x41, 0x5B, 0x7C, 0x01];
+
+    fn map() -> RegisterMap {
+        RegisterMap::from_json(
+            r#"{
+                "name": "meter",
+                "bridge": [0, 19, 162, 0, 65, 91, 124, 1],
+                "slave": 5,
+                "registers": [
+                    {"name": "setpoint", "address": 0, "data_type": "u16"},
+                    {"name": "voltage", "address": 0, "function": "ReadInputRegisters",
+                     "data_type": "u16", "scale": 0.1, "unit": "V"}
+                ]
+            }"#,
+        )
+        .unwrap()
+    }
+
+    fn sample(function: FunctionTypes) -> BridgeSample {
+        let mut sample = BridgeSample::new_empty();
+        sample.hardware_id = BRIDGE;
+        sample.slave = 5;
+        sample.datatype = DataTypes::Register;
+        sample.function = function;
+        sample.values = vec![2300];
+        sample
+    }
+
+    #[test]
+    fn holding_and_input_registers_stay_apart() {
+        let map = map();
+        let named = map.decode(&sample(FunctionTypes::ReadHoldingRegisters));
+        assert_eq!(named.len(), 1);
+        assert_eq!(named[0].name, "setpoint");
+        assert_eq!(named[0].scaled, Some(2300.0));
+
+        let named = map.decode(&sample(FunctionTypes::ReadInputRegisters));
+        assert_eq!(named.len(), 1);
+        assert_eq!(named[0].name, "voltage");
+        assert_eq!(named[0].scaled, Some(230.0));
+    }
+
+    #[test]
+    fn ignores_other_bridges_and_slaves() {
+        let map = map();
+        let mut other = sample(FunctionTypes::ReadHoldingRegisters);
+        other.hardware_id[7] = 0x02;
+        assert!(map.decode(&other).is_empty());
+
+        let mut other = sample(FunctionTypes::ReadHoldingRegisters);
+        other.slave = 6;
+        assert!(map.decode(&other).is_empty());
+    }
+
+    #[test]
+    fn polls_the_map_bridge() {
+        let tasks = map().poll_tasks(1, 0);
+        assert_eq!(tasks.len(), 2);
+        for task in tasks.iter() {
+            assert_eq!(task.packet.frame_type, FrameTypes::TransmitRequest);
+            assert_eq!(task.packet.address, BRIDGE);
+        }
+    }
+
+    #[test]
+    fn takes_the_device_time_from_the_map() {
+        let mut map = map();
+        map.registers.push(
+            serde_json::from_str(
+                r#"{"name": "clock", "address": 1, "data_type": "u32", "device_time": true}"#,
+            )
+            .unwrap(),
+        );
+        map.validate().unwrap();
+        let mut sample = sample(FunctionTypes::ReadHoldingRegisters);
+        sample.times.received = 1_600_000_100_000;
+        sample.timestamp = sample.times.received;
+        // 1600000000 seconds
+        sample.values = vec![2300, 0x5F5E, 0x1000];
+        map.apply(&mut sample);
+        assert_eq!(sample.times.device, Some(1_600_000_000_000));
+        assert_eq!(sample.timestamp, 1_600_000_000_000);
+        assert_eq!(sample.named_values.len(), 2);
+
+        map.registers[2].data_type = ValueType::String;
+        map.registers[2].length = 2;
+        assert!(map.validate().is_err());
+    }
+
+    #[tokio::test]
+    async fn polls_wired_slaves_into_the_store() {
+        use crate::simulator::ModbusSlave;
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        let (line, mut far_end) = tokio::io::duplex(256);
+        tokio::spawn(async move {
+            let mut slave = ModbusSlave::default();
+            slave.holding_registers.insert(0, 2300);
+            slave.input_registers.insert(0, 2300);
+            // both reads in the map are eight byte requests
+            let mut request = [0u8; 8];
+            while far_end.read_exact(&mut request).await.is_ok() {
+                let mut response = slave.respond(request.to_vec()).unwrap();
+                let crc = crc_helper(response.clone());
+                response.push((crc & 0x0FF) as u8);
+                response.push((crc >> 8) as u8);
+                far_end.write_all(&response).await.unwrap();
+            }
+        });
+        let mut master = RtuMaster::new(line, 19200, BRIDGE);
+        let store =
+            SampleStore::from_db(sled::Config::new().temporary(true).open().unwrap()).unwrap();
+        let map = map();
+        assert_eq!(map.poll_rtu(&mut master, &store).await.unwrap(), 2);
+
+        let mut stored: Vec<Sample> = store
+            .get_samples(&SampleTypes::Bridge, 10)
+            .unwrap()
+            .into_iter()
+            .map(|x| x.1)
+            .collect();
+        stored.sort_by_key(|x| match x {
+            Sample::Bridge(s) => s.function.clone() as u8,
+            _ => 0,
+        });
+        let named: Vec<String> = stored
+            .iter()
+            .flat_map(|x| match x {
+                Sample::Bridge(s) => map.decode(s),
+                _ => Vec::new(),
+            })
+            .map(|x| format!("{} {:?}", x.name, x.scaled))
+            .collect();
+        assert_eq!(named, vec!["setpoint Some(2300.0)", "voltage Some(230.0)"]);
+
+        // a master on another line leaves the map alone
+        let (line, _far_end) = tokio::io::duplex(256);
+        let mut other = RtuMaster::new(line, 19200, [0; 8]);
+        assert_eq!(map.poll_rtu(&mut other, &store).await.unwrap(), 0);
+    }
+}
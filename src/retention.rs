@@ -0,0 +1,154 @@
+use crate::rollup::RollupInterval;
+use crate::samples::*;
+use std::path::Path;
+
+// Limits for one sample type. Records older than max_age_secs always go.
+// Past max_bytes, raw samples are first thinned to one per downsample window
+// and series, then the oldest are evicted until the type fits again.
+#[derive(Clone, Serialize, Deserialize, PartialEq, Debug)]
+pub struct RetentionPolicy {
+    pub sample_type: SampleTypes,
+    #[serde(default)]
+    pub max_age_secs: Option<u64>,
+    #[serde(default)]
+    pub max_bytes: Option<u64>,
+    #[serde(default)]
+    pub downsample: Option<RollupInterval>,
+}
+
+#[derive(Clone, Copy, Serialize, Deserialize, PartialEq, Debug, Default)]
+pub struct EvictionCounters {
+    pub expired: u64,
+    pub downsampled: u64,
+    pub evicted: u64,
+}
+
+// Running totals since the store was created, not what changed since the last
+// report, so ingest can take the latest report as is and a lost or repeated
+// report doesn't skew the count
+#[derive(Clone, Serialize, Deserialize, PartialEq, Debug)]
+pub struct EvictionReport {
+    pub sample_type: SampleTypes,
+    pub counters: EvictionCounters,
+}
+
+impl RetentionPolicy {
+    pub fn load<P: AsRef<Path>>(path: P) -> std::io::Result<Vec<Self>> {
+        let json = std::fs::read_to_string(path)?;
+        serde_json::from_str(&json)
+            .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidData, e))
+    }
+}
+
+impl EvictionCounters {
+    pub fn total(&self) -> u64 {
+        self.expired + self.downsampled + self.evicted
+    }
+
+    pub fn add(&mut self, other: &EvictionCounters) {
+        self.expired += other.expired;
+        self.downsampled += other.downsampled;
+        self.evicted += other.evicted;
+    }
+}
+
+// Samples that measure the same thing, downsampling keeps one of each per window
+pub fn series_key(sample: &Sample) -> Vec<u8> {
+    let mut key = sample.hardware_id().unwrap_or([0; 8]).to_vec();
+    match sample {
+        Sample::Meter(m) => key.push(m.data_type.clone() as u8),
+        Sample::Bridge(b) => {
+            key.push(b.slave);
+            key.push(b.datatype.clone() as u8);
+            key.push(b.function.clone() as u8);
+            key.extend_from_slice(&b.start_address.to_be_bytes());
+        }
+        Sample::Pulse(_) | Sample::None => {}
+    }
+    key
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::modbus::FunctionTypes;
+
+    #[test]
+    fn counters_add_up() {
+        let mut counters = EvictionCounters {
+            expired: 1,
+            downsampled: 2,
+            evicted: 3,
+        };
+        counters.add(&EvictionCounters {
+            expired: 10,
+            downsampled: 0,
+            evicted: 1,
+        });
+        assert_eq!(
+            counters,
+            EvictionCounters {
+                expired: 11,
+                downsampled: 2,
+                evicted: 4,
+            }
+        );
+        assert_eq!(counters.total(), 17);
+        assert_eq!(EvictionCounters::default().total(), 0);
+    }
+
+    #[test]
+    fn series_keys_tell_measurements_apart() {
+        let mut power = MeterSample::new_empty();
+        power.hardware_id = [1; 8];
+        power.data_type = MeterDataTypes::Power;
+        let mut current = power.clone();
+        current.data_type = MeterDataTypes::Current;
+        let mut later = power.clone();
+        later.timestamp += 60_000;
+        later.values = vec![5];
+        assert_ne!(
+            series_key(&Sample::Meter(power.clone())),
+            series_key(&Sample::Meter(current))
+        );
+        assert_eq!(
+            series_key(&Sample::Meter(power)),
+            series_key(&Sample::Meter(later))
+        );
+
+        let mut holding = BridgeSample::new_empty();
+        holding.hardware_id = [1; 8];
+        holding.slave = 5;
+        holding.function = FunctionTypes::ReadHoldingRegisters;
+        let mut input = holding.clone();
+        input.function = FunctionTypes::ReadInputRegisters;
+        let mut elsewhere = holding.clone();
+        elsewhere.start_address = 10;
+        let mut other_slave = holding.clone();
+        other_slave.slave = 6;
+        let holding = series_key(&Sample::Bridge(holding));
+        assert_ne!(holding, series_key(&Sample::Bridge(input)));
+        assert_ne!(holding, series_key(&Sample::Bridge(elsewhere)));
+        assert_ne!(holding, series_key(&Sample::Bridge(other_slave)));
+
+        // a bridge's pulse counters are one series
+        let mut pulse = PulseSample::new_empty();
+        pulse.hardware_id = [1; 8];
+        assert_eq!(series_key(&Sample::Pulse(pulse)), vec![1; 8]);
+    }
+
+    #[test]
+    fn policies_default_their_limits() {
+        let json = r#"[{"sample_type": "Pulse", "max_bytes": 1000, "downsample": "FiveMinutes"}]"#;
+        let policies: Vec<RetentionPolicy> = serde_json::from_str(json).unwrap();
+        assert_eq!(
+            policies,
+            vec![RetentionPolicy {
+                sample_type: SampleTypes::Pulse,
+                max_age_secs: None,
+                max_bytes: Some(1000),
+                downsample: Some(RollupInterval::FiveMinutes),
+            }]
+        );
+    }
+}
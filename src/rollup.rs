@@ -0,0 +1,486 @@
+use crate::modbus::{DataTypes, FunctionTypes};
+use crate::pulse::PULSE_CHANNELS;
+use crate::samples::*;
+use std::collections::BTreeMap;
+
+#[derive(Clone, Copy, Serialize, Deserialize, PartialEq, Eq, PartialOrd, Ord, Hash, Debug)]
+pub enum RollupInterval {
+    OneMinute,
+    FiveMinutes,
+    FifteenMinutes,
+}
+
+// One channel over a window. Only closed windows are rolled up, but a window
+// can still be split across uploads when it holds more samples than one
+// request returns, or when a late sample turns up after its window went out.
+// Ingest merges the parts of a window by taking the smallest min and largest
+// max, weighting each mean by its count, and keeping last from the part with
+// the latest last_timestamp. Pulse deltas and consumption simply add up.
+#[derive(Clone, Serialize, Deserialize, PartialEq, Debug)]
+pub struct ChannelStats {
+    pub min: u32,
+    pub max: u32,
+    pub mean: f64,
+    pub last: u32,
+    // unflagged samples that had this channel, fewer than the rollup's samples
+    // when some were flagged or a meter's channel config changed in the window
+    pub count: u32,
+    pub first_timestamp: u128,
+    pub last_timestamp: u128,
+}
+
+#[derive(Clone, Serialize, Deserialize, PartialEq, Debug)]
+pub struct MeterRollup {
+    pub hardware_id: [u8; 8],
+    pub data_type: MeterDataTypes,
+    pub interval: RollupInterval,
+    pub start: u128,
+    pub samples: u32,
+    // oldest and newest sample in this part of the window
+    pub first_timestamp: u128,
+    pub last_timestamp: u128,
+    // samples in the window that validation flagged
+    pub flagged: u32,
+    pub values: Vec<ChannelStats>,
+    pub channels: Vec<ChannelInfo>,
+}
+
+#[derive(Clone, Serialize, Deserialize, PartialEq, Debug)]
+pub struct BridgeRollup {
+    pub hardware_id: [u8; 8],
+    pub slave: u8,
+    pub datatype: DataTypes,
+    pub function: FunctionTypes,
+    pub start_address: u16,
+    pub interval: RollupInterval,
+    pub start: u128,
+    pub samples: u32,
+    // oldest and newest sample in this part of the window
+    pub first_timestamp: u128,
+    pub last_timestamp: u128,
+    // samples in the window that validation flagged
+    pub flagged: u32,
+    pub values: Vec<ChannelStats>,
+}
+
+#[derive(Clone, Serialize, Deserialize, PartialEq, Debug)]
+pub struct PulseRollup {
+    pub hardware_id: [u8; 8],
+    pub interval: RollupInterval,
+    pub start: u128,
+    pub samples: u32,
+    // oldest and newest sample in this part of the window
+    pub first_timestamp: u128,
+    pub last_timestamp: u128,
+    // samples in the window that validation flagged
+    pub flagged: u32,
+    pub deltas: Vec<u64>,
+    pub consumption: Vec<Option<f64>>,
+    pub units: Vec<String>,
+}
+
+impl RollupInterval {
+    pub fn parse(val: &str) -> Option<Self> {
+        match val {
+            "1m" => Some(RollupInterval::OneMinute),
+            "5m" => Some(RollupInterval::FiveMinutes),
+            "15m" => Some(RollupInterval::FifteenMinutes),
+            _ => None,
+        }
+    }
+
+    pub fn millis(&self) -> u128 {
+        match self {
+            RollupInterval::OneMinute => 60_000,
+            RollupInterval::FiveMinutes => 5 * 60_000,
+            RollupInterval::FifteenMinutes => 15 * 60_000,
+        }
+    }
+
+    // Windows start on multiples of the interval since the epoch, so they line
+    // up with the minute, quarter hour and so on in UTC
+    pub fn window_start(&self, timestamp: u128) -> u128 {
+        timestamp - timestamp % self.millis()
+    }
+
+    pub fn is_complete(&self, timestamp: u128, now: u128) -> bool {
+        self.window_start(timestamp) + self.millis() <= now
+    }
+
+    // Samples in windows that haven't closed yet are left for the next upload
+    pub fn complete(&self, samples: Vec<(Vec<u8>, Sample)>, now: u128) -> Vec<(Vec<u8>, Sample)> {
+        samples
+            .into_iter()
+            .filter(|x| self.is_complete(x.1.timestamp(), now))
+            .collect()
+    }
+}
+
+// One channel's readings in time order, never empty
+fn column_stats(column: &[(u128, u32)]) -> ChannelStats {
+    let sum: u64 = column.iter().map(|x| x.1 as u64).sum();
+    ChannelStats {
+        min: column.iter().map(|x| x.1).min().unwrap(),
+        max: column.iter().map(|x| x.1).max().unwrap(),
+        mean: sum as f64 / column.len() as f64,
+        last: column[column.len() - 1].1,
+        count: column.len() as u32,
+        first_timestamp: column[0].0,
+        last_timestamp: column[column.len() - 1].0,
+    }
+}
+
+// Values in time order, one vector per sample with its timestamp. Channels
+// missing from some samples are summarised over the samples that have them.
+fn channel_stats(values: &[(u128, &Vec<u32>)]) -> Vec<ChannelStats> {
+    let channels = values.iter().map(|x| x.1.len()).max().unwrap_or(0);
+    let mut stats = Vec::new();
+    for i in 0..channels {
+        let column: Vec<(u128, u32)> = values
+            .iter()
+            .filter_map(|x| x.1.get(i).map(|v| (x.0, *v)))
+            .collect();
+        stats.push(column_stats(&column));
+    }
+    stats
+}
+
+// Meter values are matched up by the channel they came from, not their
+// position, so a config change inside a window doesn't merge two channels.
+// Samples from before channels were configurable have one value per channel.
+fn meter_channel_stats(window: &[&MeterSample]) -> (Vec<ChannelStats>, Vec<ChannelInfo>) {
+    let mut columns: BTreeMap<u8, Vec<(u128, u32)>> = BTreeMap::new();
+    let mut infos: BTreeMap<u8, ChannelInfo> = BTreeMap::new();
+    for s in window.iter() {
+        for (i, value) in s.values.iter().enumerate() {
+            let info = match s.channels.get(i) {
+                Some(c) => c.clone(),
+                None => ChannelInfo {
+                    index: i as u8,
+                    label: String::new(),
+                    phase: Phase::Unknown,
+                },
+            };
+            columns
+                .entry(info.index)
+                .or_default()
+                .push((s.timestamp, *value));
+            // the latest label wins when a channel was renamed
+            infos.insert(info.index, info);
+        }
+    }
+    let stats = columns.values().map(|x| column_stats(x)).collect();
+    let channels = match window.iter().any(|x| !x.channels.is_empty()) {
+        true => infos.into_values().collect(),
+        false => Vec::new(),
+    };
+    (stats, channels)
+}
+
+// Flagged samples are counted but left out of the aggregates
+pub fn rollup_meter(samples: &[MeterSample], interval: RollupInterval) -> Vec<MeterRollup> {
+    let mut windows: BTreeMap<([u8; 8], u8, u128), Vec<&MeterSample>> = BTreeMap::new();
+    for s in samples.iter() {
+        windows
+            .entry((
+                s.hardware_id,
+                s.data_type.clone() as u8,
+                interval.window_start(s.timestamp),
+            ))
+            .or_default()
+            .push(s);
+    }
+    let mut rollups = Vec::new();
+    for ((hardware_id, _data_type, start), mut window) in windows {
+        window.sort_by_key(|x| x.timestamp);
+        let last = window[window.len() - 1];
+        let clean: Vec<&MeterSample> = window
+            .iter()
+            .filter(|x| x.flags.is_empty())
+            .copied()
+            .collect();
+        let (values, channels) = meter_channel_stats(&clean);
+        rollups.push(MeterRollup {
+            hardware_id,
+            data_type: last.data_type.clone(),
+            interval,
+            start,
+            samples: window.len() as u32,
+            first_timestamp: window[0].timestamp,
+            last_timestamp: last.timestamp,
+            flagged: (window.len() - clean.len()) as u32,
+            values,
+            channels,
+        });
+    }
+    rollups
+}
+
+// hardware id, slave, function, start address and window start
+type BridgeWindow = ([u8; 8], u8, u8, u16, u128);
+
+// Writes and exceptions carry no readings and are left out, flagged samples
+// are counted but left out of the aggregates
+pub fn rollup_bridge(samples: &[BridgeSample], interval: RollupInterval) -> Vec<BridgeRollup> {
+    let mut windows: BTreeMap<BridgeWindow, Vec<&BridgeSample>> = BTreeMap::new();
+    for s in samples.iter().filter(|x| !x.write && x.exception.is_none()) {
+        windows
+            .entry((
+                s.hardware_id,
+                s.slave,
+                s.function.clone() as u8,
+                s.start_address,
+                interval.window_start(s.timestamp),
+            ))
+            .or_default()
+            .push(s);
+    }
+    let mut rollups = Vec::new();
+    for ((hardware_id, slave, _function, start_address, start), mut window) in windows {
+        window.sort_by_key(|x| x.timestamp);
+        let clean: Vec<&BridgeSample> = window
+            .iter()
+            .filter(|x| x.flags.is_empty())
+            .copied()
+            .collect();
+        let values: Vec<Vec<u32>> = clean
+            .iter()
+            .map(|x| x.values.iter().map(|v| *v as u32).collect())
+            .collect();
+        let values: Vec<(u128, &Vec<u32>)> = clean
+            .iter()
+            .zip(values.iter())
+            .map(|(s, v)| (s.timestamp, v))
+            .collect();
+        rollups.push(BridgeRollup {
+            hardware_id,
+            slave,
+            datatype: window[0].datatype.clone(),
+            function: window[0].function.clone(),
+            start_address,
+            interval,
+            start,
+            samples: window.len() as u32,
+            first_timestamp: window[0].timestamp,
+            last_timestamp: window[window.len() - 1].timestamp,
+            flagged: (window.len() - clean.len()) as u32,
+            values: channel_stats(&values),
+        });
+    }
+    rollups
+}
+
+// Sums the deltas filled in by the pulse tracker, so track the samples first.
+// Flagged samples are counted but their deltas are left out.
+pub fn rollup_pulse(samples: &[PulseSample], interval: RollupInterval) -> Vec<PulseRollup> {
+    let mut windows: BTreeMap<([u8; 8], u128), Vec<&PulseSample>> = BTreeMap::new();
+    for s in samples.iter() {
+        windows
+            .entry((s.hardware_id, interval.window_start(s.timestamp)))
+            .or_default()
+            .push(s);
+    }
+    let mut rollups = Vec::new();
+    for ((hardware_id, start), mut window) in windows {
+        window.sort_by_key(|x| x.timestamp);
+        let mut rollup = PulseRollup {
+            hardware_id,
+            interval,
+            start,
+            samples: window.len() as u32,
+            first_timestamp: window[0].timestamp,
+            last_timestamp: window[window.len() - 1].timestamp,
+            flagged: window.iter().filter(|x| !x.flags.is_empty()).count() as u32,
+            deltas: vec![0; PULSE_CHANNELS],
+            consumption: vec![None; PULSE_CHANNELS],
+            units: vec!["pulses".to_string(); PULSE_CHANNELS],
+        };
+        // a flagged delta is as likely a counter reset as real pulses
+        for s in window.iter().filter(|x| x.flags.is_empty()) {
+            for (i, c) in s.counts.iter().enumerate().take(PULSE_CHANNELS) {
+                rollup.deltas[i] += c.delta.unwrap_or(0) as u64;
+                if let Some(consumed) = c.consumption {
+                    rollup.consumption[i] = Some(rollup.consumption[i].unwrap_or(0.0) + consumed);
+                }
+                rollup.units[i] = c.unit.clone();
+            }
+        }
+        rollups.push(rollup);
+    }
+    rollups
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::pulse::*;
+    use crate::validation::QualityFlag;
+
+    const DEVICE: [u8; 8] = [1, 2, 3, 4, 5, 6, 7, 8];
+    // 2020-09-13 12:30:00 UTC, on a quarter hour
+    const START: u128 = 1_600_000_200_000;
+
+    fn meter(timestamp: u128, values: Vec<u32>) -> MeterSample {
+        let mut sample = MeterSample::new_empty();
+        sample.hardware_id = DEVICE;
+        sample.data_type = MeterDataTypes::Power;
+        sample.timestamp = timestamp;
+        sample.values = values;
+        sample
+    }
+
+    #[test]
+    fn windows_line_up_with_the_clock() {
+        let interval = RollupInterval::FiveMinutes;
+        assert_eq!(interval.window_start(START + 299_999), START);
+        assert_eq!(interval.window_start(START + 300_000), START + 300_000);
+        assert!(interval.is_complete(START + 10, START + 300_000));
+        assert!(!interval.is_complete(START + 10, START + 299_999));
+        assert_eq!(
+            RollupInterval::parse("15m"),
+            Some(RollupInterval::FifteenMinutes)
+        );
+        assert_eq!(RollupInterval::parse("2m"), None);
+    }
+
+    #[test]
+    fn summarises_meter_channels() {
+        let samples = [
+            meter(START + 20_000, vec![30, 7]),
+            meter(START + 10_000, vec![10]),
+            meter(START + 40_000, vec![20, 9]),
+            meter(START + 70_000, vec![99, 99]),
+        ];
+        let rollups = rollup_meter(&samples, RollupInterval::OneMinute);
+        assert_eq!(rollups.len(), 2);
+        let rollup = &rollups[0];
+        assert_eq!(rollup.start, START);
+        assert_eq!(rollup.samples, 3);
+        assert_eq!(rollup.first_timestamp, START + 10_000);
+        assert_eq!(rollup.last_timestamp, START + 40_000);
+        assert_eq!(
+            rollup.values[0],
+            ChannelStats {
+                min: 10,
+                max: 30,
+                mean: 20.0,
+                last: 20,
+                count: 3,
+                first_timestamp: START + 10_000,
+                last_timestamp: START + 40_000,
+            }
+        );
+        // the first sample had no second channel
+        assert_eq!(rollup.values[1].count, 2);
+        assert_eq!(rollup.values[1].first_timestamp, START + 20_000);
+        assert_eq!(rollup.values[1].mean, 8.0);
+    }
+
+    fn channel(index: u8, label: &str) -> ChannelInfo {
+        ChannelInfo {
+            index,
+            label: label.to_string(),
+            phase: Phase::L1,
+        }
+    }
+
+    #[test]
+    fn matches_meter_values_by_channel() {
+        let mut before = meter(START + 10_000, vec![10, 20]);
+        before.channels = vec![channel(0, "mains"), channel(1, "spare")];
+        // mains was disabled, so spare moved to the front
+        let mut after = meter(START + 20_000, vec![30]);
+        after.channels = vec![channel(1, "spare")];
+
+        let rollups = rollup_meter(&[before, after], RollupInterval::OneMinute);
+        assert_eq!(rollups.len(), 1);
+        let rollup = &rollups[0];
+        assert_eq!(
+            rollup.channels,
+            vec![channel(0, "mains"), channel(1, "spare")]
+        );
+        assert_eq!(rollup.values[0].count, 1);
+        assert_eq!(rollup.values[0].max, 10);
+        assert_eq!(rollup.values[1].count, 2);
+        assert_eq!(rollup.values[1].min, 20);
+        assert_eq!(rollup.values[1].max, 30);
+    }
+
+    #[test]
+    fn leaves_flagged_samples_out_of_the_aggregates() {
+        let mut spike = meter(START + 20_000, vec![4000]);
+        spike.flags = vec![QualityFlag::Saturated(0)];
+        let samples = [
+            meter(START + 10_000, vec![10]),
+            spike,
+            meter(START + 30_000, vec![20]),
+        ];
+        let rollup = &rollup_meter(&samples, RollupInterval::OneMinute)[0];
+        assert_eq!(rollup.samples, 3);
+        assert_eq!(rollup.flagged, 1);
+        assert_eq!(rollup.last_timestamp, START + 30_000);
+        assert_eq!(rollup.values[0].max, 20);
+        assert_eq!(rollup.values[0].mean, 15.0);
+        assert_eq!(rollup.values[0].count, 2);
+
+        let mut bridge = BridgeSample::new_empty();
+        bridge.hardware_id = DEVICE;
+        bridge.timestamp = START;
+        bridge.values = vec![5];
+        let mut flagged = bridge.clone();
+        flagged.timestamp = START + 1_000;
+        flagged.values = vec![0xFFFF];
+        flagged.flags = vec![QualityFlag::OutOfRange(0)];
+        let rollup = &rollup_bridge(&[bridge, flagged], RollupInterval::OneMinute)[0];
+        assert_eq!(rollup.flagged, 1);
+        assert_eq!(rollup.values[0].max, 5);
+        assert_eq!(rollup.values[0].count, 1);
+    }
+
+    #[test]
+    fn keeps_bridge_functions_apart() {
+        let mut holding = BridgeSample::new_empty();
+        holding.hardware_id = DEVICE;
+        holding.timestamp = START;
+        holding.function = FunctionTypes::ReadHoldingRegisters;
+        holding.values = vec![1, 2];
+        let mut input = holding.clone();
+        input.function = FunctionTypes::ReadInputRegisters;
+        let mut write = holding.clone();
+        write.write = true;
+
+        let rollups = rollup_bridge(&[holding, input, write], RollupInterval::OneMinute);
+        assert_eq!(rollups.len(), 2);
+        assert_eq!(rollups[0].function, FunctionTypes::ReadHoldingRegisters);
+        assert_eq!(rollups[1].function, FunctionTypes::ReadInputRegisters);
+        assert_eq!(rollups[0].samples, 1);
+    }
+
+    #[test]
+    fn sums_pulse_deltas() {
+        let mut tracker = PulseTracker::new(Vec::new());
+        let mut samples: Vec<PulseSample> =
+            [(START, 100), (START + 20_000, 110), (START + 50_000, 125)]
+                .iter()
+                .map(|(timestamp, first)| {
+                    let mut sample = PulseSample::new_empty();
+                    sample.hardware_id = DEVICE;
+                    sample.timestamp = *timestamp;
+                    sample.pulses[0] = *first;
+                    sample
+                })
+                .collect();
+        tracker.track(&mut samples);
+        let rollups = rollup_pulse(&samples, RollupInterval::OneMinute);
+        assert_eq!(rollups.len(), 1);
+        assert_eq!(rollups[0].deltas[0], 25);
+        assert_eq!(rollups[0].consumption[0], None);
+        assert_eq!(rollups[0].first_timestamp, START);
+        assert_eq!(rollups[0].last_timestamp, START + 50_000);
+
+        samples[2].flags = vec![QualityFlag::ImpossibleDelta(0)];
+        let rollups = rollup_pulse(&samples, RollupInterval::OneMinute);
+        assert_eq!(rollups[0].deltas[0], 10);
+        assert_eq!(rollups[0].flagged, 1);
+    }
+}
@@ -1,6 +1,9 @@
 use crate::modbus;
 use crate::modbus::*;
 use crate::packet::*;
+use crate::pulse::PulseCount;
+use crate::register_map::NamedValue;
+use crate::validation::QualityFlag;
 use std::collections::hash_map::DefaultHasher;
 use std::hash::{Hash, Hasher};
 use std::time::{Duration, SystemTime, UNIX_EPOCH};
@@ -11,9 +14,29 @@ pub enum MeterDataTypes {
     Volatage,
     Current,
     PowerFactor,
+    Energy,
     None,
 }
 
+// Meters answer one command byte per quantity. There is no written protocol
+// for the meter firmware: the power command, its packing and the 20 W scale
+// are what the collector has always decoded from deployed meters. The other
+// commands and their layouts below are unverified, they match the simulator
+// only and have to be checked against a real meter before the values are
+// trusted.
+pub const POWER_COMMAND: u8 = b'w';
+pub const VOLTAGE_COMMAND: u8 = b'v';
+pub const CURRENT_COMMAND: u8 = b'c';
+pub const POWER_FACTOR_COMMAND: u8 = b'f';
+pub const ENERGY_COMMAND: u8 = b'e';
+// Bridges answer this with their six pulse counters, anything else is Modbus.
+// It is the first reserved Modbus address (248-255) so no slave can collide
+// with it in the first byte of a request.
+pub const PULSE_COMMAND: u8 = 0xF8;
+
+pub const METER_CHANNELS: usize = 24;
+pub const METER_PHASES: usize = 3;
+
 impl MeterDataTypes {
     pub fn new(input: u8) -> Self {
         match input {
@@ -21,42 +44,254 @@ impl MeterDataTypes {
             1 => MeterDataTypes::Volatage,
             2 => MeterDataTypes::Current,
             3 => MeterDataTypes::PowerFactor,
+            4 => MeterDataTypes::Energy,
+            _ => MeterDataTypes::None,
+        }
+    }
+
+    pub fn from_command(command: u8) -> Self {
+        match command {
+            POWER_COMMAND => MeterDataTypes::Power,
+            VOLTAGE_COMMAND => MeterDataTypes::Volatage,
+            CURRENT_COMMAND => MeterDataTypes::Current,
+            POWER_FACTOR_COMMAND => MeterDataTypes::PowerFactor,
+            ENERGY_COMMAND => MeterDataTypes::Energy,
             _ => MeterDataTypes::None,
         }
     }
+
+    pub fn command(&self) -> Option<u8> {
+        match self {
+            MeterDataTypes::Power => Some(POWER_COMMAND),
+            MeterDataTypes::Volatage => Some(VOLTAGE_COMMAND),
+            MeterDataTypes::Current => Some(CURRENT_COMMAND),
+            MeterDataTypes::PowerFactor => Some(POWER_FACTOR_COMMAND),
+            MeterDataTypes::Energy => Some(ENERGY_COMMAND),
+            MeterDataTypes::None => None,
+        }
+    }
+
+    // Unit of the stored values, after the meter's own scaling is applied
+    pub fn unit(&self) -> &'static str {
+        match self {
+            MeterDataTypes::Power => "W",
+            MeterDataTypes::Volatage => "mV",
+            MeterDataTypes::Current => "mA",
+            MeterDataTypes::PowerFactor => "\u{2030}",
+            MeterDataTypes::Energy => "Wh",
+            MeterDataTypes::None => "",
+        }
+    }
+
+    // Response layouts, all big-endian. Only power is confirmed, see the
+    // command constants:
+    //   power         24 channels of 12 bits packed two to three bytes, 20 W each
+    //   current       same packing as power, 10 mA each (unverified)
+    //   voltage       one u16 per phase, 0.1 V each (unverified)
+    //   power factor  one byte per channel, hundredths (unverified)
+    //   energy        one u32 accumulator per channel, Wh (unverified)
+    pub fn decode(&self, data: &[u8]) -> Result<Vec<u32>, SampleError> {
+        let values = match self {
+            MeterDataTypes::Power => unpack_12bit(data, METER_CHANNELS)?
+                .into_iter()
+                .map(|x| x * 20)
+                .collect(),
+            MeterDataTypes::Current => unpack_12bit(data, METER_CHANNELS)?
+                .into_iter()
+                .map(|x| x * 10)
+                .collect(),
+            MeterDataTypes::Volatage => data
+                .get(..METER_PHASES * 2)
+                .ok_or(SampleError::Truncated)?
+                .chunks(2)
+                .map(|x| u16::from_be_bytes([x[0], x[1]]) as u32 * 100)
+                .collect(),
+            MeterDataTypes::PowerFactor => data
+                .get(..METER_CHANNELS)
+                .ok_or(SampleError::Truncated)?
+                .iter()
+                .map(|x| *x as u32 * 10)
+                .collect(),
+            MeterDataTypes::Energy => data
+                .get(..METER_CHANNELS * 4)
+                .ok_or(SampleError::Truncated)?
+                .chunks(4)
+                .map(|x| u32::from_be_bytes([x[0], x[1], x[2], x[3]]))
+                .collect(),
+            MeterDataTypes::None => Vec::new(),
+        };
+        Ok(values)
+    }
+}
+
+fn unpack_12bit(data: &[u8], channels: usize) -> Result<Vec<u32>, SampleError> {
+    let packed = data.get(..channels / 2 * 3).ok_or(SampleError::Truncated)?;
+    let mut values = Vec::new();
+    for bytes in packed.chunks(3) {
+        values.push(((bytes[0] as u32) << 4) | ((bytes[1] as u32) >> 4));
+        values.push((((bytes[1] as u32) & 0x0F) << 8) | bytes[2] as u32);
+    }
+    Ok(values)
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum SampleError {
+    Modbus(ModbusError),
+    UnknownCommand(u8),
+    Truncated,
+    UnknownVersion(u8),
+    WrongSchema { expected: u8, found: u8 },
+}
+
+impl std::fmt::Display for SampleError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            SampleError::Modbus(e) => write!(f, "{}", e),
+            SampleError::UnknownCommand(c) => write!(f, "unknown meter command {:#04X}", c),
+            SampleError::Truncated => write!(f, "truncated sample data"),
+            SampleError::UnknownVersion(v) => write!(f, "unknown record version {}", v),
+            SampleError::WrongSchema { expected, found } => {
+                write!(f, "expected schema tag {}, found {}", expected, found)
+            }
+        }
+    }
+}
+
+impl From<ModbusError> for SampleError {
+    fn from(e: ModbusError) -> Self {
+        SampleError::Modbus(e)
+    }
 }
 
 // All Sample Types need to implement this trait
-pub trait DeviceSample {
+pub trait DeviceSample: Sized {
     fn to_ivec(&self) -> (sled::IVec, sled::IVec);
     fn new_empty() -> Self;
-    fn from_ivec(ivec: sled::IVec) -> Self;
-    fn new(sent: Packet, received: Packet) -> Self;
+    fn from_ivec(ivec: sled::IVec) -> Result<Self, SampleError>;
+    fn new(sent: Packet, received: Packet) -> Result<Self, SampleError>;
 }
 
 #[derive(Clone, Hash, Serialize, Deserialize, PartialEq, Debug)]
 pub struct MeterSample {
     pub timestamp: u128,
+    #[serde(default)]
+    pub times: SampleTimes,
     pub hardware_id: [u8; 8],
     pub data_type: MeterDataTypes,
-    pub values: Vec<u16>,
+    pub values: Vec<u32>,
+    // one entry per value once a channel config is applied, empty before that
+    #[serde(default)]
+    pub channels: Vec<ChannelInfo>,
+    // set by validation before upload, never stored
+    #[serde(default)]
+    pub flags: Vec<QualityFlag>,
+}
+
+#[derive(Clone, Copy, Hash, Serialize, Deserialize, PartialEq, Debug)]
+pub enum Phase {
+    L1,
+    L2,
+    L3,
+    Unknown,
+}
+
+// How one meter channel is wired, set per device
+#[derive(Clone, Serialize, Deserialize, PartialEq, Debug)]
+pub struct ChannelConfig {
+    pub label: String,
+    // multiplier for CTs rated differently from the meter's default
+    pub ct_ratio: f64,
+    pub phase: Phase,
+    pub enabled: bool,
+}
+
+// What a sample carries about the channel behind each value
+#[derive(Clone, Hash, Serialize, Deserialize, PartialEq, Debug)]
+pub struct ChannelInfo {
+    pub index: u8,
+    pub label: String,
+    pub phase: Phase,
+}
+
+impl Phase {
+    pub fn new(input: u8) -> Self {
+        match input {
+            1 => Phase::L1,
+            2 => Phase::L2,
+            3 => Phase::L3,
+            _ => Phase::Unknown,
+        }
+    }
+
+    pub fn as_byte(&self) -> u8 {
+        match self {
+            Phase::L1 => 1,
+            Phase::L2 => 2,
+            Phase::L3 => 3,
+            Phase::Unknown => 0,
+        }
+    }
+}
+
+impl ChannelConfig {
+    pub fn new(label: &str) -> Self {
+        ChannelConfig {
+            label: label.to_string(),
+            ct_ratio: 1.0,
+            phase: Phase::Unknown,
+            enabled: true,
+        }
+    }
+
+    // What the decoder assumed before channels were configurable
+    pub fn defaults() -> Vec<Self> {
+        (0..METER_CHANNELS)
+            .map(|_i| ChannelConfig::new(""))
+            .collect()
+    }
 }
 
 #[derive(Clone, Hash, Serialize, Deserialize, PartialEq, Debug)]
 pub struct BridgeSample {
     pub timestamp: u128,
+    #[serde(default)]
+    pub times: SampleTimes,
     pub hardware_id: [u8; 8],
+    // Modbus slave behind the bridge that answered
+    pub slave: u8,
     pub write: bool,
     pub start_address: u16,
     pub datatype: modbus::DataTypes,
+    // FC03 and FC04 both read registers, only the function tells them apart.
+    // None on legacy records that can't say.
+    #[serde(default = "unknown_function")]
+    pub function: FunctionTypes,
     pub values: Vec<u16>,
+    pub exception: Option<ModbusException>,
+    // filled in from a register map before upload, never stored
+    #[serde(default)]
+    pub named_values: Vec<NamedValue>,
+    // filled in by decode_values before upload, never stored
+    #[serde(default)]
+    pub typed_values: Vec<RegisterValue>,
+    // set by validation before upload, never stored
+    #[serde(default)]
+    pub flags: Vec<QualityFlag>,
 }
 
 #[derive(Clone, Hash, Serialize, Deserialize, PartialEq, Debug)]
 pub struct PulseSample {
     pub timestamp: u128,
+    #[serde(default)]
+    pub times: SampleTimes,
     pub hardware_id: [u8; 8],
     pub pulses: [u16; 6],
+    // filled in by the pulse tracker before upload, never stored
+    #[serde(default)]
+    pub counts: Vec<PulseCount>,
+    // set by validation before upload, never stored
+    #[serde(default)]
+    pub flags: Vec<QualityFlag>,
 }
 
 #[derive(Clone, Hash, Serialize, Deserialize, PartialEq, Debug)]
@@ -67,6 +302,16 @@ pub enum SampleTypes {
     None,
 }
 
+// When the request went out and the response came in on the gateway clock, and
+// the time the device reported if it has a clock of its own. timestamp on each
+// sample is the best of these, see SampleTimes::best.
+#[derive(Clone, Copy, Hash, Serialize, Deserialize, PartialEq, Debug, Default)]
+pub struct SampleTimes {
+    pub sent: Option<u128>,
+    pub received: u128,
+    pub device: Option<u128>,
+}
+
 #[derive(Clone, Hash, Serialize, Deserialize, PartialEq, Debug)]
 pub enum Sample {
     Meter(MeterSample),
@@ -75,6 +320,10 @@ pub enum Sample {
     None,
 }
 
+fn unknown_function() -> FunctionTypes {
+    FunctionTypes::None
+}
+
 pub fn time_as_millis(now: SystemTime) -> u128 {
     now.duration_since(UNIX_EPOCH).unwrap().as_millis()
 }
@@ -86,98 +335,433 @@ pub fn time_from_millis(millis_since_epoch: u128) -> SystemTime {
     time
 }
 
+impl SampleTimes {
+    // Packets are stamped when they are written to and read from the radio,
+    // unstamped ones fall back to now
+    pub fn from_packets(sent: &Packet, received: &Packet) -> Self {
+        SampleTimes {
+            sent: match sent.timestamp {
+                0 => None,
+                t => Some(t),
+            },
+            received: match received.timestamp {
+                0 => time_as_millis(SystemTime::now()),
+                t => t,
+            },
+            device: None,
+        }
+    }
+
+    pub fn now() -> Self {
+        SampleTimes {
+            sent: None,
+            received: time_as_millis(SystemTime::now()),
+            device: None,
+        }
+    }
+
+    // Legacy records only kept one time, taken on decode
+    pub fn legacy(timestamp: u128) -> Self {
+        SampleTimes {
+            sent: None,
+            received: timestamp,
+            device: None,
+        }
+    }
+
+    pub fn best(&self) -> u128 {
+        self.device.unwrap_or(self.received)
+    }
+
+    // Round trip between sending the request and getting the response
+    pub fn latency(&self) -> Option<u128> {
+        self.sent.map(|x| self.received.saturating_sub(x))
+    }
+
+    fn to_bytes(self, vec: &mut Vec<u8>) {
+        push_optional_time(vec, self.sent);
+        vec.extend_from_slice(&self.received.to_be_bytes());
+        push_optional_time(vec, self.device);
+    }
+}
+
+impl Sample {
+    pub fn sample_type(&self) -> SampleTypes {
+        match self {
+            Sample::Meter(_s) => SampleTypes::Meter,
+            Sample::Bridge(_s) => SampleTypes::Bridge,
+            Sample::Pulse(_s) => SampleTypes::Pulse,
+            Sample::None => SampleTypes::None,
+        }
+    }
+
+    pub fn timestamp(&self) -> u128 {
+        match self {
+            Sample::Meter(s) => s.timestamp,
+            Sample::Bridge(s) => s.timestamp,
+            Sample::Pulse(s) => s.timestamp,
+            Sample::None => 0,
+        }
+    }
+
+    pub fn hardware_id(&self) -> Option<[u8; 8]> {
+        match self {
+            Sample::Meter(s) => Some(s.hardware_id),
+            Sample::Bridge(s) => Some(s.hardware_id),
+            Sample::Pulse(s) => Some(s.hardware_id),
+            Sample::None => None,
+        }
+    }
+}
+
+fn push_optional_time(vec: &mut Vec<u8>, time: Option<u128>) {
+    match time {
+        Some(t) => {
+            vec.push(1);
+            vec.extend_from_slice(&t.to_be_bytes());
+        }
+        None => vec.push(0),
+    }
+}
+
 fn calculate_hash<T: Hash>(t: &T) -> u64 {
     let mut s = DefaultHasher::new();
     t.hash(&mut s);
     s.finish()
 }
 
+// Stored samples start with RECORD_MAGIC, the format version and a schema tag
+// for the sample type, followed by the fields in big-endian order. Records
+// without the magic were written by older builds with a native-endian timestamp.
+pub const RECORD_MAGIC: [u8; 3] = [0xD5, 0x53, 0x44];
+// A layout change never goes in without a new version, and every older
+// version keeps its reader.
+pub const RECORD_VERSION: u8 = 1;
+pub const LEGACY_VERSION: u8 = 0;
+const RECORD_HEADER_LENGTH: usize = 5;
+// the big-endian timestamp's bytes above MAX_PLAUSIBLE_MILLIS
+const TIMESTAMP_HIGH_BYTES: usize = 10;
+// Anything past this many millis was written with the other byte order
+const MAX_PLAUSIBLE_MILLIS: u128 = 1 << 48;
+
+impl SampleTypes {
+    pub fn schema_tag(&self) -> u8 {
+        match self {
+            SampleTypes::Meter => 1,
+            SampleTypes::Bridge => 2,
+            SampleTypes::Pulse => 3,
+            SampleTypes::None => 0,
+        }
+    }
+}
+
+pub fn record_header(sample_type: SampleTypes) -> Vec<u8> {
+    let mut header = RECORD_MAGIC.to_vec();
+    header.push(RECORD_VERSION);
+    header.push(sample_type.schema_tag());
+    header
+}
+
+// A little-endian legacy timestamp can start with the magic bytes, but its
+// sixth byte holds bits 40-47 and is non-zero for anything written after 2004.
+// The same byte is the top of a versioned record's big-endian timestamp, so
+// the high timestamp bytes being zero tells the two apart.
+pub fn is_versioned(record: &[u8]) -> bool {
+    record.len() >= RECORD_HEADER_LENGTH + TIMESTAMP_HIGH_BYTES
+        && record[..3] == RECORD_MAGIC
+        && record[RECORD_HEADER_LENGTH..RECORD_HEADER_LENGTH + TIMESTAMP_HIGH_BYTES]
+            .iter()
+            .all(|x| *x == 0)
+}
+
+pub fn record_version(record: &[u8]) -> u8 {
+    match is_versioned(record) {
+        true => record[3],
+        false => LEGACY_VERSION,
+    }
+}
+
+// Every format puts the timestamp first, so it can be read without knowing the type
+pub fn record_timestamp(record: &[u8]) -> Option<u128> {
+    let mut r = RecordReader::new(record);
+    let version = record_version(record);
+    if version != LEGACY_VERSION {
+        r.take(RECORD_HEADER_LENGTH).ok()?;
+    }
+    r.timestamp(version).ok()
+}
+
+fn record_ivecs(vec: Vec<u8>) -> (sled::IVec, sled::IVec) {
+    let key = calculate_hash(&vec).to_be_bytes();
+    (sled::IVec::from(vec), sled::IVec::from(&key))
+}
+
+struct RecordReader<'a> {
+    data: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> RecordReader<'a> {
+    fn new(data: &'a [u8]) -> Self {
+        RecordReader { data, pos: 0 }
+    }
+
+    fn remaining(&self) -> usize {
+        self.data.len() - self.pos
+    }
+
+    fn take(&mut self, n: usize) -> Result<&'a [u8], SampleError> {
+        if self.remaining() < n {
+            return Err(SampleError::Truncated);
+        }
+        let bytes = &self.data[self.pos..self.pos + n];
+        self.pos += n;
+        Ok(bytes)
+    }
+
+    fn u8(&mut self) -> Result<u8, SampleError> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn u16(&mut self) -> Result<u16, SampleError> {
+        Ok(u16::from_be_bytes([self.u8()?, self.u8()?]))
+    }
+
+    // Returns the format version, LEGACY_VERSION for records without a header
+    fn header(&mut self, sample_type: SampleTypes) -> Result<u8, SampleError> {
+        if !is_versioned(self.data) {
+            return Ok(LEGACY_VERSION);
+        }
+        self.take(3)?;
+        let version = self.u8()?;
+        let tag = self.u8()?;
+        if version == LEGACY_VERSION || version > RECORD_VERSION {
+            return Err(SampleError::UnknownVersion(version));
+        }
+        if tag != sample_type.schema_tag() {
+            return Err(SampleError::WrongSchema {
+                expected: sample_type.schema_tag(),
+                found: tag,
+            });
+        }
+        Ok(version)
+    }
+
+    fn timestamp(&mut self, version: u8) -> Result<u128, SampleError> {
+        let mut bytes = [0u8; 16];
+        bytes.copy_from_slice(self.take(16)?);
+        let timestamp = match version {
+            LEGACY_VERSION => match u128::from_ne_bytes(bytes) {
+                t if t < MAX_PLAUSIBLE_MILLIS => t,
+                t => t.swap_bytes(),
+            },
+            _ => u128::from_be_bytes(bytes),
+        };
+        Ok(timestamp)
+    }
+
+    fn optional_time(&mut self) -> Result<Option<u128>, SampleError> {
+        match self.u8()? {
+            0 => Ok(None),
+            _ => Ok(Some(self.timestamp(RECORD_VERSION)?)),
+        }
+    }
+
+    // Sample times follow the hardware id in versioned records
+    fn times(&mut self, version: u8, timestamp: u128) -> Result<SampleTimes, SampleError> {
+        if version == LEGACY_VERSION {
+            return Ok(SampleTimes::legacy(timestamp));
+        }
+        Ok(SampleTimes {
+            sent: self.optional_time()?,
+            received: self.timestamp(version)?,
+            device: self.optional_time()?,
+        })
+    }
+
+    fn hardware_id(&mut self) -> Result<[u8; 8], SampleError> {
+        let mut id = [0u8; 8];
+        id.copy_from_slice(self.take(8)?);
+        Ok(id)
+    }
+
+    // Values are counted in the record, but the count can't promise more than is there
+    fn u16_values(&mut self, count: usize) -> Result<Vec<u16>, SampleError> {
+        if self.remaining() < count * 2 {
+            return Err(SampleError::Truncated);
+        }
+        let mut values = Vec::new();
+        for _i in 0..count {
+            values.push(self.u16()?);
+        }
+        Ok(values)
+    }
+
+    fn u32_values(&mut self, count: usize) -> Result<Vec<u32>, SampleError> {
+        if self.remaining() < count * 4 {
+            return Err(SampleError::Truncated);
+        }
+        let mut values = Vec::new();
+        for _i in 0..count {
+            let bytes = self.take(4)?;
+            values.push(u32::from_be_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]));
+        }
+        Ok(values)
+    }
+}
+
+// Rewrites every legacy or older version record in the tree in the current
+// format. The key is a hash of the record, so each one moves to a new key.
+// Returns how many moved, records that don't decode are left for SampleStore
+// to quarantine.
+pub fn migrate_legacy<T: DeviceSample>(tree: &sled::Tree) -> sled::Result<usize> {
+    let mut migrated = 0;
+    for entry in tree.iter() {
+        let (key, value) = entry?;
+        if record_version(value.as_ref()) == RECORD_VERSION {
+            continue;
+        }
+        let (new_value, new_key) = match T::from_ivec(value) {
+            Ok(sample) => sample.to_ivec(),
+            Err(e) => {
+                eprintln!("not migrating record {:X?}: {}", key.as_ref(), e);
+                continue;
+            }
+        };
+        tree.insert(new_key, new_value)?;
+        tree.remove(key)?;
+        migrated += 1;
+    }
+    Ok(migrated)
+}
+
+impl MeterSample {
+    // Drops channels the meter doesn't have or that are disabled, scales by the
+    // CT ratio and labels what is left. Voltages are per phase, not per channel,
+    // and are left alone.
+    pub fn apply_channels(&mut self, config: &[ChannelConfig]) {
+        let ct_scaled = match self.data_type {
+            MeterDataTypes::Power | MeterDataTypes::Current | MeterDataTypes::Energy => true,
+            MeterDataTypes::PowerFactor => false,
+            MeterDataTypes::Volatage | MeterDataTypes::None => return,
+        };
+        let mut values = Vec::new();
+        let mut channels = Vec::new();
+        for (i, (value, c)) in self.values.iter().zip(config.iter()).enumerate() {
+            if !c.enabled {
+                continue;
+            }
+            match ct_scaled {
+                true => values.push((*value as f64 * c.ct_ratio).round() as u32),
+                false => values.push(*value),
+            }
+            channels.push(ChannelInfo {
+                index: i as u8,
+                label: c.label.clone(),
+                phase: c.phase,
+            });
+        }
+        self.values = values;
+        self.channels = channels;
+    }
+}
+
 impl DeviceSample for MeterSample {
     fn new_empty() -> Self {
         MeterSample {
             timestamp: time_as_millis(UNIX_EPOCH),
+            times: SampleTimes::default(),
             hardware_id: [0; 8],
             data_type: MeterDataTypes::None,
             values: Vec::new(),
+            channels: Vec::new(),
+            flags: Vec::new(),
         }
     }
 
-    fn new(sent: Packet, received: Packet) -> Self {
-        let _watt = vec!['w' as u8];
-        match sent.data {
-            _watt => {
-                let mut values: Vec<u16> = Vec::new();
-                for i in 0..12 {
-                    values.push(
-                        20 * (((received.data[i * 3] as u16) << 4)
-                            + ((received.data[i * 3 + 1] as u16) >> 4)),
-                    );
-                    values.push(
-                        20 * ((((received.data[i * 3 + 1] as u16) & 0x0F) << 8)
-                            + (received.data[i * 3 + 2] as u16)),
-                    );
-                }
-                let timestamp = SystemTime::now();
-                let hardware_id = received.address;
-
-                MeterSample {
-                    timestamp: time_as_millis(timestamp),
-                    hardware_id: hardware_id,
-                    data_type: MeterDataTypes::Power,
-                    values: values,
-                }
-            }
+    fn new(sent: Packet, received: Packet) -> Result<Self, SampleError> {
+        let command = *sent.data.first().ok_or(SampleError::Truncated)?;
+        let data_type = MeterDataTypes::from_command(command);
+        if data_type == MeterDataTypes::None {
+            return Err(SampleError::UnknownCommand(command));
         }
+        let values = data_type.decode(&received.data)?;
+        let times = SampleTimes::from_packets(&sent, &received);
+
+        Ok(MeterSample {
+            timestamp: times.best(),
+            times,
+            hardware_id: received.address,
+            data_type,
+            values,
+            channels: Vec::new(),
+            flags: Vec::new(),
+        })
     }
 
     fn to_ivec(&self) -> (sled::IVec, sled::IVec) {
-        let mut vec: Vec<u8> = Vec::new();
-        let timestamp = self.timestamp;
-        vec.extend_from_slice(&timestamp.to_ne_bytes());
+        let mut vec = record_header(SampleTypes::Meter);
+        vec.extend_from_slice(&self.timestamp.to_be_bytes());
         vec.extend_from_slice(&self.hardware_id);
+        self.times.to_bytes(&mut vec);
         vec.push(self.data_type.clone() as u8);
-        let mut u8vec: Vec<u8> = Vec::new();
-        for d in self.values.clone() {
-            u8vec.push((d >> 8) as u8);
-            u8vec.push((d & 0x0FF) as u8);
+        vec.extend_from_slice(&(self.values.len() as u16).to_be_bytes());
+        for v in self.values.iter() {
+            vec.extend_from_slice(&v.to_be_bytes());
         }
-        vec.extend_from_slice(u8vec.as_slice());
-
-        (
-            sled::IVec::from(vec.clone()),
-            sled::IVec::from(&calculate_hash(&vec).to_ne_bytes()),
-        )
-    }
-
-    fn from_ivec(ivec: sled::IVec) -> Self {
-        let mut as_vec = Vec::new();
-        as_vec.extend_from_slice(ivec.as_ref());
-        let mut time_slice: [u8; 16] = [0; 16];
-        for i in 0..16 {
-            time_slice[i] = as_vec.remove(0);
+        vec.push(self.channels.len() as u8);
+        for c in self.channels.iter() {
+            let label = c.label.as_bytes();
+            let label = &label[..label.len().min(u8::MAX as usize)];
+            vec.push(c.index);
+            vec.push(c.phase.as_byte());
+            vec.push(label.len() as u8);
+            vec.extend_from_slice(label);
         }
-        let time_as_millis = u128::from_ne_bytes(time_slice);
-
-        let mut hardware_id: [u8; 8] = [0; 8];
-        for i in 0..8 {
-            hardware_id[i] = as_vec.remove(0);
-        }
-
-        let data_type = MeterDataTypes::new(as_vec.remove(0));
-        let mut values: Vec<u16> = Vec::new();
+        record_ivecs(vec)
+    }
 
-        for _i in 0..24 {
-            let mut val: u16 = (as_vec.remove(0) as u16) << 8;
-            val |= as_vec.remove(0) as u16;
-            values.push(val);
+    fn from_ivec(ivec: sled::IVec) -> Result<Self, SampleError> {
+        let mut r = RecordReader::new(ivec.as_ref());
+        let version = r.header(SampleTypes::Meter)?;
+        let timestamp = r.timestamp(version)?;
+        let hardware_id = r.hardware_id()?;
+        let times = r.times(version, timestamp)?;
+        let data_type = MeterDataTypes::new(r.u8()?);
+        let values = match version {
+            LEGACY_VERSION => {
+                let count = r.remaining() / 2;
+                r.u16_values(count)?.into_iter().map(u32::from).collect()
+            }
+            _ => {
+                let count = r.u16()? as usize;
+                r.u32_values(count)?
+            }
+        };
+        let mut channels = Vec::new();
+        if version != LEGACY_VERSION {
+            for _i in 0..r.u8()? {
+                let index = r.u8()?;
+                let phase = Phase::new(r.u8()?);
+                let length = r.u8()? as usize;
+                let label = String::from_utf8_lossy(r.take(length)?).to_string();
+                channels.push(ChannelInfo {
+                    index,
+                    label,
+                    phase,
+                });
+            }
         }
 
-        MeterSample {
-            timestamp: time_as_millis,
-            hardware_id: hardware_id,
-            data_type: data_type,
-            values: values,
-        }
+        Ok(MeterSample {
+            timestamp,
+            times,
+            hardware_id,
+            data_type,
+            values,
+            channels,
+            flags: Vec::new(),
+        })
     }
 }
 
@@ -185,136 +769,142 @@ impl DeviceSample for PulseSample {
     fn new_empty() -> Self {
         PulseSample {
             timestamp: time_as_millis(UNIX_EPOCH),
+            times: SampleTimes::default(),
             hardware_id: [0; 8],
             pulses: [0; 6],
+            counts: Vec::new(),
+            flags: Vec::new(),
         }
     }
 
-    fn new(_sent: Packet, received: Packet) -> Self {
-        let timestamp = SystemTime::now();
+    fn new(sent: Packet, received: Packet) -> Result<Self, SampleError> {
+        let times = SampleTimes::from_packets(&sent, &received);
         let hardware_id = received.address;
         let mut ret = PulseSample {
-            timestamp: time_as_millis(timestamp),
+            timestamp: times.best(),
+            times,
             hardware_id: hardware_id,
             pulses: [0; 6],
+            counts: Vec::new(),
+            flags: Vec::new(),
         };
-        for i in 0..ret.pulses.len() {
-            ret.pulses[i] = (received.data[2 * i] as u16) << 8 | received.data[2 * i + 1] as u16;
+        // one big-endian u16 counter per input
+        let data = received
+            .data
+            .get(..ret.pulses.len() * 2)
+            .ok_or(SampleError::Truncated)?;
+        for (i, bytes) in data.chunks(2).enumerate() {
+            ret.pulses[i] = u16::from_be_bytes([bytes[0], bytes[1]]);
         }
 
-        ret
+        Ok(ret)
     }
 
     fn to_ivec(&self) -> (sled::IVec, sled::IVec) {
-        let mut vec: Vec<u8> = Vec::new();
-        let timestamp = self.timestamp;
-        vec.extend_from_slice(&timestamp.to_ne_bytes());
+        let mut vec = record_header(SampleTypes::Pulse);
+        vec.extend_from_slice(&self.timestamp.to_be_bytes());
         vec.extend_from_slice(&self.hardware_id);
-        for i in 0..self.pulses.len() {
-            let val = self.pulses[i];
-            vec.push((val >> 8) as u8);
-            vec.push((val & 0x0FF) as u8);
+        self.times.to_bytes(&mut vec);
+        for p in self.pulses.iter() {
+            vec.extend_from_slice(&p.to_be_bytes());
         }
-
-        (
-            sled::IVec::from(vec.clone()),
-            sled::IVec::from(&calculate_hash(&vec).to_ne_bytes()),
-        )
+        record_ivecs(vec)
     }
 
-    fn from_ivec(ivec: sled::IVec) -> Self {
-        let mut as_vec = Vec::new();
-        as_vec.extend_from_slice(ivec.as_ref());
-        let mut time_slice: [u8; 16] = [0; 16];
-        for i in 0..16 {
-            time_slice[i] = as_vec.remove(0);
-        }
-        let time_as_millis = u128::from_ne_bytes(time_slice);
-
-        let mut hardware_id: [u8; 8] = [0; 8];
-        for i in 0..8 {
-            hardware_id[i] = as_vec.remove(0);
-        }
-
+    fn from_ivec(ivec: sled::IVec) -> Result<Self, SampleError> {
+        let mut r = RecordReader::new(ivec.as_ref());
+        let version = r.header(SampleTypes::Pulse)?;
+        let timestamp = r.timestamp(version)?;
+        let hardware_id = r.hardware_id()?;
         let mut ret = PulseSample {
-            timestamp: time_as_millis,
-            hardware_id: hardware_id,
+            timestamp,
+            times: r.times(version, timestamp)?,
+            hardware_id,
             pulses: [0; 6],
+            counts: Vec::new(),
+            flags: Vec::new(),
         };
-
         for i in 0..ret.pulses.len() {
-            ret.pulses[i] = (as_vec.remove(0) as u16) << 8;
-            ret.pulses[i] |= as_vec.remove(0) as u16;
+            ret.pulses[i] = r.u16()?;
         }
-        ret
+        Ok(ret)
     }
 }
 
-impl DeviceSample for BridgeSample {
-    fn new_empty() -> Self {
-        BridgeSample {
-            timestamp: time_as_millis(UNIX_EPOCH),
-            hardware_id: [0; 8],
-            write: false,
-            start_address: 0,
-            datatype: modbus::DataTypes::None,
-            values: Vec::new(),
-        }
-    }
-    fn new(sent: Packet, received: Packet) -> Self {
-        let received_modbus = ModbusMessage::received_from_data(received.data);
-        let sent_modbus = ModbusMessage::sent_from_data(sent.data);
-
-        let timestamp = SystemTime::now();
-        let hardware_id = received.address;
+impl BridgeSample {
+    // Shared by the XBee bridge and the wired transports, which only differ in
+    // how the request and response reach us. A response or write request with
+    // fewer values than the request's quantity is an error.
+    pub fn from_modbus(
+        hardware_id: [u8; 8],
+        sent_modbus: &ModbusMessage,
+        received_modbus: &ModbusMessage,
+    ) -> Result<Self, ModbusError> {
+        let times = SampleTimes::now();
 
         let mut data_points: Vec<u16> = Vec::new();
 
         match sent_modbus.function {
+            _ if received_modbus.exception.is_some() => {
+                eprintln!(
+                    "modbus exception {:?} from slave {} at {:X?}",
+                    received_modbus.exception, received_modbus.address, hardware_id
+                );
+            }
             FunctionTypes::ReadCoilStatus
             | FunctionTypes::ReadHoldingRegisters
             | FunctionTypes::ReadInputRegisters
             | FunctionTypes::ReadInputStatus => match received_modbus.data_type {
                 DataTypes::Coil | DataTypes::Input => {
-                    let mut byte_num = 0;
-                    for _i in 0..sent_modbus.num_data_points {
-                        let result = (received_modbus.data[byte_num]
-                            & (0x01 << (sent_modbus.num_data_points % 8)))
-                            >> sent_modbus.num_data_points % 8;
-                        data_points.push(result as u16);
-                        if sent_modbus.num_data_points % 8 == 7 {
-                            byte_num += 1;
-                        }
+                    let bits = unpack_bits(&received_modbus.data, sent_modbus.num_data_points);
+                    if bits.len() < sent_modbus.num_data_points as usize {
+                        return Err(ModbusError::Truncated);
+                    }
+                    for bit in bits {
+                        data_points.push(bit as u16);
                     }
                 }
                 DataTypes::Register => {
-                    for i in 0..sent_modbus.num_data_points {
-                        let mut result = (received_modbus.data[(i * 2) as usize] as u16) << 8;
-                        result |= (received_modbus.data[(i * 2 + 1) as usize]) as u16;
-                        data_points.push(result);
-                    }
+                    data_points = registers(&received_modbus.data, sent_modbus.num_data_points)?;
                 }
                 DataTypes::None => {}
             },
             FunctionTypes::WriteSingleCoil | FunctionTypes::WriteSingleRegister => {
+                match sent_modbus.data_type {
+                    DataTypes::Coil | DataTypes::Input => match sent_modbus.data.first() {
+                        Some(0xFF) => data_points.push(1),
+                        Some(_v) => data_points.push(0),
+                        None => return Err(ModbusError::Truncated),
+                    },
+                    DataTypes::Register => {
+                        data_points = registers(&sent_modbus.data, 1)?;
+                    }
+                    DataTypes::None => {}
+                }
+            }
+            FunctionTypes::WriteMultipleCoils | FunctionTypes::WriteMultipleRegisters => {
+                //the response only echoes the quantity, the values come from what we sent
+                if received_modbus.start_address != sent_modbus.start_address
+                    || received_modbus.num_data_points != sent_modbus.num_data_points
+                {
+                    eprintln!("multiple write response does not match request");
+                }
                 match sent_modbus.data_type {
                     DataTypes::Coil | DataTypes::Input => {
-                        if sent_modbus.data[0] == 0xFF {
-                            data_points.push(1);
-                        } else {
-                            data_points.push(0);
+                        let bits = unpack_bits(&sent_modbus.data, sent_modbus.num_data_points);
+                        if bits.len() < sent_modbus.num_data_points as usize {
+                            return Err(ModbusError::Truncated);
+                        }
+                        for bit in bits {
+                            data_points.push(bit as u16);
                         }
                     }
                     DataTypes::Register => {
-                        let result =
-                            ((sent_modbus.data[0] as u16) << 8) | (sent_modbus.data[1] as u16);
-                        data_points.push(result);
+                        data_points = registers(&sent_modbus.data, sent_modbus.num_data_points)?;
                     }
                     DataTypes::None => {}
                 }
             }
-            FunctionTypes::WriteMultipleCoils | FunctionTypes::WriteMultipleRegisters => { //writing multiple not currently supported
-            }
             _ => {}
         }
         let mut write = false;
@@ -329,69 +919,424 @@ impl DeviceSample for BridgeSample {
             | FunctionTypes::WriteMultipleRegisters => write = true,
             _ => {}
         }
-        let data_type = sent_modbus.data_type;
+        let data_type = sent_modbus.data_type.clone();
 
-        BridgeSample {
-            timestamp: time_as_millis(timestamp),
+        Ok(BridgeSample {
+            timestamp: times.best(),
+            times,
             hardware_id: hardware_id,
+            slave: sent_modbus.address,
             write: write,
             start_address: sent_modbus.start_address,
             datatype: data_type,
+            function: sent_modbus.function.clone(),
             values: data_points,
+            exception: received_modbus.exception,
+            named_values: Vec::new(),
+            typed_values: Vec::new(),
+            flags: Vec::new(),
+        })
+    }
+}
+
+// Big-endian registers, count of them or Truncated if the data is shorter
+fn registers(data: &[u8], count: u16) -> Result<Vec<u16>, ModbusError> {
+    let bytes = data
+        .get(..count as usize * 2)
+        .ok_or(ModbusError::Truncated)?;
+    Ok(bytes
+        .chunks(2)
+        .map(|x| u16::from_be_bytes([x[0], x[1]]))
+        .collect())
+}
+
+// Bit reads only have one function each, register reads and writes could have
+// been either of two
+fn legacy_function(datatype: &DataTypes, write: bool) -> FunctionTypes {
+    match (datatype, write) {
+        (DataTypes::Coil, false) => FunctionTypes::ReadCoilStatus,
+        (DataTypes::Input, false) => FunctionTypes::ReadInputStatus,
+        _ => FunctionTypes::None,
+    }
+}
+
+impl BridgeSample {
+    // Reads the values back to back as one type, for slaves without a register
+    // map. A trailing partial value is left out.
+    pub fn decode_values(&mut self, value_type: ValueType, order: WordOrder) {
+        let count = value_type
+            .register_count()
+            .unwrap_or(self.values.len() as u16)
+            .max(1) as usize;
+        self.typed_values = self
+            .values
+            .chunks_exact(count)
+            .filter_map(|x| RegisterValue::decode(x, value_type, order))
+            .collect();
+    }
+}
+
+impl DeviceSample for BridgeSample {
+    fn new_empty() -> Self {
+        BridgeSample {
+            timestamp: time_as_millis(UNIX_EPOCH),
+            times: SampleTimes::default(),
+            hardware_id: [0; 8],
+            slave: 0,
+            write: false,
+            start_address: 0,
+            datatype: modbus::DataTypes::None,
+            function: FunctionTypes::None,
+            values: Vec::new(),
+            exception: None,
+            named_values: Vec::new(),
+            typed_values: Vec::new(),
+            flags: Vec::new(),
         }
     }
+    fn new(sent: Packet, received: Packet) -> Result<Self, SampleError> {
+        let times = SampleTimes::from_packets(&sent, &received);
+        let received_modbus = ModbusMessage::received_from_data(received.data)?;
+        let sent_modbus = ModbusMessage::sent_from_data(sent.data)?;
+
+        let mut sample =
+            BridgeSample::from_modbus(received.address, &sent_modbus, &received_modbus)?;
+        sample.times = times;
+        sample.timestamp = times.best();
+        Ok(sample)
+    }
 
     fn to_ivec(&self) -> (sled::IVec, sled::IVec) {
-        let mut vec: Vec<u8> = Vec::new();
-        let timestamp = self.timestamp;
-        vec.extend_from_slice(&timestamp.to_ne_bytes());
+        let mut vec = record_header(SampleTypes::Bridge);
+        vec.extend_from_slice(&self.timestamp.to_be_bytes());
         vec.extend_from_slice(&self.hardware_id);
+        self.times.to_bytes(&mut vec);
+        vec.push(self.slave);
         vec.push(self.write as u8);
-        vec.push((self.start_address >> 8) as u8);
-        vec.push((self.start_address & 0x0FF) as u8);
+        vec.extend_from_slice(&self.start_address.to_be_bytes());
         vec.push(self.datatype.clone() as u8);
-        for v in self.values.clone() {
-            vec.push((v >> 8) as u8);
-            vec.push((v & 0x0FF) as u8);
+        vec.push(self.function.clone() as u8);
+        vec.push(self.exception.map_or(0, |x| x.code()));
+        vec.extend_from_slice(&(self.values.len() as u16).to_be_bytes());
+        for v in self.values.iter() {
+            vec.extend_from_slice(&v.to_be_bytes());
         }
-        (
-            sled::IVec::from(vec.clone()),
-            sled::IVec::from(&calculate_hash(&vec).to_ne_bytes()),
-        )
+        record_ivecs(vec)
     }
 
-    fn from_ivec(ivec: sled::IVec) -> Self {
-        let mut as_vec = Vec::new();
-        as_vec.extend_from_slice(ivec.as_ref());
-        let mut time_slice: [u8; 16] = [0; 16];
-        for i in 0..16 {
-            time_slice[i] = as_vec.remove(0);
-        }
-        let time_as_millis = u128::from_ne_bytes(time_slice);
-        let mut hardware_id: [u8; 8] = [0; 8];
-        for i in 0..8 {
-            hardware_id[i] = as_vec.remove(0);
-        }
+    fn from_ivec(ivec: sled::IVec) -> Result<Self, SampleError> {
+        let mut r = RecordReader::new(ivec.as_ref());
+        let version = r.header(SampleTypes::Bridge)?;
+        let timestamp = r.timestamp(version)?;
+        let hardware_id = r.hardware_id()?;
+        let times = r.times(version, timestamp)?;
+        // legacy records have no slave id, exception or value count, the values
+        // run to the end of the record
+        let slave = match version {
+            LEGACY_VERSION => 0,
+            _ => r.u8()?,
+        };
+        let write = r.u8()? != 0;
+        let start_address = r.u16()?;
+        let datatype = DataTypes::new(r.u8()?);
+        let function = match version {
+            LEGACY_VERSION => legacy_function(&datatype, write),
+            _ => FunctionTypes::new_function_type(r.u8()?),
+        };
+        let exception = match version {
+            LEGACY_VERSION => None,
+            _ => match r.u8()? {
+                0 => None,
+                c => Some(ModbusException::new(c)),
+            },
+        };
+        let count = match version {
+            LEGACY_VERSION if !r.remaining().is_multiple_of(2) => {
+                return Err(SampleError::Truncated)
+            }
+            LEGACY_VERSION => r.remaining() / 2,
+            _ => r.u16()? as usize,
+        };
+        let values = r.u16_values(count)?;
 
-        let write = as_vec.remove(0) != 0;
-        let mut start_address = (as_vec.remove(0) as u16) << 8;
-        start_address |= as_vec.remove(0) as u16;
-        let datatype = DataTypes::new(as_vec.remove(0));
+        Ok(BridgeSample {
+            timestamp,
+            times,
+            hardware_id,
+            slave,
+            write,
+            start_address,
+            datatype,
+            function,
+            values,
+            exception,
+            named_values: Vec::new(),
+            typed_values: Vec::new(),
+            flags: Vec::new(),
+        })
+    }
+}
 
-        let mut values = Vec::new();
-        while as_vec.is_empty() == false {
-            let mut val = (as_vec.remove(0) as u16) << 8;
-            val |= as_vec.remove(0) as u16;
-            values.push(val);
-        }
+#[cfg(test)]
+mod tests {
+    use super::*;
 
-        BridgeSample {
-            timestamp: time_as_millis,
-            hardware_id: hardware_id,
-            write: write,
-            start_address: start_address,
-            datatype: datatype,
-            values: values,
+    const DEVICE: [u8; 8] = [1, 2, 3, 4, 5, 6, 7, 8];
+    const TIMESTAMP: u128 = 1_600_000_000_000;
+
+    // Builds the records the baseline wrote: native-endian timestamp, hardware id,
+    // then the type's fields with no header
+    fn legacy_record(fields: &[u8]) -> sled::IVec {
+        let mut vec = TIMESTAMP.to_ne_bytes().to_vec();
+        vec.extend_from_slice(&DEVICE);
+        vec.extend_from_slice(fields);
+        sled::IVec::from(vec)
+    }
+
+    fn bridge_sample() -> BridgeSample {
+        let mut sample = BridgeSample::new_empty();
+        sample.timestamp = TIMESTAMP;
+        sample.times = SampleTimes {
+            sent: Some(TIMESTAMP - 40),
+            received: TIMESTAMP,
+            device: None,
+        };
+        sample.hardware_id = DEVICE;
+        sample.slave = 5;
+        sample.start_address = 0x0100;
+        sample.datatype = DataTypes::Register;
+        sample.function = FunctionTypes::ReadInputRegisters;
+        sample.values = vec![0xBEEF, 0x0001];
+        sample
+    }
+
+    #[test]
+    fn bridge_record_round_trip() {
+        let mut sample = bridge_sample();
+        let (value, _key) = sample.to_ivec();
+        assert_eq!(BridgeSample::from_ivec(value).unwrap(), sample);
+
+        sample.values = Vec::new();
+        sample.exception = Some(ModbusException::new(0x02));
+        let (value, _key) = sample.to_ivec();
+        assert_eq!(BridgeSample::from_ivec(value).unwrap(), sample);
+    }
+
+    #[test]
+    fn meter_and_pulse_record_round_trip() {
+        let mut meter = MeterSample::new_empty();
+        meter.timestamp = TIMESTAMP;
+        meter.times = SampleTimes::legacy(TIMESTAMP);
+        meter.hardware_id = DEVICE;
+        meter.data_type = MeterDataTypes::Power;
+        meter.values = vec![70_000, 20];
+        meter.channels = vec![ChannelInfo {
+            index: 3,
+            label: "pump".to_string(),
+            phase: Phase::L2,
+        }];
+        let (value, _key) = meter.to_ivec();
+        assert_eq!(MeterSample::from_ivec(value).unwrap(), meter);
+
+        let mut pulse = PulseSample::new_empty();
+        pulse.timestamp = TIMESTAMP;
+        pulse.times = SampleTimes::legacy(TIMESTAMP);
+        pulse.hardware_id = DEVICE;
+        pulse.pulses = [1, 2, 3, 4, 5, 0xFFFF];
+        let (value, _key) = pulse.to_ivec();
+        assert_eq!(PulseSample::from_ivec(value).unwrap(), pulse);
+    }
+
+    #[test]
+    fn decodes_legacy_bridge_records() {
+        // write, start address 0x0100, registers, then two values
+        let record = legacy_record(&[0, 0x01, 0x00, DataTypes::Register as u8, 0xBE, 0xEF, 0, 1]);
+        let mut expected = bridge_sample();
+        expected.times = SampleTimes::legacy(TIMESTAMP);
+        expected.slave = 0;
+        expected.function = FunctionTypes::None;
+        assert_eq!(BridgeSample::from_ivec(record).unwrap(), expected);
+
+        let record = legacy_record(&[1, 0x00, 0x07, DataTypes::Coil as u8, 0, 1]);
+        let sample = BridgeSample::from_ivec(record).unwrap();
+        assert!(sample.write);
+        assert_eq!(sample.start_address, 7);
+        assert_eq!(sample.values, vec![1]);
+        assert_eq!(sample.exception, None);
+        assert_eq!(sample.function, FunctionTypes::None);
+
+        let record = legacy_record(&[0, 0x00, 0x07, DataTypes::Input as u8, 0, 1]);
+        let sample = BridgeSample::from_ivec(record).unwrap();
+        assert_eq!(sample.function, FunctionTypes::ReadInputStatus);
+
+        let record = legacy_record(&[0, 0x01, 0x00, DataTypes::Register as u8, 0xBE]);
+        assert_eq!(BridgeSample::from_ivec(record), Err(SampleError::Truncated));
+    }
+
+    // Millis 0x0102_0144_53D5 in little-endian order start with RECORD_MAGIC
+    #[test]
+    fn legacy_timestamps_never_pass_for_the_magic() {
+        let timestamp: u128 = 0x0102_0144_53D5;
+        let mut record = timestamp.to_le_bytes().to_vec();
+        assert_eq!(record[..3], RECORD_MAGIC);
+        assert_eq!(record[3], RECORD_VERSION);
+        assert_eq!(record[4], SampleTypes::Bridge.schema_tag());
+        record.extend_from_slice(&DEVICE);
+        record.extend_from_slice(&[0, 0x00, 0x07, DataTypes::Coil as u8, 0, 1]);
+        assert!(!is_versioned(&record));
+        assert_eq!(record_version(&record), LEGACY_VERSION);
+        assert_eq!(record_timestamp(&record), Some(timestamp));
+
+        // the earliest little-endian millis with a zero sixth byte is 2^40, 2004
+        assert_ne!(TIMESTAMP.to_le_bytes()[5], 0);
+        let (value, _key) = bridge_sample().to_ivec();
+        assert!(is_versioned(value.as_ref()));
+    }
+
+    #[test]
+    fn decodes_legacy_meter_and_pulse_records() {
+        let mut fields = vec![MeterDataTypes::Power as u8];
+        for i in 0..METER_CHANNELS as u16 {
+            fields.extend_from_slice(&i.to_be_bytes());
         }
+        let meter = MeterSample::from_ivec(legacy_record(&fields)).unwrap();
+        assert_eq!(meter.timestamp, TIMESTAMP);
+        assert_eq!(meter.hardware_id, DEVICE);
+        assert_eq!(
+            meter.values,
+            (0..METER_CHANNELS as u32).collect::<Vec<u32>>()
+        );
+
+        let pulse =
+            PulseSample::from_ivec(legacy_record(&[0, 1, 0, 2, 0, 3, 0, 4, 0, 5, 1, 0])).unwrap();
+        assert_eq!(pulse.pulses, [1, 2, 3, 4, 5, 256]);
+        assert_eq!(pulse.times, SampleTimes::legacy(TIMESTAMP));
+    }
+
+    #[test]
+    fn migrates_legacy_records() {
+        let db = sled::Config::new().temporary(true).open().unwrap();
+        let tree = db.open_tree("bridge").unwrap();
+        let record = legacy_record(&[0, 0x01, 0x00, DataTypes::Register as u8, 0xBE, 0xEF, 0, 1]);
+        tree.insert(b"old", record.clone()).unwrap();
+        assert_eq!(migrate_legacy::<BridgeSample>(&tree).unwrap(), 1);
+        assert_eq!(migrate_legacy::<BridgeSample>(&tree).unwrap(), 0);
+
+        let (_key, value) = tree.iter().next().unwrap().unwrap();
+        assert_eq!(record_version(value.as_ref()), RECORD_VERSION);
+        assert_eq!(
+            BridgeSample::from_ivec(value).unwrap(),
+            BridgeSample::from_ivec(record).unwrap()
+        );
+    }
+
+    // Adds the CRC to a response and wraps it the way the bridge forwards it
+    fn bridge_response(frame: &[u8]) -> Packet {
+        let mut data = frame.to_vec();
+        let crc = crc_helper(data.clone());
+        data.push((crc & 0x0FF) as u8);
+        data.push((crc >> 8) as u8);
+        Packet::new_receive(&DEVICE, [0x12, 0x34], 0x01, &data)
+    }
+
+    #[test]
+    fn decodes_bridge_responses() {
+        let sent = ModbusMessage::new_read_message(5, 3, 0x0100, 2).as_bytes();
+        let sent = Packet::new_transmit(&DEVICE, &sent);
+        let received = bridge_response(&[5, 3, 4, 0xBE, 0xEF, 0, 1]);
+        let sample = BridgeSample::new(sent, received).unwrap();
+        assert_eq!(sample.slave, 5);
+        assert_eq!(sample.start_address, 0x0100);
+        assert_eq!(sample.function, FunctionTypes::ReadHoldingRegisters);
+        assert_eq!(sample.values, vec![0xBEEF, 1]);
+    }
+
+    #[test]
+    fn decodes_coil_counts_off_a_byte_boundary() {
+        let sent = ModbusMessage::new_read_message(5, 1, 0, 10).as_bytes();
+        let received = bridge_response(&[5, 1, 2, 0b0100_1101, 0b1111_1110]);
+        let sample = BridgeSample::new(Packet::new_transmit(&DEVICE, &sent), received).unwrap();
+        assert_eq!(sample.datatype, DataTypes::Coil);
+        assert_eq!(sample.values, vec![1, 0, 1, 1, 0, 0, 1, 0, 0, 1]);
+
+        let bits = [
+            true, false, false, true, true, false, true, true, false, true, true,
+        ];
+        let sent = ModbusMessage::new_write_multiple_coils(5, 0x20, &bits).as_bytes();
+        let received = bridge_response(&[5, 0x0F, 0x00, 0x20, 0x00, 0x0B]);
+        let sample = BridgeSample::new(Packet::new_transmit(&DEVICE, &sent), received).unwrap();
+        assert!(sample.write);
+        let expected: Vec<u16> = bits.iter().map(|x| *x as u16).collect();
+        assert_eq!(sample.values, expected);
+    }
+
+    #[test]
+    fn rejects_truncated_payloads() {
+        let pulses = Packet::new_receive(&DEVICE, [0x12, 0x34], 0x01, &[0; 11]);
+        assert_eq!(
+            PulseSample::new(Packet::new_transmit(&DEVICE, &[PULSE_COMMAND]), pulses),
+            Err(SampleError::Truncated)
+        );
+
+        // two registers asked for, one in the reply but with a good CRC
+        let sent = ModbusMessage::new_read_message(5, 3, 0x0100, 2).as_bytes();
+        let received = bridge_response(&[5, 3, 2, 0xBE, 0xEF]);
+        assert_eq!(
+            BridgeSample::new(Packet::new_transmit(&DEVICE, &sent), received),
+            Err(SampleError::Modbus(ModbusError::Truncated))
+        );
+
+        // ten coils asked for, one byte of them back
+        let sent = ModbusMessage::new_read_message(5, 1, 0, 10).as_bytes();
+        let received = bridge_response(&[5, 1, 1, 0xFF]);
+        assert_eq!(
+            BridgeSample::new(Packet::new_transmit(&DEVICE, &sent), received),
+            Err(SampleError::Modbus(ModbusError::Truncated))
+        );
+
+        let received = bridge_response(&[5, 3, 4, 0xBE, 0xEF, 0, 1]);
+        assert_eq!(
+            BridgeSample::new(Packet::new_transmit(&DEVICE, &[5, 3, 1]), received),
+            Err(SampleError::Modbus(ModbusError::Truncated))
+        );
+        assert_eq!(
+            ModbusMessage::sent_from_data(vec![5, 0x10, 0, 0, 0, 2, 4, 0, 1]).err(),
+            Some(ModbusError::Truncated)
+        );
+        // the byte count doesn't cover the quantity
+        assert_eq!(
+            ModbusMessage::sent_from_data(vec![5, 0x10, 0, 0, 0, 2, 2, 0, 1, 0, 0]).err(),
+            Some(ModbusError::Truncated)
+        );
+    }
+
+    #[test]
+    fn rejects_newer_and_mismatched_records() {
+        let (value, _key) = bridge_sample().to_ivec();
+        let mut newer = value.to_vec();
+        newer[3] = RECORD_VERSION + 1;
+        assert_eq!(
+            BridgeSample::from_ivec(sled::IVec::from(newer)),
+            Err(SampleError::UnknownVersion(RECORD_VERSION + 1))
+        );
+        assert!(matches!(
+            PulseSample::from_ivec(value),
+            Err(SampleError::WrongSchema { .. })
+        ));
+    }
+
+    #[test]
+    fn decodes_values_into_the_sample() {
+        let mut sample = bridge_sample();
+        sample.values = vec![0x3344, 0x1122, 0x5566];
+        sample.decode_values(ValueType::U32, WordOrder::WordSwapped);
+        assert_eq!(sample.typed_values, vec![RegisterValue::U32(0x11223344)]);
+
+        // decoded values never reach the record
+        let (value, _key) = sample.to_ivec();
+        assert!(BridgeSample::from_ivec(value)
+            .unwrap()
+            .typed_values
+            .is_empty());
     }
 }
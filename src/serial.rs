@@ -0,0 +1,251 @@
+use crate::packet::*;
+use bytes::{Buf, BytesMut};
+use std::io;
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio_serial::{SerialPortBuilderExt, SerialStream};
+use tokio_util::codec::{Decoder, Encoder, Framed};
+
+const START_DELIMITER: u8 = 0x7E;
+
+// start delimiter, two length bytes and the checksum wrap every frame
+const FRAME_OVERHEAD: usize = 4;
+
+pub const DEFAULT_BAUD_RATE: u32 = 9600;
+
+pub type XBeeTransport<T> = Framed<T, XBeeCodec>;
+
+#[derive(Clone, Debug)]
+pub struct SerialConfig {
+    pub path: String,
+    pub baud_rate: u32,
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct XBeeCodec {
+    pub dropped_frames: u64,
+}
+
+impl SerialConfig {
+    pub fn new(path: &str, baud_rate: u32) -> Self {
+        SerialConfig {
+            path: path.to_string(),
+            baud_rate,
+        }
+    }
+
+    pub fn open(&self) -> io::Result<XBeeTransport<SerialStream>> {
+        let port = tokio_serial::new(self.path.as_str(), self.baud_rate).open_native_async()?;
+        Ok(new_transport(port))
+    }
+}
+
+// Any byte stream works here, so tests can hand in one end of a pty pair
+// (see SerialStream::pair) with a simulated coordinator on the other end.
+pub fn new_transport<T: AsyncRead + AsyncWrite>(io: T) -> XBeeTransport<T> {
+    Framed::new(io, XBeeCodec::default())
+}
+
+fn frame_checksum_valid(frame: &[u8]) -> bool {
+    let mut sum: u8 = 0;
+    frame[3..]
+        .iter()
+        .for_each(|x| sum = sum.overflowing_add(*x).0);
+    sum == 0xFF
+}
+
+impl Decoder for XBeeCodec {
+    type Item = Packet;
+    type Error = io::Error;
+
+    fn decode(&mut self, src: &mut BytesMut) -> Result<Option<Packet>, io::Error> {
+        loop {
+            match src.iter().position(|x| *x == START_DELIMITER) {
+                Some(p) => src.advance(p),
+                None => {
+                    src.clear();
+                    return Ok(None);
+                }
+            }
+
+            if src.len() < 3 {
+                return Ok(None);
+            }
+            let length = ((src[1] as usize) << 8) | (src[2] as usize);
+            if length == 0 {
+                // can't be a real frame, resync on the next delimiter
+                src.advance(1);
+                self.dropped_frames += 1;
+                continue;
+            }
+            if src.len() < length + FRAME_OVERHEAD {
+                src.reserve(length + FRAME_OVERHEAD - src.len());
+                return Ok(None);
+            }
+
+            if !frame_checksum_valid(&src[..length + FRAME_OVERHEAD]) {
+                eprintln!("dropping frame with bad checksum");
+                src.advance(1);
+                self.dropped_frames += 1;
+                continue;
+            }
+
+            let mut raw = src.split_to(length + FRAME_OVERHEAD).to_vec();
+            match Packet::from_data(&mut raw) {
+                Ok(packet) => return Ok(Some(packet)),
+                Err(e) => {
+                    eprintln!("dropping frame: {}", e);
+                    self.dropped_frames += 1;
+                }
+            }
+        }
+    }
+}
+
+impl Encoder<Packet> for XBeeCodec {
+    type Error = io::Error;
+
+    fn encode(&mut self, packet: Packet, dst: &mut BytesMut) -> Result<(), io::Error> {
+        let bytes = packet.as_bytes();
+        if bytes.first() != Some(&START_DELIMITER) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("unsupported frame type {:?}", packet.frame_type),
+            ));
+        }
+        dst.extend_from_slice(&bytes);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::samples::PULSE_COMMAND;
+    use crate::simulator::*;
+    use futures::{SinkExt, StreamExt};
+    use std::time::Duration;
+
+    // Wraps a frame body in the delimiter, length and a checksum that passes
+    fn frame(body: &[u8]) -> Vec<u8> {
+        let mut raw = vec![START_DELIMITER, (body.len() >> 8) as u8, body.len() as u8];
+        raw.extend_from_slice(body);
+        let sum = body.iter().fold(0u8, |a, x| a.wrapping_add(*x));
+        raw.push(0xFF - sum);
+        raw
+    }
+
+    #[test]
+    fn decodes_receive_packet() {
+        let mut body = vec![0x90, 1, 2, 3, 4, 5, 6, 7, 8, 0x12, 0x34, 0x01];
+        body.extend_from_slice(&[0xAA, b'w']);
+        let mut src = BytesMut::from(&frame(&body)[..]);
+        let packet = XBeeCodec::default().decode(&mut src).unwrap().unwrap();
+        assert_eq!(packet.frame_type, FrameTypes::ReceivePacket);
+        assert_eq!(packet.address, [1, 2, 3, 4, 5, 6, 7, 8]);
+        assert_eq!(packet.network_address, [0x12, 0x34]);
+        assert_eq!(packet.data, vec![0xAA, b'w']);
+        assert!(src.is_empty());
+    }
+
+    #[test]
+    fn waits_for_the_rest_of_a_frame() {
+        let raw = frame(&[0x8B, 1, 0xFF, 0xFE, 0, 0, 0]);
+        let mut codec = XBeeCodec::default();
+        let mut src = BytesMut::from(&raw[..5]);
+        assert!(codec.decode(&mut src).unwrap().is_none());
+        src.extend_from_slice(&raw[5..]);
+        let packet = codec.decode(&mut src).unwrap().unwrap();
+        assert_eq!(packet.frame_type, FrameTypes::TransmitStatus);
+        assert_eq!(packet.frame_id, 1);
+    }
+
+    #[test]
+    fn skips_noise_and_bad_checksums() {
+        let mut raw = vec![0x00, 0x13];
+        let mut bad = frame(&[0x8B, 1, 0xFF, 0xFE, 0, 0, 0]);
+        *bad.last_mut().unwrap() ^= 0xFF;
+        raw.append(&mut bad);
+        raw.append(&mut frame(&[0x8B, 2, 0xFF, 0xFE, 0, 0, 0]));
+        let mut codec = XBeeCodec::default();
+        let mut src = BytesMut::from(&raw[..]);
+        let packet = codec.decode(&mut src).unwrap().unwrap();
+        assert_eq!(packet.frame_id, 2);
+        assert_eq!(codec.dropped_frames, 1);
+    }
+
+    // A valid checksum on a frame too short for its type used to panic in from_data
+    #[test]
+    fn drops_short_frames_with_valid_checksums() {
+        let mut codec = XBeeCodec::default();
+        for body in [
+            vec![0x90, 1, 2, 3],
+            vec![0x8B, 1],
+            vec![0x97, 1, 2, 3, 4, 5, 6, 7, 8, 9],
+            vec![0x88, 1],
+            vec![0x10, 1, 2],
+        ] {
+            let mut src = BytesMut::from(&frame(&body)[..]);
+            assert!(codec.decode(&mut src).unwrap().is_none());
+            assert!(src.is_empty());
+        }
+        assert_eq!(codec.dropped_frames, 5);
+    }
+
+    fn coordinator(faults: FaultConfig) -> Coordinator {
+        let mut coordinator = Coordinator::new(faults);
+        coordinator.add_device(VirtualDevice::new_bridge([9; 8], [0x12, 0x34]));
+        coordinator
+    }
+
+    // CH on the local radio, and a transmit the bridge answers with a status
+    fn requests() -> Vec<Packet> {
+        let mut transmit = Packet::new_transmit(&[9; 8], &[PULSE_COMMAND]);
+        transmit.set_frame_id(2);
+        transmit.insert_packet_identifer(2);
+        vec![Packet::new_local_at(1, 0x4348, &[]), transmit]
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn talks_to_a_coordinator_over_a_pty() {
+        let (host, radio) = SerialStream::pair().unwrap();
+        tokio::spawn(coordinator(FaultConfig::default()).run(radio));
+        let mut transport = new_transport(host);
+
+        let requests = requests();
+        transport.send(requests[0].clone()).await.unwrap();
+        let response = transport.next().await.unwrap().unwrap();
+        assert_eq!(response.frame_type, FrameTypes::LocalAtResponse);
+        assert_eq!(response.frame_id, 1);
+        assert_eq!(response.data, vec![0x0F]);
+
+        transport.send(requests[1].clone()).await.unwrap();
+        let status = transport.next().await.unwrap().unwrap();
+        assert_eq!(status.frame_type, FrameTypes::TransmitStatus);
+        assert_eq!(status.frame_id, 2);
+        let received = transport.next().await.unwrap().unwrap();
+        assert_eq!(received.frame_type, FrameTypes::ReceivePacket);
+        assert_eq!(received.address, [9; 8]);
+        assert_eq!(received.data.len(), 13);
+        assert_eq!(transport.codec().dropped_frames, 0);
+    }
+
+    #[tokio::test]
+    async fn counts_corrupt_frames_from_the_coordinator() {
+        let (host, radio) = tokio::io::duplex(1024);
+        let faults = FaultConfig {
+            bad_checksum_rate: 1.0,
+            ..FaultConfig::default()
+        };
+        tokio::spawn(coordinator(faults).run(radio));
+        let mut transport = new_transport(host);
+
+        for request in requests() {
+            transport.send(request).await.unwrap();
+        }
+        let next = tokio::time::timeout(Duration::from_millis(100), transport.next()).await;
+        assert!(next.is_err());
+        // the AT response, the transmit status and the bridge's reply
+        assert_eq!(transport.codec().dropped_frames, 3);
+    }
+}
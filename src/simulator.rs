@@ -0,0 +1,792 @@
+use crate::device::DeviceTypes;
+use crate::modbus::*;
+use crate::modbus_tcp::TcpFraming;
+use crate::packet::*;
+use crate::samples::*;
+use crate::serial::*;
+use futures::StreamExt;
+use std::collections::HashMap;
+use std::convert::TryFrom;
+use std::io;
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::net::TcpListener;
+
+const DISCOVERY_COMMAND: u16 = 0x4444; // "DD"
+const AT_STATUS_OK: u8 = 0x00;
+const AT_STATUS_INVALID_COMMAND: u8 = 0x02;
+const DELIVERY_SUCCESS: u8 = 0x00;
+const DELIVERY_ADDRESS_NOT_FOUND: u8 = 0x24;
+const RECEIVE_ACKNOWLEDGED: u8 = 0x01;
+const UNKNOWN_NETWORK_ADDRESS: [u8; 2] = [0xFF, 0xFE];
+
+// Meters answer the commands in samples.rs, bridges answer PULSE_COMMAND with
+// their six pulse counters and treat anything else as an RTU frame for the
+// RS-485 side.
+#[derive(Clone, Debug, Default)]
+pub struct ModbusSlave {
+    pub coils: HashMap<u16, bool>,
+    pub inputs: HashMap<u16, bool>,
+    pub holding_registers: HashMap<u16, u16>,
+    pub input_registers: HashMap<u16, u16>,
+}
+
+#[derive(Clone, Debug)]
+pub struct VirtualDevice {
+    pub device_type: DeviceTypes,
+    pub address: [u8; 8],
+    pub network_address: [u8; 2],
+    // meter readings in the units MeterSample stores them in
+    pub watts: [u32; 24],
+    pub millivolts: [u32; 3],
+    pub milliamps: [u32; 24],
+    pub power_factor: [u32; 24],
+    pub watt_hours: [u32; 24],
+    pub pulses: [u16; 6],
+    pub slaves: HashMap<u8, ModbusSlave>,
+}
+
+// Rates are probabilities between 0 and 1 applied to every outgoing frame.
+#[derive(Clone, Debug, Default)]
+pub struct FaultConfig {
+    pub drop_rate: f64,
+    pub bad_checksum_rate: f64,
+    pub delay: Duration,
+    pub seed: u64,
+}
+
+pub struct Coordinator {
+    pub devices: Vec<VirtualDevice>,
+    pub faults: FaultConfig,
+    // local AT parameters keyed by command, e.g. "ID" or "CH"
+    pub settings: HashMap<u16, Vec<u8>>,
+    rng_state: u64,
+}
+
+// Stands in for an Ethernet meter or a serial gateway on the bench
+pub struct ModbusTcpServer {
+    pub framing: TcpFraming,
+    pub slaves: HashMap<u8, ModbusSlave>,
+}
+
+// two 12-bit channels are packed into every three bytes
+fn pack_12bit(values: &[u32], unit: u32) -> Vec<u8> {
+    let mut data = Vec::new();
+    for pair in values.chunks(2) {
+        let first = (pair[0] / unit).min(0x0FFF);
+        let second = (pair[1] / unit).min(0x0FFF);
+        data.push((first >> 4) as u8);
+        data.push((((first & 0x0F) << 4) | (second >> 8)) as u8);
+        data.push((second & 0x0FF) as u8);
+    }
+    data
+}
+
+fn at_code(name: &[u8; 2]) -> u16 {
+    ((name[0] as u16) << 8) | (name[1] as u16)
+}
+
+impl VirtualDevice {
+    pub fn new_power_meter(address: [u8; 8], network: [u8; 2]) -> Self {
+        VirtualDevice {
+            device_type: DeviceTypes::PowerMeter,
+            address,
+            network_address: network,
+            watts: [0; 24],
+            millivolts: [0; 3],
+            milliamps: [0; 24],
+            power_factor: [0; 24],
+            watt_hours: [0; 24],
+            pulses: [0; 6],
+            slaves: HashMap::new(),
+        }
+    }
+
+    pub fn new_bridge(address: [u8; 8], network: [u8; 2]) -> Self {
+        VirtualDevice {
+            device_type: DeviceTypes::Bridge,
+            address,
+            network_address: network,
+            watts: [0; 24],
+            millivolts: [0; 3],
+            milliamps: [0; 24],
+            power_factor: [0; 24],
+            watt_hours: [0; 24],
+            pulses: [0; 6],
+            slaves: HashMap::new(),
+        }
+    }
+
+    pub fn add_slave(&mut self, address: u8, slave: ModbusSlave) {
+        self.slaves.insert(address, slave);
+    }
+
+    fn remote_at_response(&self, request: &Packet) -> Packet {
+        let command = ((request.command[0] as u16) << 8) | (request.command[1] as u16);
+        match command {
+            DISCOVERY_COMMAND => Packet::new_remote_at_response(
+                request.frame_id,
+                &self.address,
+                self.network_address,
+                command,
+                AT_STATUS_OK,
+                &[0x00, 0x00, 0x00, self.device_type.clone() as u8],
+            ),
+            _ => Packet::new_remote_at_response(
+                request.frame_id,
+                &self.address,
+                self.network_address,
+                command,
+                AT_STATUS_INVALID_COMMAND,
+                &[],
+            ),
+        }
+    }
+
+    fn receive(&mut self, request: &Packet) -> Option<Packet> {
+        if request.data.len() < 2 {
+            return None;
+        }
+        let mut payload = request.data.clone();
+        let identifier = payload.remove(1);
+
+        let response = match self.device_type {
+            DeviceTypes::PowerMeter => match payload[0] {
+                POWER_COMMAND => pack_12bit(&self.watts, 20),
+                CURRENT_COMMAND => pack_12bit(&self.milliamps, 10),
+                VOLTAGE_COMMAND => self
+                    .millivolts
+                    .iter()
+                    .flat_map(|x| ((x / 100).min(0xFFFF) as u16).to_be_bytes())
+                    .collect(),
+                POWER_FACTOR_COMMAND => self
+                    .power_factor
+                    .iter()
+                    .map(|x| (x / 10).min(100) as u8)
+                    .collect(),
+                ENERGY_COMMAND => self
+                    .watt_hours
+                    .iter()
+                    .flat_map(|x| x.to_be_bytes())
+                    .collect(),
+                _ => return None,
+            },
+            DeviceTypes::Bridge => match payload[0] {
+                PULSE_COMMAND => self.pulse_payload(),
+                _ => self.modbus_payload(payload)?,
+            },
+            DeviceTypes::None => return None,
+        };
+
+        let mut data = vec![identifier];
+        data.extend_from_slice(&response);
+        Some(Packet::new_receive(
+            &self.address,
+            self.network_address,
+            RECEIVE_ACKNOWLEDGED,
+            &data,
+        ))
+    }
+
+    fn pulse_payload(&self) -> Vec<u8> {
+        let mut data = Vec::new();
+        for count in self.pulses.iter() {
+            data.push((count >> 8) as u8);
+            data.push((count & 0x0FF) as u8);
+        }
+        data
+    }
+
+    fn modbus_payload(&mut self, request: Vec<u8>) -> Option<Vec<u8>> {
+        if request.len() < 8 {
+            return None;
+        }
+        let crc = crc_helper(request[..request.len() - 2].to_vec());
+        if request[request.len() - 2] != (crc & 0x0FF) as u8
+            || request[request.len() - 1] != (crc >> 8) as u8
+        {
+            // a real slave stays silent when the CRC doesn't match
+            return None;
+        }
+
+        let slave = self.slaves.get_mut(&request[0])?;
+        let mut response = slave.respond(request)?;
+        let crc = crc_helper(response.clone());
+        response.push((crc & 0x0FF) as u8);
+        response.push((crc >> 8) as u8);
+        Some(response)
+    }
+}
+
+impl ModbusSlave {
+    // Takes a complete RTU request and returns the reply frame without its CRC,
+    // or None for a request too short to parse, which a real slave ignores.
+    // A quantity outside the protocol limits gets an IllegalDataValue exception.
+    pub fn respond(&mut self, request: Vec<u8>) -> Option<Vec<u8>> {
+        let message = match ModbusMessage::sent_from_data(request.clone()) {
+            Ok(m) => m,
+            Err(ModbusError::InvalidQuantity(_q)) => {
+                return Some(vec![
+                    request[0],
+                    request[1] | 0x80,
+                    ModbusException::IllegalDataValue.code(),
+                ])
+            }
+            Err(_e) => return None,
+        };
+        let echo = request[..6].to_vec();
+        let slave = self;
+
+        let function_code = echo[1];
+        let mut response = vec![message.address, message.function.clone() as u8];
+        match message.function {
+            FunctionTypes::None => {
+                response = vec![message.address, function_code | 0x80];
+                response.push(ModbusException::IllegalFunction.code());
+            }
+            FunctionTypes::ReadCoilStatus | FunctionTypes::ReadInputStatus => {
+                let bits = match message.function {
+                    FunctionTypes::ReadCoilStatus => &slave.coils,
+                    _ => &slave.inputs,
+                };
+                let values: Vec<bool> = (0..message.num_data_points)
+                    .map(|i| {
+                        *bits
+                            .get(&message.start_address.wrapping_add(i))
+                            .unwrap_or(&false)
+                    })
+                    .collect();
+                let packed = pack_bits(&values);
+                response.push(packed.len() as u8);
+                response.extend_from_slice(&packed);
+            }
+            FunctionTypes::ReadHoldingRegisters | FunctionTypes::ReadInputRegisters => {
+                let registers = match message.function {
+                    FunctionTypes::ReadHoldingRegisters => &slave.holding_registers,
+                    _ => &slave.input_registers,
+                };
+                // at most 125 registers get past sent_from_data, 250 bytes
+                response.push(u8::try_from(message.num_data_points as usize * 2).ok()?);
+                for i in 0..message.num_data_points {
+                    let address = message.start_address.wrapping_add(i);
+                    let value = *registers.get(&address).unwrap_or(&0);
+                    response.push((value >> 8) as u8);
+                    response.push((value & 0x0FF) as u8);
+                }
+            }
+            FunctionTypes::WriteSingleCoil => {
+                slave
+                    .coils
+                    .insert(message.start_address, message.data[0] == 0xFF);
+                response = echo;
+            }
+            FunctionTypes::WriteSingleRegister => {
+                let value = ((message.data[0] as u16) << 8) | (message.data[1] as u16);
+                slave.holding_registers.insert(message.start_address, value);
+                response = echo;
+            }
+            FunctionTypes::WriteMultipleCoils => {
+                let values = unpack_bits(&message.data, message.num_data_points);
+                for (i, value) in values.into_iter().enumerate() {
+                    slave
+                        .coils
+                        .insert(message.start_address.wrapping_add(i as u16), value);
+                }
+                response = echo;
+            }
+            FunctionTypes::WriteMultipleRegisters => {
+                for i in 0..message.num_data_points {
+                    let value = ((message.data[(i * 2) as usize] as u16) << 8)
+                        | (message.data[(i * 2 + 1) as usize] as u16);
+                    slave
+                        .holding_registers
+                        .insert(message.start_address.wrapping_add(i), value);
+                }
+                response = echo;
+            }
+        }
+        Some(response)
+    }
+}
+
+impl Coordinator {
+    pub fn new(faults: FaultConfig) -> Self {
+        let seed = match faults.seed {
+            0 => 0x2545_F491_4F6C_DD1D,
+            s => s,
+        };
+        let mut settings = HashMap::new();
+        settings.insert(at_code(b"ID"), vec![0x00; 8]);
+        settings.insert(at_code(b"CH"), vec![0x0F]);
+        settings.insert(at_code(b"SH"), vec![0x00, 0x13, 0xA2, 0x00]);
+        settings.insert(at_code(b"SL"), vec![0x40, 0x00, 0x00, 0x01]);
+        settings.insert(at_code(b"NI"), b"COORDINATOR".to_vec());
+        settings.insert(at_code(b"NJ"), vec![0xFF]);
+        settings.insert(at_code(b"AP"), vec![0x01]);
+        settings.insert(at_code(b"BD"), vec![0x03]);
+        settings.insert(at_code(b"EE"), vec![0x00]);
+        settings.insert(at_code(b"KY"), Vec::new());
+
+        Coordinator {
+            devices: Vec::new(),
+            faults,
+            settings,
+            rng_state: seed,
+        }
+    }
+
+    pub fn add_device(&mut self, device: VirtualDevice) {
+        self.devices.push(device);
+    }
+
+    pub fn device_mut(&mut self, address: &[u8; 8]) -> Option<&mut VirtualDevice> {
+        self.devices.iter_mut().find(|x| x.address == *address)
+    }
+
+    // Returns the raw frames the coordinator would put on the serial line in
+    // response to a frame from the host, after fault injection.
+    pub fn handle(&mut self, request: &Packet) -> Vec<Vec<u8>> {
+        let mut responses: Vec<Packet> = Vec::new();
+
+        match request.frame_type {
+            FrameTypes::RemoteAtRequest => {
+                self.devices
+                    .iter()
+                    .filter(|x| request.is_broadcast || x.address == request.address)
+                    .for_each(|x| responses.push(x.remote_at_response(request)));
+            }
+            FrameTypes::LocalAtCommand => {
+                responses.append(&mut self.local_at_responses(request));
+            }
+            FrameTypes::TransmitRequest => {
+                let targets: Vec<usize> = self
+                    .devices
+                    .iter()
+                    .enumerate()
+                    .filter(|(_i, x)| request.is_broadcast || x.address == request.address)
+                    .map(|(i, _x)| i)
+                    .collect();
+
+                if request.frame_id != 0x00 {
+                    let (network, status) = match (request.is_broadcast, targets.first()) {
+                        (true, _) => (UNKNOWN_NETWORK_ADDRESS, DELIVERY_SUCCESS),
+                        (false, Some(i)) => (self.devices[*i].network_address, DELIVERY_SUCCESS),
+                        (false, None) => (UNKNOWN_NETWORK_ADDRESS, DELIVERY_ADDRESS_NOT_FOUND),
+                    };
+                    responses.push(Packet::new_transmit_status(
+                        request.frame_id,
+                        network,
+                        0x00,
+                        status,
+                        0x00,
+                    ));
+                }
+
+                for i in targets {
+                    if let Some(p) = self.devices[i].receive(request) {
+                        responses.push(p);
+                    }
+                }
+            }
+            _ => {}
+        }
+
+        responses
+            .iter()
+            .filter_map(|x| self.apply_faults(x.as_bytes()))
+            .collect()
+    }
+
+    fn local_at_responses(&mut self, request: &Packet) -> Vec<Packet> {
+        let command = ((request.command[0] as u16) << 8) | (request.command[1] as u16);
+        let mut responses = Vec::new();
+
+        if command == at_code(b"ND") {
+            for device in self.devices.iter() {
+                let mut data = Vec::new();
+                data.extend_from_slice(&device.network_address);
+                data.extend_from_slice(&device.address);
+                data.push(0x00); // empty node identifier
+                data.extend_from_slice(&[0xFF, 0xFE]); // parent
+                data.push(0x01); // router
+                data.push(0x00);
+                data.extend_from_slice(&[0xC1, 0x05]); // Digi profile
+                data.extend_from_slice(&[0x10, 0x1E]); // Digi manufacturer
+                responses.push(Packet::new_local_at_response(
+                    request.frame_id,
+                    command,
+                    AT_STATUS_OK,
+                    &data,
+                ));
+            }
+            return responses;
+        }
+        if command == at_code(b"WR") || command == at_code(b"AC") {
+            responses.push(Packet::new_local_at_response(
+                request.frame_id,
+                command,
+                AT_STATUS_OK,
+                &[],
+            ));
+            return responses;
+        }
+
+        let response = match self.settings.get_mut(&command) {
+            Some(value) => match request.data.is_empty() {
+                // KY can be set but never read back
+                true if command == at_code(b"KY") => Vec::new(),
+                true => value.clone(),
+                false => {
+                    *value = request.data.clone();
+                    Vec::new()
+                }
+            },
+            None => {
+                responses.push(Packet::new_local_at_response(
+                    request.frame_id,
+                    command,
+                    AT_STATUS_INVALID_COMMAND,
+                    &[],
+                ));
+                return responses;
+            }
+        };
+        responses.push(Packet::new_local_at_response(
+            request.frame_id,
+            command,
+            AT_STATUS_OK,
+            &response,
+        ));
+        responses
+    }
+
+    pub async fn run<T: AsyncRead + AsyncWrite + Unpin>(mut self, io: T) -> io::Result<()> {
+        let mut transport = new_transport(io);
+        while let Some(frame) = transport.next().await {
+            let request = frame?;
+            for response in self.handle(&request) {
+                if self.faults.delay > Duration::from_millis(0) {
+                    tokio::time::sleep(self.faults.delay).await;
+                }
+                transport.get_mut().write_all(&response).await?;
+            }
+        }
+        Ok(())
+    }
+
+    fn apply_faults(&mut self, mut frame: Vec<u8>) -> Option<Vec<u8>> {
+        if self.next_random() < self.faults.drop_rate {
+            return None;
+        }
+        if self.next_random() < self.faults.bad_checksum_rate {
+            if let Some(checksum) = frame.last_mut() {
+                *checksum = !*checksum;
+            }
+        }
+        Some(frame)
+    }
+
+    // xorshift64, good enough to make fault injection reproducible from a seed
+    fn next_random(&mut self) -> f64 {
+        let mut x = self.rng_state;
+        x ^= x << 13;
+        x ^= x >> 7;
+        x ^= x << 17;
+        self.rng_state = x;
+        (x >> 11) as f64 / (1u64 << 53) as f64
+    }
+}
+
+impl ModbusTcpServer {
+    pub fn new(framing: TcpFraming) -> Self {
+        ModbusTcpServer {
+            framing,
+            slaves: HashMap::new(),
+        }
+    }
+
+    pub fn add_slave(&mut self, address: u8, slave: ModbusSlave) {
+        self.slaves.insert(address, slave);
+    }
+
+    // Serves one connection at a time, which is all the client opens
+    pub async fn run(mut self, listener: TcpListener) -> io::Result<()> {
+        loop {
+            let (stream, _peer) = listener.accept().await?;
+            match self.serve(stream).await {
+                Ok(_) => {}
+                Err(e) => eprintln!("modbus tcp connection closed: {}", e),
+            }
+        }
+    }
+
+    // With RtuOverTcp framing this also stands in for an RS-485 line, e.g. on
+    // one end of a pty pair
+    pub async fn serve<S: AsyncRead + AsyncWrite + Unpin>(
+        &mut self,
+        mut stream: S,
+    ) -> io::Result<()> {
+        loop {
+            match self.framing {
+                TcpFraming::Mbap => {
+                    let mut header = [0u8; 7];
+                    stream.read_exact(&mut header).await?;
+                    let length = u16::from_be_bytes([header[4], header[5]]) as usize;
+                    // the length counts the unit id and at least a function code
+                    if length < 2 {
+                        return Err(io::Error::new(
+                            io::ErrorKind::InvalidData,
+                            format!("MBAP length {} too short", length),
+                        ));
+                    }
+                    let mut request = vec![header[6]];
+                    request.resize(length, 0);
+                    stream.read_exact(&mut request[1..]).await?;
+                    // the RTU parser expects a CRC on the end, it is never checked here
+                    request.extend_from_slice(&[0, 0]);
+
+                    let response = match self.slaves.get_mut(&header[6]) {
+                        Some(slave) => match slave.respond(request) {
+                            Some(r) => r,
+                            None => continue,
+                        },
+                        None => continue,
+                    };
+                    let mut frame = header[..4].to_vec();
+                    frame.extend_from_slice(&(response.len() as u16).to_be_bytes());
+                    frame.extend_from_slice(&response);
+                    stream.write_all(&frame).await?;
+                }
+                TcpFraming::RtuOverTcp => {
+                    let mut request = vec![0u8; 8];
+                    stream.read_exact(&mut request).await?;
+                    // multi writes carry a byte count where the CRC would otherwise start
+                    if request[1] == FunctionTypes::WriteMultipleCoils as u8
+                        || request[1] == FunctionTypes::WriteMultipleRegisters as u8
+                    {
+                        let mut rest = vec![0u8; request[6] as usize + 1];
+                        stream.read_exact(&mut rest).await?;
+                        request.append(&mut rest);
+                    }
+
+                    let crc = crc_helper(request[..request.len() - 2].to_vec());
+                    if request[request.len() - 2] != (crc & 0x0FF) as u8
+                        || request[request.len() - 1] != (crc >> 8) as u8
+                    {
+                        continue;
+                    }
+                    if request[0] == BROADCAST_ADDRESS {
+                        for slave in self.slaves.values_mut() {
+                            slave.respond(request.clone());
+                        }
+                        continue;
+                    }
+                    let mut response = match self.slaves.get_mut(&request[0]) {
+                        Some(slave) => match slave.respond(request) {
+                            Some(r) => r,
+                            None => continue,
+                        },
+                        None => continue,
+                    };
+                    let crc = crc_helper(response.clone());
+                    response.push((crc & 0x0FF) as u8);
+                    response.push((crc >> 8) as u8);
+                    stream.write_all(&response).await?;
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::device::{Device, DeviceDB};
+    use crate::frame_id::FrameIdAllocator;
+    use crate::message::Message;
+    use crate::store::SampleStore;
+    use crate::task::*;
+    use futures::SinkExt;
+    use tokio::io::DuplexStream;
+
+    const BRIDGE: [u8; 8] = [9, 9, 9, 9, 9, 9, 9, 9];
+
+    fn bridge() -> VirtualDevice {
+        let mut bridge = VirtualDevice::new_bridge(BRIDGE, [0x12, 0x34]);
+        let mut slave = ModbusSlave::default();
+        slave.holding_registers.insert(0, 0xBEEF);
+        bridge.add_slave(5, slave);
+        bridge
+    }
+
+    // The host end of a serial line with a coordinator and one bridge behind it
+    fn connect(faults: FaultConfig) -> XBeeTransport<DuplexStream> {
+        let mut coordinator = Coordinator::new(faults);
+        coordinator.add_device(bridge());
+        let (host, radio) = tokio::io::duplex(1024);
+        tokio::spawn(coordinator.run(radio));
+        new_transport(host)
+    }
+
+    fn read_task() -> Task {
+        let data = ModbusMessage::new_read_message(5, 3, 0, 1).as_bytes();
+        let packet = Packet::new_transmit(&BRIDGE, &data);
+        Task::new(packet, TaskTypes::Single, 0, 0, DeviceTypes::Bridge)
+    }
+
+    async fn nothing_arrives(transport: &mut XBeeTransport<DuplexStream>) -> bool {
+        tokio::time::timeout(Duration::from_millis(100), transport.next())
+            .await
+            .is_err()
+    }
+
+    fn request(data: &[u8], id: u8) -> Packet {
+        let mut packet = Packet::new_transmit(&BRIDGE, data);
+        packet.set_frame_id(id);
+        packet.insert_packet_identifer(id);
+        packet
+    }
+
+    #[test]
+    fn bridge_answers_slave_112_over_modbus() {
+        let mut bridge = VirtualDevice::new_bridge(BRIDGE, [0x12, 0x34]);
+        bridge.pulses = [1, 2, 3, 4, 5, 6];
+        let mut slave = ModbusSlave::default();
+        slave.holding_registers.insert(0, 0xBEEF);
+        bridge.add_slave(112, slave);
+
+        let sent = ModbusMessage::new_read_message(112, 3, 0, 1).as_bytes();
+        let response = bridge.receive(&request(&sent, 7)).unwrap();
+        assert_eq!(response.data[0], 7);
+        assert_eq!(&response.data[1..6], &[112, 3, 2, 0xBE, 0xEF]);
+
+        let response = bridge.receive(&request(&[PULSE_COMMAND], 8)).unwrap();
+        assert_eq!(&response.data[1..], &[0, 1, 0, 2, 0, 3, 0, 4, 0, 5, 0, 6]);
+    }
+
+    #[test]
+    fn slaves_answer_bad_quantities_with_an_exception() {
+        let mut slave = ModbusSlave::default();
+        for (function, quantity) in [(3, 126), (1, 2001), (4, 0)] {
+            let request = ModbusMessage::new_read_message(5, function, 0, quantity).as_bytes();
+            assert_eq!(
+                slave.respond(request),
+                Some(vec![
+                    5,
+                    function | 0x80,
+                    ModbusException::IllegalDataValue.code()
+                ])
+            );
+        }
+        let request = ModbusMessage::new_read_message(5, 3, 0, 125).as_bytes();
+        let response = slave.respond(request).unwrap();
+        assert_eq!(response[2], 250);
+        assert_eq!(response.len(), 3 + 250);
+    }
+
+    #[tokio::test]
+    async fn tcp_server_rejects_short_mbap_lengths() {
+        let mut server = ModbusTcpServer::new(TcpFraming::Mbap);
+        server.add_slave(5, ModbusSlave::default());
+        let (mut client, mut stream) = tokio::io::duplex(64);
+        for length in [0u8, 1] {
+            client.write_all(&[0, 1, 0, 0, 0, length, 5]).await.unwrap();
+            let result = server.serve(&mut stream).await;
+            assert_eq!(result.unwrap_err().kind(), io::ErrorKind::InvalidData);
+        }
+    }
+
+    #[tokio::test]
+    async fn round_trip_through_the_device_database() {
+        let db = sled::Config::new().temporary(true).open().unwrap();
+        let mut devicedb = DeviceDB::from_db(db).unwrap();
+        devicedb.add_device(Device::new(DeviceTypes::Bridge, BRIDGE, [0x12, 0x34]));
+        let store = sled::Config::new().temporary(true).open().unwrap();
+        let store = SampleStore::from_db(store).unwrap();
+
+        let mut transport = connect(FaultConfig::default());
+        let packet = devicedb.send_task(read_task(), false).unwrap();
+        transport.send(packet).await.unwrap();
+
+        let mut status = transport.next().await.unwrap().unwrap();
+        assert_eq!(status.frame_type, FrameTypes::TransmitStatus);
+        assert_eq!(status.delivery_status, DELIVERY_SUCCESS);
+        devicedb.clear_sent_message(&mut status);
+
+        let mut received = transport.next().await.unwrap().unwrap();
+        assert_eq!(received.frame_type, FrameTypes::ReceivePacket);
+        let (device_type, sent) = devicedb.clear_sent_message(&mut received);
+        devicedb.handle_message(Message::new_packet(received, sent, device_type), &store);
+
+        let samples = store.get_samples(&SampleTypes::Bridge, 10).unwrap();
+        assert_eq!(samples.len(), 1);
+        match &samples[0].1 {
+            Sample::Bridge(s) => {
+                assert_eq!(s.slave, 5);
+                assert_eq!(s.values, vec![0xBEEF]);
+            }
+            _ => panic!("expected a bridge sample"),
+        }
+        assert_eq!(transport.codec().dropped_frames, 0);
+    }
+
+    #[tokio::test]
+    async fn dropped_frames_never_arrive() {
+        let mut transport = connect(FaultConfig {
+            drop_rate: 1.0,
+            ..FaultConfig::default()
+        });
+        let mut packet = read_task().packet;
+        Device::add_identifier(
+            &mut packet,
+            DeviceTypes::Bridge,
+            &mut FrameIdAllocator::new(),
+        );
+        transport.send(packet).await.unwrap();
+        assert!(nothing_arrives(&mut transport).await);
+        assert_eq!(transport.codec().dropped_frames, 0);
+    }
+
+    #[tokio::test]
+    async fn bad_checksums_are_dropped_by_the_codec() {
+        let mut transport = connect(FaultConfig {
+            bad_checksum_rate: 1.0,
+            ..FaultConfig::default()
+        });
+        let mut packet = read_task().packet;
+        Device::add_identifier(
+            &mut packet,
+            DeviceTypes::Bridge,
+            &mut FrameIdAllocator::new(),
+        );
+        transport.send(packet).await.unwrap();
+        assert!(nothing_arrives(&mut transport).await);
+        // the transmit status and the response
+        assert_eq!(transport.codec().dropped_frames, 2);
+    }
+
+    #[test]
+    fn faults_repeat_for_a_fixed_seed() {
+        let data = ModbusMessage::new_read_message(5, 3, 0, 1).as_bytes();
+        let faults = FaultConfig {
+            drop_rate: 0.5,
+            bad_checksum_rate: 0.5,
+            seed: 42,
+            ..FaultConfig::default()
+        };
+        let run = |faults: FaultConfig| {
+            let mut coordinator = Coordinator::new(faults);
+            coordinator.add_device(bridge());
+            (1..=20u8)
+                .map(|id| coordinator.handle(&request(&data, id)))
+                .collect::<Vec<Vec<Vec<u8>>>>()
+        };
+        let first = run(faults.clone());
+        assert_eq!(first, run(faults.clone()));
+        // with these rates some frames get through and some don't
+        let counts: Vec<usize> = first.iter().map(|x| x.len()).collect();
+        assert!(counts.contains(&0) || counts.contains(&1));
+        assert!(counts.iter().sum::<usize>() > 0);
+        assert_ne!(first, run(FaultConfig { seed: 7, ..faults }));
+    }
+}
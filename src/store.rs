@@ -0,0 +1,550 @@
+use crate::query::*;
+use crate::retention::*;
+use crate::samples::*;
+use std::collections::HashMap;
+use std::path::Path;
+use std::time::{Duration, SystemTime};
+
+const QUARANTINE_TREE: &str = "quarantine";
+const ARCHIVE_TREE: &str = "archive";
+const EVICTION_TREE: &str = "evictions";
+
+// Samples live in one sled tree per sample type. Records that no longer decode
+// are moved to the quarantine tree, keyed by their tree name and old key, so a
+// bad record is kept for inspection without stopping every read after it.
+// Samples that only went upstream as rollups go to the archive tree the same
+// way and stay there until prune_archive finds them older than the retention
+// the uploader asks for.
+pub struct SampleStore {
+    db: sled::Db,
+    quarantine: sled::Tree,
+    archive: sled::Tree,
+    // running eviction counts per sample type as JSON, kept across restarts
+    evictions: sled::Tree,
+}
+
+struct StoredRecord {
+    key: sled::IVec,
+    timestamp: u128,
+    size: u64,
+    series: Vec<u8>,
+    removed: bool,
+}
+
+impl SampleTypes {
+    pub fn tree_name(&self) -> &'static str {
+        match self {
+            SampleTypes::Meter => "meter",
+            SampleTypes::Bridge => "bridge",
+            SampleTypes::Pulse => "pulse",
+            SampleTypes::None => "none",
+        }
+    }
+}
+
+impl SampleStore {
+    pub fn open<P: AsRef<Path>>(path: P) -> sled::Result<Self> {
+        SampleStore::from_db(sled::open(path)?)
+    }
+
+    pub fn from_db(db: sled::Db) -> sled::Result<Self> {
+        let quarantine = db.open_tree(QUARANTINE_TREE)?;
+        let archive = db.open_tree(ARCHIVE_TREE)?;
+        let evictions = db.open_tree(EVICTION_TREE)?;
+        Ok(SampleStore {
+            db,
+            quarantine,
+            archive,
+            evictions,
+        })
+    }
+
+    pub fn tree(&self, sample_type: &SampleTypes) -> sled::Result<sled::Tree> {
+        self.db.open_tree(sample_type.tree_name())
+    }
+
+    pub fn insert(&self, sample: &Sample) -> sled::Result<Vec<u8>> {
+        let (sample_type, (value, key)) = match sample {
+            Sample::Meter(s) => (SampleTypes::Meter, s.to_ivec()),
+            Sample::Bridge(s) => (SampleTypes::Bridge, s.to_ivec()),
+            Sample::Pulse(s) => (SampleTypes::Pulse, s.to_ivec()),
+            Sample::None => return Ok(Vec::new()),
+        };
+        self.tree(&sample_type)?.insert(key.clone(), value)?;
+        Ok(key.to_vec())
+    }
+
+    // The oldest num_samples decodable samples, quarantining anything corrupt on
+    // the way. Keys are hashes, so the whole tree is read to find them; counter
+    // deltas and validation depend on samples going out in time order.
+    pub fn get_samples(
+        &self,
+        sample_type: &SampleTypes,
+        num_samples: u16,
+    ) -> sled::Result<Vec<(Vec<u8>, Sample)>> {
+        let tree = self.tree(sample_type)?;
+        let mut samples = Vec::new();
+        for entry in tree.iter() {
+            let (key, value) = entry?;
+            if let Some(sample) = self.decode(&tree, sample_type, key.clone(), value)? {
+                samples.push((key.to_vec(), sample));
+            }
+        }
+        samples.sort_by_key(|x| x.1.timestamp());
+        samples.truncate(num_samples as usize);
+        Ok(samples)
+    }
+
+    // Keys are hashes, so every query scans the whole tree for its sample type
+    pub fn query(&self, query: &SampleQuery) -> sled::Result<SamplePage> {
+        let tree = self.tree(&query.sample_type)?;
+        let mut matches = Vec::new();
+        for entry in tree.iter() {
+            let (key, value) = entry?;
+            match self.decode(&tree, &query.sample_type, key.clone(), value)? {
+                Some(sample) if query.matches(&sample) => matches.push((key.to_vec(), sample)),
+                _ => {}
+            }
+        }
+        Ok(query.page(matches))
+    }
+
+    fn decode(
+        &self,
+        tree: &sled::Tree,
+        sample_type: &SampleTypes,
+        key: sled::IVec,
+        value: sled::IVec,
+    ) -> sled::Result<Option<Sample>> {
+        let decoded = match sample_type {
+            SampleTypes::Meter => MeterSample::from_ivec(value.clone()).map(Sample::Meter),
+            SampleTypes::Bridge => BridgeSample::from_ivec(value.clone()).map(Sample::Bridge),
+            SampleTypes::Pulse => PulseSample::from_ivec(value.clone()).map(Sample::Pulse),
+            SampleTypes::None => Ok(Sample::None),
+        };
+        match decoded {
+            Ok(sample) => Ok(Some(sample)),
+            Err(e) => {
+                eprintln!(
+                    "quarantining {} record {:X?}: {}",
+                    sample_type.tree_name(),
+                    key.as_ref(),
+                    e
+                );
+                self.quarantine(tree, sample_type, key, value)?;
+                Ok(None)
+            }
+        }
+    }
+
+    pub fn clear_samples(&self, sample_type: &SampleTypes, keys: &[Vec<u8>]) -> sled::Result<()> {
+        self.remove_samples(sample_type, keys, false)
+    }
+
+    // Clears the samples but keeps the raw records until prune_archive drops them
+    pub fn archive_samples(&self, sample_type: &SampleTypes, keys: &[Vec<u8>]) -> sled::Result<()> {
+        self.remove_samples(sample_type, keys, true)
+    }
+
+    fn remove_samples(
+        &self,
+        sample_type: &SampleTypes,
+        keys: &[Vec<u8>],
+        archive: bool,
+    ) -> sled::Result<()> {
+        let tree = self.tree(sample_type)?;
+        for key in keys.iter() {
+            let removed = tree.remove(key)?;
+            if let (Some(value), true) = (removed, archive) {
+                self.archive.insert(prefixed_key(sample_type, key), value)?;
+            }
+        }
+        Ok(())
+    }
+
+    // Drops archived samples older than the retention, returns how many went
+    pub fn prune_archive(&self, retention: Duration) -> sled::Result<usize> {
+        let cutoff = time_as_millis(SystemTime::now()).saturating_sub(retention.as_millis());
+        let mut pruned = 0;
+        for entry in self.archive.iter() {
+            let (key, value) = entry?;
+            match record_timestamp(value.as_ref()) {
+                Some(t) if t >= cutoff => {}
+                _ => {
+                    self.archive.remove(key)?;
+                    pruned += 1;
+                }
+            }
+        }
+        Ok(pruned)
+    }
+
+    pub fn archived(&self) -> usize {
+        self.archive.len()
+    }
+
+    fn quarantine(
+        &self,
+        tree: &sled::Tree,
+        sample_type: &SampleTypes,
+        key: sled::IVec,
+        value: sled::IVec,
+    ) -> sled::Result<()> {
+        self.quarantine
+            .insert(prefixed_key(sample_type, key.as_ref()), value)?;
+        tree.remove(key)?;
+        Ok(())
+    }
+
+    // Applies one policy and returns what it removed this time, which is also
+    // added to the totals returned by evictions
+    pub fn enforce_retention(&self, policy: &RetentionPolicy) -> sled::Result<EvictionCounters> {
+        let tree = self.tree(&policy.sample_type)?;
+        let mut counters = EvictionCounters::default();
+        let mut records = Vec::new();
+        for entry in tree.iter() {
+            let (key, value) = entry?;
+            let size = (key.len() + value.len()) as u64;
+            if let Some(sample) = self.decode(&tree, &policy.sample_type, key.clone(), value)? {
+                records.push(StoredRecord {
+                    key,
+                    timestamp: sample.timestamp(),
+                    size,
+                    series: series_key(&sample),
+                    removed: false,
+                });
+            }
+        }
+        records.sort_by_key(|x| x.timestamp);
+
+        if let Some(age) = policy.max_age_secs {
+            let cutoff = time_as_millis(SystemTime::now()).saturating_sub(age as u128 * 1000);
+            let expired = records.iter().take_while(|x| x.timestamp < cutoff).count();
+            for r in records.drain(..expired) {
+                tree.remove(r.key)?;
+            }
+            counters.expired = expired as u64;
+        }
+
+        if let Some(max_bytes) = policy.max_bytes {
+            let mut total: u64 = records.iter().map(|x| x.size).sum();
+            if let Some(interval) = policy.downsample {
+                // a window at a time from the oldest, keeping the newest sample
+                // of each series, until the type fits
+                let mut start = 0;
+                while start < records.len() && total > max_bytes {
+                    let window = interval.window_start(records[start].timestamp);
+                    let end = start
+                        + records[start..]
+                            .iter()
+                            .take_while(|x| interval.window_start(x.timestamp) == window)
+                            .count();
+                    let mut newest = HashMap::new();
+                    for (i, r) in records[start..end].iter().enumerate() {
+                        newest.insert(r.series.clone(), i);
+                    }
+                    for (i, r) in records[start..end].iter_mut().enumerate() {
+                        if newest[&r.series] != i {
+                            tree.remove(&r.key)?;
+                            r.removed = true;
+                            total -= r.size;
+                            counters.downsampled += 1;
+                        }
+                    }
+                    start = end;
+                }
+                records.retain(|x| !x.removed);
+            }
+            for r in records.iter() {
+                if total <= max_bytes {
+                    break;
+                }
+                tree.remove(&r.key)?;
+                total -= r.size;
+                counters.evicted += 1;
+            }
+        }
+
+        if counters.total() > 0 {
+            eprintln!(
+                "retention removed {} records: {:?}",
+                policy.sample_type.tree_name(),
+                counters
+            );
+            let mut running = self.eviction_counters(&policy.sample_type)?;
+            running.add(&counters);
+            match serde_json::to_vec(&running) {
+                Ok(v) => {
+                    self.evictions.insert(policy.sample_type.tree_name(), v)?;
+                }
+                Err(e) => eprintln!("failed to save eviction counts: {}", e),
+            }
+        }
+        Ok(counters)
+    }
+
+    fn eviction_counters(&self, sample_type: &SampleTypes) -> sled::Result<EvictionCounters> {
+        match self.evictions.get(sample_type.tree_name())? {
+            Some(v) => match serde_json::from_slice(v.as_ref()) {
+                Ok(c) => Ok(c),
+                Err(e) => {
+                    eprintln!(
+                        "resetting unreadable {} eviction counts: {}",
+                        sample_type.tree_name(),
+                        e
+                    );
+                    Ok(EvictionCounters::default())
+                }
+            },
+            None => Ok(EvictionCounters::default()),
+        }
+    }
+
+    pub fn evictions(&self) -> sled::Result<Vec<EvictionReport>> {
+        let mut reports = Vec::new();
+        for sample_type in [SampleTypes::Meter, SampleTypes::Bridge, SampleTypes::Pulse] {
+            reports.push(EvictionReport {
+                counters: self.eviction_counters(&sample_type)?,
+                sample_type,
+            });
+        }
+        Ok(reports)
+    }
+
+    pub fn quarantined(&self) -> usize {
+        self.quarantine.len()
+    }
+
+    pub fn migrate_legacy(&self) -> sled::Result<usize> {
+        let mut migrated = migrate_legacy::<MeterSample>(&self.tree(&SampleTypes::Meter)?)?;
+        migrated += migrate_legacy::<BridgeSample>(&self.tree(&SampleTypes::Bridge)?)?;
+        migrated += migrate_legacy::<PulseSample>(&self.tree(&SampleTypes::Pulse)?)?;
+        Ok(migrated)
+    }
+}
+
+// Quarantine and archive keys are the tree name and the sample's key
+fn prefixed_key(sample_type: &SampleTypes, key: &[u8]) -> Vec<u8> {
+    let mut prefixed = sample_type.tree_name().as_bytes().to_vec();
+    prefixed.push(b'/');
+    prefixed.extend_from_slice(key);
+    prefixed
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::rollup::RollupInterval;
+
+    #[test]
+    fn hands_out_the_oldest_samples_first() {
+        let db = sled::Config::new().temporary(true).open().unwrap();
+        let store = SampleStore::from_db(db).unwrap();
+        for timestamp in [5_000, 1_000, 4_000, 2_000, 3_000] {
+            let mut sample = PulseSample::new_empty();
+            sample.timestamp = timestamp;
+            store.insert(&Sample::Pulse(sample)).unwrap();
+        }
+        let samples = store.get_samples(&SampleTypes::Pulse, 3).unwrap();
+        let timestamps: Vec<u128> = samples.iter().map(|x| x.1.timestamp()).collect();
+        assert_eq!(timestamps, vec![1_000, 2_000, 3_000]);
+    }
+
+    #[test]
+    fn queries_one_device_over_pages() {
+        let db = sled::Config::new().temporary(true).open().unwrap();
+        let store = SampleStore::from_db(db).unwrap();
+        for (hardware_id, timestamp) in [([1; 8], 3_000), ([2; 8], 2_500), ([1; 8], 1_000)] {
+            let mut sample = PulseSample::new_empty();
+            sample.hardware_id = hardware_id;
+            sample.timestamp = timestamp;
+            store.insert(&Sample::Pulse(sample)).unwrap();
+        }
+        for timestamp in [2_000, 4_000] {
+            let mut sample = PulseSample::new_empty();
+            sample.hardware_id = [1; 8];
+            sample.timestamp = timestamp;
+            store.insert(&Sample::Pulse(sample)).unwrap();
+        }
+        store
+            .insert(&Sample::Bridge(BridgeSample::new_empty()))
+            .unwrap();
+
+        let mut query = SampleQuery::new(SampleTypes::Pulse);
+        query.hardware_id = Some([1; 8]);
+        query.from = Some(1_500);
+        query.order = SortOrder::NewestFirst;
+        query.limit = 2;
+        let page = store.query(&query).unwrap();
+        let timestamps: Vec<u128> = page.samples.iter().map(|x| x.1.timestamp()).collect();
+        assert_eq!(timestamps, vec![4_000, 3_000]);
+        assert_eq!(page.total, 3);
+        assert_eq!(page.next_offset, Some(2));
+
+        query.offset = 2;
+        let page = store.query(&query).unwrap();
+        assert_eq!(page.samples.len(), 1);
+        assert_eq!(page.samples[0].1.timestamp(), 2_000);
+        assert_eq!(page.next_offset, None);
+    }
+
+    #[test]
+    fn archives_cleared_samples_until_the_retention_passes() {
+        let db = sled::Config::new().temporary(true).open().unwrap();
+        let store = SampleStore::from_db(db).unwrap();
+        let now = time_as_millis(SystemTime::now());
+        let mut keys = Vec::new();
+        for timestamp in [now - 7_200_000, now - 60_000, now] {
+            let mut sample = PulseSample::new_empty();
+            sample.timestamp = timestamp;
+            keys.push(store.insert(&Sample::Pulse(sample)).unwrap());
+        }
+
+        store
+            .archive_samples(&SampleTypes::Pulse, &keys[..2])
+            .unwrap();
+        store
+            .clear_samples(&SampleTypes::Pulse, &keys[2..])
+            .unwrap();
+        assert!(store
+            .get_samples(&SampleTypes::Pulse, 10)
+            .unwrap()
+            .is_empty());
+        assert_eq!(store.archived(), 2);
+
+        let pruned = store.prune_archive(Duration::from_secs(3600)).unwrap();
+        assert_eq!(pruned, 1);
+        assert_eq!(store.archived(), 1);
+    }
+
+    // Every pulse record is the same size, so limits can be set in records
+    fn pulse_store(samples: &[([u8; 8], u128)]) -> (SampleStore, u64) {
+        let db = sled::Config::new().temporary(true).open().unwrap();
+        let store = SampleStore::from_db(db).unwrap();
+        let mut size = 0;
+        for (hardware_id, timestamp) in samples.iter() {
+            let mut sample = PulseSample::new_empty();
+            sample.hardware_id = *hardware_id;
+            sample.timestamp = *timestamp;
+            let (value, key) = sample.to_ivec();
+            size = (key.len() + value.len()) as u64;
+            store.insert(&Sample::Pulse(sample)).unwrap();
+        }
+        (store, size)
+    }
+
+    fn pulse_timestamps(store: &SampleStore) -> Vec<u128> {
+        let samples = store.get_samples(&SampleTypes::Pulse, 100).unwrap();
+        samples.iter().map(|x| x.1.timestamp()).collect()
+    }
+
+    #[test]
+    fn downsamples_the_oldest_windows_first() {
+        // 2020-09-13 12:30:00 UTC, on a minute
+        const START: u128 = 1_600_000_200_000;
+        let (store, size) = pulse_store(&[
+            ([1; 8], START),
+            ([1; 8], START + 10_000),
+            ([2; 8], START + 15_000),
+            ([1; 8], START + 20_000),
+            ([1; 8], START + 60_000),
+            ([1; 8], START + 70_000),
+        ]);
+        let policy = RetentionPolicy {
+            sample_type: SampleTypes::Pulse,
+            max_age_secs: None,
+            max_bytes: Some(4 * size),
+            downsample: Some(RollupInterval::OneMinute),
+        };
+        let counters = store.enforce_retention(&policy).unwrap();
+        assert_eq!(counters.downsampled, 2);
+        assert_eq!(counters.evicted, 0);
+        // the newest of each series in the first window stays, the second
+        // window wasn't needed
+        assert_eq!(
+            pulse_timestamps(&store),
+            vec![
+                START + 15_000,
+                START + 20_000,
+                START + 60_000,
+                START + 70_000
+            ]
+        );
+    }
+
+    #[test]
+    fn evicts_the_oldest_past_max_bytes() {
+        let (store, size) = pulse_store(&[
+            ([1; 8], 3_000),
+            ([1; 8], 1_000),
+            ([1; 8], 5_000),
+            ([1; 8], 2_000),
+            ([1; 8], 4_000),
+        ]);
+        let mut policy = RetentionPolicy {
+            sample_type: SampleTypes::Pulse,
+            max_age_secs: None,
+            max_bytes: Some(3 * size),
+            downsample: None,
+        };
+        let counters = store.enforce_retention(&policy).unwrap();
+        assert_eq!(counters.evicted, 2);
+        assert_eq!(pulse_timestamps(&store), vec![3_000, 4_000, 5_000]);
+
+        // all in one window, so downsampling gets there without evicting
+        policy.max_bytes = Some(size);
+        policy.downsample = Some(RollupInterval::OneMinute);
+        let counters = store.enforce_retention(&policy).unwrap();
+        assert_eq!(counters.downsampled, 2);
+        assert_eq!(counters.evicted, 0);
+        assert_eq!(pulse_timestamps(&store), vec![5_000]);
+
+        let reports = store.evictions().unwrap();
+        let pulse = reports
+            .iter()
+            .find(|x| x.sample_type == SampleTypes::Pulse)
+            .unwrap();
+        assert_eq!(pulse.counters.evicted, 2);
+        assert_eq!(pulse.counters.downsampled, 2);
+        assert_eq!(pulse.counters.total(), 4);
+    }
+
+    #[test]
+    fn evictions_add_up_across_runs() {
+        let db = sled::Config::new().temporary(true).open().unwrap();
+        let store = SampleStore::from_db(db.clone()).unwrap();
+        let now = time_as_millis(SystemTime::now());
+        let insert = |timestamp: u128| {
+            let mut sample = PulseSample::new_empty();
+            sample.timestamp = timestamp;
+            store.insert(&Sample::Pulse(sample)).unwrap();
+        };
+        let policy = RetentionPolicy {
+            sample_type: SampleTypes::Pulse,
+            max_age_secs: Some(3600),
+            max_bytes: None,
+            downsample: None,
+        };
+
+        insert(now - 7_200_000);
+        insert(now);
+        let counters = store.enforce_retention(&policy).unwrap();
+        assert_eq!(counters.expired, 1);
+        insert(now - 7_100_000);
+        store.enforce_retention(&policy).unwrap();
+        // nothing to do leaves the totals alone
+        assert_eq!(store.enforce_retention(&policy).unwrap().total(), 0);
+
+        let reopened = SampleStore::from_db(db).unwrap();
+        let reports = reopened.evictions().unwrap();
+        let pulse = reports
+            .iter()
+            .find(|x| x.sample_type == SampleTypes::Pulse)
+            .unwrap();
+        assert_eq!(pulse.counters.expired, 2);
+        assert_eq!(
+            reopened.get_samples(&SampleTypes::Pulse, 10).unwrap().len(),
+            1
+        );
+    }
+}
@@ -0,0 +1,390 @@
+use crate::pulse::*;
+use crate::retention::series_key;
+use crate::samples::*;
+use std::collections::HashMap;
+use std::path::Path;
+use std::time::SystemTime;
+
+// Channel numbers are the meter channel or pulse input, or the register's
+// position in a bridge sample
+#[derive(Clone, Copy, Hash, Serialize, Deserialize, PartialEq, Debug)]
+pub enum QualityFlag {
+    // at the meter's full scale, the real value may be higher
+    Saturated(u8),
+    OutOfRange(u8),
+    // the same non-zero reading too many samples in a row
+    Stuck(u8),
+    // a counter or accumulator moved in a way it can't, e.g. backwards
+    ImpossibleDelta(u8),
+    FutureTimestamp,
+    StaleTimestamp,
+    // older than a sample already seen for the same series
+    OutOfOrder,
+    ModbusException,
+}
+
+#[derive(Clone, Serialize, Deserialize, PartialEq, Debug)]
+#[serde(default)]
+pub struct ValidationLimits {
+    // phases reading 0 are taken as not connected and never flagged
+    pub min_millivolts: u32,
+    pub max_millivolts: u32,
+    pub max_power_factor: u32,
+    pub stuck_samples: u32,
+    pub max_pulse_rate: f64,
+    pub max_future_millis: u128,
+    pub max_age_millis: u128,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct SeriesState {
+    pub timestamp: u128,
+    pub values: Vec<u32>,
+    pub repeats: Vec<u32>,
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct Validator {
+    pub limits: ValidationLimits,
+    pub last: HashMap<Vec<u8>, SeriesState>,
+}
+
+// The state of every series in a batch before it was validated, None for
+// series seen for the first time
+pub type ValidatorSnapshot = Vec<(Vec<u8>, Option<SeriesState>)>;
+
+impl Default for ValidationLimits {
+    fn default() -> Self {
+        ValidationLimits {
+            min_millivolts: 80_000,
+            max_millivolts: 300_000,
+            max_power_factor: 1000,
+            stuck_samples: 30,
+            max_pulse_rate: 100.0,
+            max_future_millis: 60_000,
+            max_age_millis: 30 * 24 * 60 * 60 * 1000,
+        }
+    }
+}
+
+impl ValidationLimits {
+    pub fn load<P: AsRef<Path>>(path: P) -> std::io::Result<Self> {
+        let json = std::fs::read_to_string(path)?;
+        serde_json::from_str(&json)
+            .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidData, e))
+    }
+}
+
+impl MeterDataTypes {
+    // Largest value the meter can report, for the 12-bit quantities
+    pub fn full_scale(&self) -> Option<u32> {
+        match self {
+            MeterDataTypes::Power => Some(4095 * 20),
+            MeterDataTypes::Current => Some(4095 * 10),
+            _ => None,
+        }
+    }
+}
+
+impl Validator {
+    pub fn new(limits: ValidationLimits) -> Self {
+        Validator {
+            limits,
+            last: HashMap::new(),
+        }
+    }
+
+    // Samples have to come in timestamp order for the stuck and delta checks,
+    // one older than the series' last sample only gets the checks that don't
+    // compare with it and leaves the series state alone.
+    // Nothing is dropped or changed, the flags only say what looks wrong.
+    pub fn validate(&mut self, sample: &Sample) -> Vec<QualityFlag> {
+        let mut flags = Vec::new();
+        let timestamp = sample.timestamp();
+        let now = time_as_millis(SystemTime::now());
+        if timestamp > now + self.limits.max_future_millis {
+            flags.push(QualityFlag::FutureTimestamp);
+        }
+        if timestamp + self.limits.max_age_millis < now {
+            flags.push(QualityFlag::StaleTimestamp);
+        }
+
+        let key = series_key(sample);
+        let out_of_order = self.last.get(&key).is_some_and(|p| timestamp < p.timestamp);
+        if out_of_order {
+            flags.push(QualityFlag::OutOfOrder);
+        }
+        let previous = match out_of_order {
+            true => None,
+            false => self.last.get(&key).cloned(),
+        };
+
+        let values: Vec<u32> = match sample {
+            Sample::Meter(m) => {
+                self.check_meter(m, previous.as_ref(), &mut flags);
+                m.values.clone()
+            }
+            Sample::Pulse(p) => {
+                self.check_pulse(p, previous.as_ref(), &mut flags);
+                p.pulses.iter().map(|x| *x as u32).collect()
+            }
+            Sample::Bridge(b) => {
+                if b.exception.is_some() {
+                    flags.push(QualityFlag::ModbusException);
+                }
+                b.values.iter().map(|x| *x as u32).collect()
+            }
+            Sample::None => Vec::new(),
+        };
+        if out_of_order {
+            return flags;
+        }
+
+        let mut repeats = vec![0; values.len()];
+        if let Some(p) = &previous {
+            for (i, v) in values.iter().enumerate() {
+                if p.values.get(i) == Some(v) {
+                    repeats[i] = p.repeats.get(i).unwrap_or(&0) + 1;
+                }
+            }
+        }
+        self.last.insert(
+            key,
+            SeriesState {
+                timestamp,
+                values,
+                repeats,
+            },
+        );
+        flags
+    }
+
+    fn check_meter(
+        &self,
+        sample: &MeterSample,
+        previous: Option<&SeriesState>,
+        flags: &mut Vec<QualityFlag>,
+    ) {
+        for (i, v) in sample.values.iter().enumerate() {
+            let channel = sample.channels.get(i).map_or(i as u8, |x| x.index);
+            // CT scaling moves full scale, so only unscaled values can be checked
+            match sample.data_type.full_scale() {
+                Some(max) if sample.channels.is_empty() && *v >= max => {
+                    flags.push(QualityFlag::Saturated(channel));
+                }
+                _ => {}
+            }
+            let in_range = match sample.data_type {
+                MeterDataTypes::Volatage => {
+                    *v == 0
+                        || (*v >= self.limits.min_millivolts && *v <= self.limits.max_millivolts)
+                }
+                MeterDataTypes::PowerFactor => *v <= self.limits.max_power_factor,
+                _ => true,
+            };
+            if !in_range {
+                flags.push(QualityFlag::OutOfRange(channel));
+            }
+            let previous = match previous {
+                Some(p) => p,
+                None => continue,
+            };
+            match sample.data_type {
+                MeterDataTypes::Energy => {
+                    if previous.values.get(i).is_some_and(|x| v < x) {
+                        flags.push(QualityFlag::ImpossibleDelta(channel));
+                    }
+                }
+                // energy sits still whenever nothing is drawing power
+                _ => {
+                    let repeats = previous.repeats.get(i).unwrap_or(&0) + 1;
+                    if *v != 0
+                        && previous.values.get(i) == Some(v)
+                        && repeats >= self.limits.stuck_samples
+                    {
+                        flags.push(QualityFlag::Stuck(channel));
+                    }
+                }
+            }
+        }
+    }
+
+    fn check_pulse(
+        &self,
+        sample: &PulseSample,
+        previous: Option<&SeriesState>,
+        flags: &mut Vec<QualityFlag>,
+    ) {
+        let previous = match previous {
+            Some(p) => p,
+            None => return,
+        };
+        let elapsed = sample.timestamp - previous.timestamp;
+        for (i, pulses) in sample.pulses.iter().enumerate() {
+            let last = match previous.values.get(i) {
+                Some(l) => *l as u16,
+                None => continue,
+            };
+            let (delta, event) = counter_delta(last, *pulses);
+            let too_fast =
+                elapsed > 0 && delta as f64 * 1000.0 / elapsed as f64 > self.limits.max_pulse_rate;
+            if event == PulseEvent::Reset || too_fast {
+                flags.push(QualityFlag::ImpossibleDelta(i as u8));
+            }
+        }
+    }
+
+    // State of the series the samples belong to, to put back with restore if
+    // their upload fails. Other series of the same device are left out, a
+    // bridge's pulse and Modbus samples go up separately.
+    pub fn snapshot<'a, I: IntoIterator<Item = &'a Sample>>(
+        &self,
+        samples: I,
+    ) -> ValidatorSnapshot {
+        let mut snapshot: ValidatorSnapshot = Vec::new();
+        for sample in samples {
+            let key = series_key(sample);
+            if snapshot.iter().any(|x| x.0 == key) {
+                continue;
+            }
+            let state = self.last.get(&key).cloned();
+            snapshot.push((key, state));
+        }
+        snapshot
+    }
+
+    pub fn restore(&mut self, snapshot: ValidatorSnapshot) {
+        for (key, state) in snapshot {
+            match state {
+                Some(s) => self.last.insert(key, s),
+                None => self.last.remove(&key),
+            };
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::modbus::*;
+
+    const DEVICE: [u8; 8] = [1, 2, 3, 4, 5, 6, 7, 8];
+
+    fn meter(timestamp: u128, watts: u32) -> Sample {
+        let mut sample = MeterSample::new_empty();
+        sample.hardware_id = DEVICE;
+        sample.data_type = MeterDataTypes::Power;
+        sample.timestamp = timestamp;
+        sample.values = vec![watts];
+        Sample::Meter(sample)
+    }
+
+    fn pulse(timestamp: u128, first: u16) -> Sample {
+        let mut sample = PulseSample::new_empty();
+        sample.hardware_id = DEVICE;
+        sample.timestamp = timestamp;
+        sample.pulses[0] = first;
+        Sample::Pulse(sample)
+    }
+
+    fn bridge(timestamp: u128, value: u16) -> Sample {
+        let mut sample = BridgeSample::new_empty();
+        sample.hardware_id = DEVICE;
+        sample.timestamp = timestamp;
+        sample.function = FunctionTypes::ReadHoldingRegisters;
+        sample.values = vec![value];
+        Sample::Bridge(sample)
+    }
+
+    fn now() -> u128 {
+        time_as_millis(SystemTime::now())
+    }
+
+    #[test]
+    fn flags_ranges_and_timestamps() {
+        let mut validator = Validator::default();
+        let now = now();
+        assert_eq!(validator.validate(&meter(now, 100)), vec![]);
+        assert_eq!(
+            validator.validate(&meter(now + 1, 4095 * 20)),
+            vec![QualityFlag::Saturated(0)]
+        );
+        assert_eq!(
+            validator.validate(&meter(now + 3_600_000, 100)),
+            vec![QualityFlag::FutureTimestamp]
+        );
+    }
+
+    #[test]
+    fn out_of_order_samples_still_get_stateless_checks() {
+        let mut validator = Validator::default();
+        let now = now();
+        validator.validate(&meter(now, 100));
+        assert_eq!(
+            validator.validate(&meter(now - 1_000, 4095 * 20)),
+            vec![QualityFlag::OutOfOrder, QualityFlag::Saturated(0)]
+        );
+
+        let mut exception = bridge(now - 1_000, 0);
+        validator.validate(&bridge(now, 0));
+        if let Sample::Bridge(b) = &mut exception {
+            b.exception = Some(ModbusException::IllegalDataAddress);
+        }
+        assert_eq!(
+            validator.validate(&exception),
+            vec![QualityFlag::OutOfOrder, QualityFlag::ModbusException]
+        );
+        // the late sample didn't move the series back
+        assert_eq!(validator.validate(&meter(now + 1, 100)), vec![]);
+    }
+
+    #[test]
+    fn flags_stuck_values_and_counter_resets() {
+        let mut validator = Validator::new(ValidationLimits {
+            stuck_samples: 2,
+            ..ValidationLimits::default()
+        });
+        let now = now();
+        validator.validate(&meter(now, 100));
+        validator.validate(&meter(now + 1, 100));
+        assert_eq!(
+            validator.validate(&meter(now + 2, 100)),
+            vec![QualityFlag::Stuck(0)]
+        );
+
+        validator.validate(&pulse(now, 30_000));
+        assert_eq!(
+            validator.validate(&pulse(now + 60_000, 10)),
+            vec![QualityFlag::ImpossibleDelta(0)]
+        );
+    }
+
+    #[test]
+    fn restores_only_the_series_in_the_batch() {
+        let mut validator = Validator::default();
+        let now = now();
+        validator.validate(&pulse(now, 100));
+        validator.validate(&bridge(now, 7));
+
+        let batch = [pulse(now + 1_000, 110), pulse(now + 2_000, 120)];
+        let snapshot = validator.snapshot(batch.iter());
+        assert_eq!(snapshot.len(), 1);
+        for sample in batch.iter() {
+            validator.validate(sample);
+        }
+        validator.validate(&bridge(now + 2_000, 8));
+        let new_series = [meter(now, 1)];
+        let meter_snapshot = validator.snapshot(new_series.iter());
+        validator.validate(&new_series[0]);
+
+        validator.restore(snapshot);
+        validator.restore(meter_snapshot);
+        assert_eq!(validator.last[&series_key(&pulse(0, 0))].timestamp, now);
+        // the bridge series of the same device kept its newer state
+        assert_eq!(
+            validator.last[&series_key(&bridge(0, 0))].timestamp,
+            now + 2_000
+        );
+        assert!(!validator.last.contains_key(&series_key(&new_series[0])));
+    }
+}